@@ -0,0 +1,70 @@
+use anyhow::Error;
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
+use bevy::prelude::*;
+
+use crate::replay::VeilidReplay;
+use crate::transcript::Transcript;
+
+/// Loads replays (`*.replay.json`) and transcripts (`*.transcript.json`) through the
+/// [`AssetServer`]. Requires bevy's `AssetPlugin`, and its `file_watcher` feature to
+/// hot-reload them.
+#[derive(Default, Clone)]
+pub struct VeilidAssetPlugin;
+
+impl Plugin for VeilidAssetPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<VeilidReplay>();
+        app.init_asset::<Transcript>();
+        app.register_asset_loader(VeilidReplayLoader);
+        app.register_asset_loader(TranscriptLoader);
+    }
+}
+
+#[derive(Default)]
+pub struct VeilidReplayLoader;
+
+impl AssetLoader for VeilidReplayLoader {
+    type Asset = VeilidReplay;
+    type Settings = ();
+    type Error = Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<VeilidReplay, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["replay.json"]
+    }
+}
+
+#[derive(Default)]
+pub struct TranscriptLoader;
+
+impl AssetLoader for TranscriptLoader {
+    type Asset = Transcript;
+    type Settings = ();
+    type Error = Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Transcript, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["transcript.json"]
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::turn::{EventTurnEnded, TurnState};
+use crate::{timestamp, VeilidApp};
+
+/// Chess-clock style time control.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeControl {
+    /// Time each player starts with.
+    pub base: Duration,
+    /// Time added to a player's clock after each of their moves.
+    pub increment: Duration,
+    /// Longest a single turn may take, regardless of the time left on the clock.
+    pub move_limit: Option<Duration>,
+    /// Extra time for the other player's move to arrive before their clock expires locally.
+    pub grace: Duration,
+}
+
+impl Default for TimeControl {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(10 * 60),
+            increment: Duration::ZERO,
+            move_limit: None,
+            grace: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Remaining time per player under a [`TimeControl`].
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TurnClock {
+    control: Option<TimeControl>,
+    remaining: HashMap<CryptoTyped<CryptoKey>, Duration>,
+    expired: Option<CryptoTyped<CryptoKey>>,
+    #[serde(skip)]
+    paused_at: Option<u64>,
+    paused_in_turn: u64,
+}
+
+impl TurnClock {
+    pub fn new(control: TimeControl) -> Self {
+        Self {
+            control: Some(control),
+            ..default()
+        }
+    }
+
+    pub fn control(&self) -> Option<&TimeControl> {
+        self.control.as_ref()
+    }
+
+    /// Refills every clock for a new game under the same time control.
+    pub fn reset(&mut self) {
+        self.remaining.clear();
+        self.expired = None;
+        self.paused_in_turn = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    pub(crate) fn pause(&mut self, at: u64) {
+        self.paused_at.get_or_insert(at);
+    }
+
+    pub(crate) fn resume(&mut self, at: u64, turn_started_at: Option<u64>) {
+        let Some(paused_at) = self.paused_at.take() else {
+            return;
+        };
+        let from = turn_started_at.map_or(paused_at, |started_at| started_at.max(paused_at));
+        self.paused_in_turn += at.saturating_sub(from);
+    }
+
+    fn paused_during(&self, started_at: u64, at: u64) -> Duration {
+        let running_pause = self
+            .paused_at
+            .map_or(0, |paused_at| at.saturating_sub(paused_at.max(started_at)));
+        Duration::from_millis(self.paused_in_turn + running_pause)
+    }
+
+    /// The player who ran out of time, if any.
+    pub fn expired(&self) -> Option<CryptoTyped<CryptoKey>> {
+        self.expired
+    }
+
+    /// Time left for `player` at the end of their last turn.
+    pub fn banked(&self, player: CryptoTyped<CryptoKey>) -> Option<Duration> {
+        let control = self.control?;
+        Some(*self.remaining.get(&player).unwrap_or(&control.base))
+    }
+
+    /// Time left for `player` right now, counting the running turn if it's theirs.
+    pub fn remaining(
+        &self,
+        player: CryptoTyped<CryptoKey>,
+        turn_state: &TurnState,
+    ) -> Option<Duration> {
+        let banked = self.banked(player)?;
+        match self.running(turn_state) {
+            Some((current, elapsed)) if current == player => Some(banked.saturating_sub(elapsed)),
+            _ => Some(banked),
+        }
+    }
+
+    // Limits a peer's timestamp to the time it arrived, less the grace for delivery, so
+    // a peer can't claim time it didn't spend. It also can't precede `not_before`.
+    pub(crate) fn remote_time(&self, at: u64, not_before: Option<u64>) -> u64 {
+        self.limit(at, not_before, timestamp())
+    }
+
+    fn limit(&self, at: u64, not_before: Option<u64>, now: u64) -> u64 {
+        let grace = self.control.unwrap_or_default().grace.as_millis() as u64;
+        let earliest = now
+            .saturating_sub(grace)
+            .max(not_before.unwrap_or(0))
+            .min(now);
+        at.clamp(earliest, now)
+    }
+
+    // Charges a finished turn to its player and returns whether they ran out of time.
+    fn charge(&mut self, e: &EventTurnEnded) -> bool {
+        let paused = e
+            .started_at
+            .map(|started_at| self.paused_during(started_at, e.ended_at))
+            .unwrap_or_default();
+        self.paused_in_turn = 0;
+
+        let (Some(control), Some(started_at)) = (self.control, e.started_at) else {
+            return false;
+        };
+        if self.expired.is_some() {
+            return false;
+        }
+
+        let elapsed =
+            Duration::from_millis(e.ended_at.saturating_sub(started_at)).saturating_sub(paused);
+        if self.is_over_limit(e.player, elapsed) {
+            self.expired = Some(e.player);
+            return true;
+        }
+
+        let banked = self.banked(e.player).unwrap_or(control.base);
+        self.remaining
+            .insert(e.player, banked - elapsed + control.increment);
+        false
+    }
+
+    fn running(&self, turn_state: &TurnState) -> Option<(CryptoTyped<CryptoKey>, Duration)> {
+        self.control?;
+        let player = turn_state.current_player()?;
+        let started_at = turn_state.started_at()?;
+        let now = timestamp();
+        let elapsed = Duration::from_millis(now.saturating_sub(started_at))
+            .saturating_sub(self.paused_during(started_at, now));
+        Some((player, elapsed))
+    }
+
+    fn is_over_limit(&self, player: CryptoTyped<CryptoKey>, elapsed: Duration) -> bool {
+        let Some(control) = self.control else {
+            return false;
+        };
+        let banked = self.banked(player).unwrap_or(control.base);
+        elapsed > banked || control.move_limit.is_some_and(|limit| elapsed > limit)
+    }
+}
+
+// ------
+// Events
+// ------
+
+/// Sent about once a second while a clock is running.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventClockTick {
+    pub player: CryptoTyped<CryptoKey>,
+    pub remaining: Duration,
+}
+
+/// `player` ran out of time or exceeded the per-move limit and forfeits.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventTimeExpired {
+    pub player: CryptoTyped<CryptoKey>,
+    pub turn: u32,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn charge_clock_on_turn_end(
+    mut er_turn_ended: EventReader<EventTurnEnded>,
+    mut ew_time_expired: EventWriter<EventTimeExpired>,
+    mut clock: ResMut<TurnClock>,
+) {
+    for e in er_turn_ended.read() {
+        if clock.charge(e) {
+            ew_time_expired.send(EventTimeExpired {
+                player: e.player,
+                turn: e.turn,
+            });
+        }
+    }
+}
+
+pub(crate) fn tick_clock(
+    mut ew_clock_tick: EventWriter<EventClockTick>,
+    mut ew_time_expired: EventWriter<EventTimeExpired>,
+    mut last_tick: Local<Option<(u32, u64)>>,
+    mut clock: ResMut<TurnClock>,
+    turn_state: Res<TurnState>,
+    veilid_app: Res<VeilidApp>,
+) {
+    if clock.expired.is_some() || clock.is_paused() {
+        return;
+    }
+    let (Some(control), Some((player, elapsed))) = (clock.control, clock.running(&turn_state))
+    else {
+        return;
+    };
+
+    let is_local = veilid_app
+        .app
+        .as_ref()
+        .is_some_and(|app| app.our_dht_key == player);
+    let grace = if is_local {
+        Duration::ZERO
+    } else {
+        control.grace
+    };
+
+    if clock.is_over_limit(player, elapsed.saturating_sub(grace)) {
+        clock.expired = Some(player);
+        ew_time_expired.send(EventTimeExpired {
+            player,
+            turn: turn_state.turn(),
+        });
+        return;
+    }
+
+    let tick = Some((turn_state.turn(), elapsed.as_secs()));
+    if *last_tick != tick {
+        *last_tick = tick;
+        ew_clock_tick.send(EventClockTick {
+            player,
+            remaining: clock.remaining(player, &turn_state).unwrap_or_default(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn player(b: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([b; 32]))
+    }
+
+    fn clock() -> TurnClock {
+        TurnClock::new(TimeControl {
+            base: Duration::from_secs(60),
+            increment: Duration::from_secs(2),
+            move_limit: Some(Duration::from_secs(30)),
+            grace: Duration::from_secs(5),
+        })
+    }
+
+    fn turn_ended(started_at: u64, ended_at: u64) -> EventTurnEnded {
+        EventTurnEnded {
+            turn: 1,
+            player: player(1),
+            started_at: Some(started_at),
+            ended_at,
+        }
+    }
+
+    #[test]
+    fn banks_time_left_and_increment() {
+        let mut clock = clock();
+        assert_eq!(clock.banked(player(1)), Some(Duration::from_secs(60)));
+
+        assert!(!clock.charge(&turn_ended(0, 10_000)));
+        assert_eq!(clock.banked(player(1)), Some(Duration::from_secs(52)));
+        assert_eq!(clock.banked(player(2)), Some(Duration::from_secs(60)));
+        assert_eq!(TurnClock::default().banked(player(1)), None);
+    }
+
+    #[test]
+    fn does_not_charge_paused_time() {
+        let mut clock = clock();
+        clock.pause(2_000);
+        assert!(clock.is_paused());
+        clock.resume(22_000, Some(0));
+        assert!(!clock.is_paused());
+
+        assert!(!clock.charge(&turn_ended(0, 25_000)));
+        assert_eq!(clock.banked(player(1)), Some(Duration::from_secs(57)));
+    }
+
+    #[test]
+    fn counts_the_running_turn() {
+        let clock = clock();
+        let mut turn_state = TurnState::new(vec![player(1), player(2)]);
+        let remaining = clock.remaining(player(1), &turn_state).unwrap();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(59));
+
+        turn_state.advance_at(timestamp() - 10_000);
+        let remaining = clock.remaining(player(2), &turn_state).unwrap();
+        assert!(remaining <= Duration::from_secs(50));
+        assert_eq!(
+            clock.remaining(player(1), &turn_state),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn expires_over_the_clock_or_the_move_limit() {
+        let mut clock = clock();
+        assert!(clock.charge(&turn_ended(0, 31_000)));
+        assert_eq!(clock.expired(), Some(player(1)));
+
+        let mut clock = TurnClock::new(TimeControl {
+            base: Duration::from_secs(10),
+            ..default()
+        });
+        assert!(!clock.charge(&turn_ended(0, 9_000)));
+        assert!(clock.charge(&turn_ended(0, 2_000)));
+
+        clock.reset();
+        assert_eq!(clock.expired(), None);
+        assert_eq!(clock.banked(player(1)), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn keeps_banked_time_when_saved() {
+        let mut clock = clock();
+        clock.charge(&turn_ended(0, 10_000));
+        clock.pause(20_000);
+
+        let saved: TurnClock =
+            serde_json::from_str(&serde_json::to_string(&clock).unwrap()).unwrap();
+        assert_eq!(saved.banked(player(1)), Some(Duration::from_secs(52)));
+        assert_eq!(saved.control(), clock.control());
+        assert!(!saved.is_paused());
+    }
+
+    #[test]
+    fn limits_remote_timestamps() {
+        let clock = clock();
+        let now = 100_000;
+
+        assert_eq!(clock.limit(98_000, None, now), 98_000);
+        assert_eq!(clock.limit(50_000, None, now), 95_000);
+        assert_eq!(clock.limit(200_000, None, now), now);
+        assert_eq!(clock.limit(96_000, Some(97_000), now), 97_000);
+        assert_eq!(clock.limit(96_000, Some(200_000), now), now);
+    }
+}
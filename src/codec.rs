@@ -0,0 +1,358 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::EventConnectedPeer;
+
+/// A wire format for payloads. The built-in formats implement it; implement it for any
+/// other format and make it available with [`register_codec`]. Values are type-erased so
+/// codecs can be stored as trait objects.
+pub trait Codec: Send + Sync {
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error>;
+
+    /// Hands a deserializer reading `bytes` to `visit`, which decodes the value from it.
+    fn decode(&self, bytes: &[u8], visit: DecodeVisitor) -> Result<(), Error>;
+}
+
+pub type DecodeVisitor<'a> =
+    &'a mut dyn FnMut(&mut dyn erased_serde::Deserializer<'_>) -> Result<(), erased_serde::Error>;
+
+/// Wire formats known to the plugin. Binary formats are behind the `bincode`, `postcard`
+/// and `msgpack` cargo features. `Custom` names a codec added with [`register_codec`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CodecKind {
+    #[default]
+    Json,
+    Bincode,
+    Postcard,
+    MessagePack,
+    Custom(u32),
+}
+
+fn custom_codecs() -> &'static RwLock<HashMap<u32, Arc<dyn Codec>>> {
+    static CODECS: OnceLock<RwLock<HashMap<u32, Arc<dyn Codec>>>> = OnceLock::new();
+    CODECS.get_or_init(Default::default)
+}
+
+/// Makes `codec` available as [`CodecKind::Custom`]`(id)`, replacing any codec registered
+/// under `id` before. Peers only use it with each other if both registered it under the
+/// same id.
+pub fn register_codec(id: u32, codec: impl Codec + 'static) {
+    custom_codecs().write().unwrap().insert(id, Arc::new(codec));
+}
+
+impl CodecKind {
+    /// Returns `true` if this format was compiled into or registered with the current build.
+    pub fn is_available(&self) -> bool {
+        match self {
+            CodecKind::Json => true,
+            CodecKind::Bincode => cfg!(feature = "bincode"),
+            CodecKind::Postcard => cfg!(feature = "postcard"),
+            CodecKind::MessagePack => cfg!(feature = "msgpack"),
+            CodecKind::Custom(id) => custom_codecs().read().unwrap().contains_key(id),
+        }
+    }
+
+    /// Every format this build can decode.
+    pub fn available() -> Vec<CodecKind> {
+        let mut available: Vec<_> = [
+            CodecKind::Json,
+            CodecKind::Bincode,
+            CodecKind::Postcard,
+            CodecKind::MessagePack,
+        ]
+        .into_iter()
+        .filter(CodecKind::is_available)
+        .collect();
+        let mut custom: Vec<_> = custom_codecs().read().unwrap().keys().copied().collect();
+        custom.sort();
+        available.extend(custom.into_iter().map(CodecKind::Custom));
+        available
+    }
+
+    fn with_codec<R>(&self, f: impl FnOnce(&dyn Codec) -> Result<R, Error>) -> Result<R, Error> {
+        match self {
+            CodecKind::Json => f(&JsonCodec),
+            #[cfg(feature = "bincode")]
+            CodecKind::Bincode => f(&BincodeCodec),
+            #[cfg(feature = "postcard")]
+            CodecKind::Postcard => f(&PostcardCodec),
+            #[cfg(feature = "msgpack")]
+            CodecKind::MessagePack => f(&MessagePackCodec),
+            CodecKind::Custom(id) => {
+                let codec = custom_codecs().read().unwrap().get(id).cloned();
+                match codec {
+                    Some(codec) => f(codec.as_ref()),
+                    None => Err(anyhow!("codec {:?} is not registered", self)),
+                }
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(anyhow!("codec {:?} is not enabled in this build", self)),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        self.with_codec(|codec| codec.encode(value))
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        self.with_codec(|codec| {
+            let mut value = None;
+            codec.decode(bytes, &mut |deserializer| {
+                value = Some(erased_serde::deserialize(deserializer)?);
+                Ok(())
+            })?;
+            value.ok_or_else(|| anyhow!("codec {:?} decoded nothing", self))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8], visit: DecodeVisitor) -> Result<(), Error> {
+        let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))?;
+        Ok(deserializer.end()?)
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+// The options `bincode::serialize` uses, so both directions agree
+#[cfg(feature = "bincode")]
+fn bincode_options() -> impl bincode::Options {
+    use bincode::Options;
+
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+        use bincode::Options;
+
+        Ok(bincode_options().serialize(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8], visit: DecodeVisitor) -> Result<(), Error> {
+        let mut deserializer = bincode::Deserializer::from_slice(bytes, bincode_options());
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PostcardCodec;
+
+#[cfg(feature = "postcard")]
+impl Codec for PostcardCodec {
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+        Ok(postcard::to_stdvec(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8], visit: DecodeVisitor) -> Result<(), Error> {
+        let mut deserializer = postcard::Deserializer::from_bytes(bytes);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePackCodec {
+    fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+        Ok(rmp_serde::to_vec(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8], visit: DecodeVisitor) -> Result<(), Error> {
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes);
+        visit(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum CodecMessage {
+    Supported { codecs: Vec<CodecKind>, reply: bool },
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct PeerCodecs {
+    announced: HashSet<CryptoTyped<CryptoKey>>,
+    supported: HashMap<CryptoTyped<CryptoKey>, Vec<CodecKind>>,
+}
+
+impl PeerCodecs {
+    // JSON until the peer announced it can decode `preferred`
+    pub fn choose(&self, peer: CryptoTyped<CryptoKey>, preferred: CodecKind) -> CodecKind {
+        match self.supported.get(&peer) {
+            Some(codecs) if codecs.contains(&preferred) => preferred,
+            _ => CodecKind::Json,
+        }
+    }
+}
+
+// -------
+// Systems
+// -------
+
+fn supported_message(reply: bool) -> ProtocolMessage {
+    ProtocolMessage::Codec(CodecMessage::Supported {
+        codecs: CodecKind::available(),
+        reply,
+    })
+}
+
+pub(crate) fn announce_codecs_on_connect(
+    mut er_connected_peer: EventReader<EventConnectedPeer>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut peer_codecs: ResMut<PeerCodecs>,
+) {
+    for e in er_connected_peer.read() {
+        if !peer_codecs.announced.insert(e.dht_key) {
+            continue;
+        }
+        ew_send_protocol.send(EventSendProtocol {
+            message: supported_message(false),
+            dht_key: e.dht_key,
+        });
+    }
+}
+
+pub(crate) fn on_ev_receive_codecs(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut peer_codecs: ResMut<PeerCodecs>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Codec(CodecMessage::Supported { codecs, reply }) = &e.message else {
+            continue;
+        };
+
+        // A peer that announces unasked started a new session, so it gets our codecs even
+        // if it had them before.
+        if !reply {
+            peer_codecs.announced.insert(e.dht_key);
+            ew_send_protocol.send(EventSendProtocol {
+                message: supported_message(true),
+                dht_key: e.dht_key,
+            });
+        }
+        peer_codecs.supported.insert(e.dht_key, codecs.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Reversed;
+
+    impl Codec for Reversed {
+        fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, Error> {
+            let mut bytes = JsonCodec.encode(value)?;
+            bytes.reverse();
+            Ok(bytes)
+        }
+
+        fn decode(&self, bytes: &[u8], visit: DecodeVisitor) -> Result<(), Error> {
+            let mut bytes = bytes.to_vec();
+            bytes.reverse();
+            JsonCodec.decode(&bytes, visit)
+        }
+    }
+
+    #[test]
+    fn round_trips_json() {
+        let bytes = CodecKind::Json.encode(&(1u32, "two")).unwrap();
+        let value: (u32, String) = CodecKind::Json.decode(&bytes).unwrap();
+        assert_eq!(value, (1, "two".to_string()));
+        assert!(CodecKind::Json.decode::<u32>(b"1 2").is_err());
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Blob {
+        name: String,
+        #[serde(with = "crate::envelope::base64_payload")]
+        bytes: Vec<u8>,
+    }
+
+    #[test]
+    fn round_trips_every_available_codec() {
+        let blob = Blob {
+            name: "blob".to_string(),
+            bytes: vec![0, 1, 2, 255],
+        };
+        for codec in CodecKind::available() {
+            let bytes = codec.encode(&blob).unwrap();
+            assert_eq!(codec.decode::<Blob>(&bytes).unwrap(), blob, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn falls_back_to_json_until_announced() {
+        let peer = CryptoTyped::new(
+            veilid_duplex::veilid_core::CRYPTO_KIND_VLD0,
+            CryptoKey::new([1; 32]),
+        );
+        let mut peer_codecs = PeerCodecs::default();
+        assert_eq!(
+            peer_codecs.choose(peer, CodecKind::Bincode),
+            CodecKind::Json
+        );
+
+        peer_codecs
+            .supported
+            .insert(peer, vec![CodecKind::Json, CodecKind::Postcard]);
+        assert_eq!(
+            peer_codecs.choose(peer, CodecKind::Bincode),
+            CodecKind::Json
+        );
+        assert_eq!(
+            peer_codecs.choose(peer, CodecKind::Postcard),
+            CodecKind::Postcard
+        );
+    }
+
+    #[test]
+    fn uses_registered_codecs() {
+        let codec = CodecKind::Custom(7);
+        assert!(!CodecKind::Custom(8).is_available());
+        assert!(CodecKind::Custom(8).encode(&1u32).is_err());
+
+        register_codec(7, Reversed);
+        assert!(codec.is_available());
+        assert!(CodecKind::available().contains(&codec));
+        let bytes = codec.encode(&vec![1u32, 2]).unwrap();
+        assert_eq!(bytes, b"]2,1[");
+        assert_eq!(codec.decode::<Vec<u32>>(&bytes).unwrap(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+/// Compression algorithms known to the plugin. `Lz4` is behind the `lz4` cargo feature.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Deflate,
+    Lz4,
+}
+
+/// Payload compression settings, see [`VeilidSettings::compression`](crate::VeilidSettings::compression).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionSettings {
+    pub algorithm: CompressionAlgorithm,
+    /// Compression level, 0-9 for `Deflate`. Ignored by `Lz4`.
+    pub level: u32,
+    /// Serialized payloads shorter than this many bytes are sent uncompressed.
+    pub threshold: usize,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Deflate,
+            level: 6,
+            threshold: 1024,
+        }
+    }
+}
+
+impl CompressionAlgorithm {
+    pub fn compress(&self, level: u32, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            CompressionAlgorithm::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::new(level.min(9)),
+                );
+                encoder.write_all(bytes)?;
+                Ok(encoder.finish()?)
+            }
+            #[cfg(feature = "lz4")]
+            CompressionAlgorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable()),
+        }
+    }
+
+    /// Fails if the payload would decompress to more than `limit` bytes.
+    pub fn decompress(&self, bytes: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+        match self {
+            CompressionAlgorithm::Deflate => {
+                let decoder = flate2::read::DeflateDecoder::new(bytes);
+                let mut decompressed = Vec::new();
+                decoder
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > limit {
+                    return Err(too_large(limit));
+                }
+                Ok(decompressed)
+            }
+            #[cfg(feature = "lz4")]
+            CompressionAlgorithm::Lz4 => {
+                let size = bytes
+                    .get(..4)
+                    .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+                    .ok_or_else(|| anyhow!("truncated lz4 payload"))?;
+                if size > limit {
+                    return Err(too_large(limit));
+                }
+                Ok(lz4_flex::decompress_size_prepended(bytes)?)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(self.unavailable()),
+        }
+    }
+
+    fn unavailable(&self) -> Error {
+        anyhow!("compression {:?} is not enabled in this build", self)
+    }
+}
+
+fn too_large(limit: usize) -> Error {
+    anyhow!("payload decompresses to more than {} bytes", limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deflate_round_trip() {
+        let data = vec![42u8; 4096];
+        let compressed = CompressionAlgorithm::Deflate.compress(6, &data).unwrap();
+        assert!(compressed.len() < data.len());
+        let decompressed = CompressionAlgorithm::Deflate
+            .decompress(&compressed, data.len())
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn deflate_respects_limit() {
+        let compressed = CompressionAlgorithm::Deflate
+            .compress(9, &vec![0u8; 1024 * 1024])
+            .unwrap();
+        assert!(CompressionAlgorithm::Deflate
+            .decompress(&compressed, 1024)
+            .is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_respects_limit() {
+        let compressed = CompressionAlgorithm::Lz4
+            .compress(0, &vec![0u8; 1024 * 1024])
+            .unwrap();
+        assert!(CompressionAlgorithm::Lz4
+            .decompress(&compressed, 1024)
+            .is_err());
+        assert!(CompressionAlgorithm::Lz4.decompress(&[1, 2], 1024).is_err());
+    }
+}
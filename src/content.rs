@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashSet};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::identity::EventPersonaRotated;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::rematch::EventRematchStarted;
+use crate::{EventConnectedPeer, EventVeilidInitialized};
+
+pub type ContentHash = [u8; 32];
+
+/// Game content both peers must agree on, such as rule versions and asset bundles.
+#[derive(Resource, Default, Clone, Debug)]
+pub struct ContentManifest {
+    items: BTreeMap<String, ContentHash>,
+}
+
+impl ContentManifest {
+    /// Declares an item by hashing `content`, e.g. a version string or an asset's bytes.
+    pub fn declare(&mut self, name: impl Into<String>, content: impl AsRef<[u8]>) -> &mut Self {
+        self.items
+            .insert(name.into(), *blake3::hash(content.as_ref()).as_bytes());
+        self
+    }
+
+    /// Declares an item by a precomputed hash.
+    pub fn declare_hash(&mut self, name: impl Into<String>, hash: ContentHash) -> &mut Self {
+        self.items.insert(name.into(), hash);
+        self
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<ContentHash> {
+        self.items.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ContentHash> {
+        self.items.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &ContentHash)> {
+        self.items.iter()
+    }
+
+    /// Items whose hashes differ between `self` and `other`, including items declared on
+    /// only one side.
+    pub fn diff(&self, other: &ContentManifest) -> Vec<ContentDifference> {
+        let names: std::collections::BTreeSet<_> =
+            self.items.keys().chain(other.items.keys()).collect();
+
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let local = self.items.get(name).copied();
+                let remote = other.items.get(name).copied();
+                (local != remote).then(|| ContentDifference {
+                    name: name.clone(),
+                    local,
+                    remote,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentDifference {
+    pub name: String,
+    /// `None` if the item isn't declared locally.
+    pub local: Option<ContentHash>,
+    /// `None` if the item isn't declared by the peer.
+    pub remote: Option<ContentHash>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ContentMessage {
+    Manifest {
+        items: Vec<(String, ContentHash)>,
+        reply: bool,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Result of comparing manifests with a peer. `differences` is empty when both sides
+/// declared identical content.
+#[derive(Event)]
+pub struct EventContentChecked {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub differences: Vec<ContentDifference>,
+}
+
+// -------
+// Systems
+// -------
+
+fn manifest_message(manifest: &ContentManifest, reply: bool) -> ProtocolMessage {
+    ProtocolMessage::Content(ContentMessage::Manifest {
+        items: manifest
+            .iter()
+            .map(|(name, hash)| (name.clone(), *hash))
+            .collect(),
+        reply,
+    })
+}
+
+// Peers whose content was compared this session. EventConnectedPeer fires after every
+// message that is sent while awaiting a peer, so the manifest is sent only once.
+#[derive(Resource, Default)]
+pub(crate) struct CheckedPeers(HashSet<CryptoTyped<CryptoKey>>);
+
+pub(crate) fn send_manifest_on_connect(
+    mut er_connected_peer: EventReader<EventConnectedPeer>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut checked_peers: ResMut<CheckedPeers>,
+    manifest: Res<ContentManifest>,
+) {
+    for e in er_connected_peer.read() {
+        if !checked_peers.0.insert(e.dht_key) {
+            continue;
+        }
+        ew_send_protocol.send(EventSendProtocol {
+            message: manifest_message(&manifest, false),
+            dht_key: e.dht_key,
+        });
+    }
+}
+
+pub(crate) fn on_ev_receive_manifest(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_content_checked: EventWriter<EventContentChecked>,
+    mut checked_peers: ResMut<CheckedPeers>,
+    manifest: Res<ContentManifest>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Content(ContentMessage::Manifest { items, reply }) = &e.message else {
+            continue;
+        };
+        checked_peers.0.insert(e.dht_key);
+
+        // Answer with our own manifest so both sides run the comparison, even when only
+        // one of them saw the connection.
+        if !reply {
+            ew_send_protocol.send(EventSendProtocol {
+                message: manifest_message(&manifest, true),
+                dht_key: e.dht_key,
+            });
+        }
+
+        let remote = ContentManifest {
+            items: items.iter().cloned().collect(),
+        };
+        ew_content_checked.send(EventContentChecked {
+            dht_key: e.dht_key,
+            differences: manifest.diff(&remote),
+        });
+    }
+}
+
+// A rematch or a new network session compares the content again.
+pub(crate) fn clear_checked_peers(
+    mut er_rematch_started: EventReader<EventRematchStarted>,
+    mut er_veilid_initialized: EventReader<EventVeilidInitialized>,
+    mut er_persona_rotated: EventReader<EventPersonaRotated>,
+    mut checked_peers: ResMut<CheckedPeers>,
+) {
+    let restarted = er_rematch_started.read().count()
+        + er_veilid_initialized.read().count()
+        + er_persona_rotated.read().count();
+    if restarted > 0 {
+        checked_peers.0.clear();
+    }
+}
@@ -0,0 +1,888 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::anyhow;
+use bevy::prelude::*;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use rand::seq::SliceRandom;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::simultaneous::EventCheatDetected;
+use crate::{EventError, VeilidApp};
+
+const MAX_DECK_SIZE: u32 = 1024;
+
+type Point = [u8; 32];
+
+/// Who holds a card of a shuffled deck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardOwner {
+    /// Still in the deck, unknown to both players.
+    Deck,
+    /// In the local player's hand.
+    Local,
+    /// In the other player's hand.
+    Remote,
+    /// Played or revealed from the deck, known to both players.
+    Public,
+}
+
+struct Deck {
+    dht_key: CryptoTyped<CryptoKey>,
+    size: u32,
+    initiator: bool,
+    secret: Scalar,
+    keys: Vec<Scalar>,
+    stages: Vec<Vec<RistrettoPoint>>,
+    owners: Vec<CardOwner>,
+    cards: Vec<Option<u32>>,
+    remote_keys: HashMap<u32, Scalar>,
+    pending_draws: HashSet<u32>,
+    shared: HashSet<u32>,
+    verification_sent: bool,
+}
+
+impl Deck {
+    fn new(dht_key: CryptoTyped<CryptoKey>, size: u32, initiator: bool) -> Self {
+        Self {
+            dht_key,
+            size,
+            initiator,
+            secret: random_scalar(),
+            keys: (0..size).map(|_| random_scalar()).collect(),
+            stages: Vec::new(),
+            owners: vec![CardOwner::Deck; size as usize],
+            cards: vec![None; size as usize],
+            remote_keys: HashMap::new(),
+            pending_draws: HashSet::new(),
+            shared: HashSet::new(),
+            verification_sent: false,
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.stages.len() == 4
+    }
+
+    fn encrypt_and_shuffle(&self, points: &[RistrettoPoint]) -> Vec<RistrettoPoint> {
+        let mut points: Vec<_> = points.iter().map(|p| self.secret * p).collect();
+        points.shuffle(&mut rand::thread_rng());
+        points
+    }
+
+    fn lock(&self, points: &[RistrettoPoint]) -> Vec<RistrettoPoint> {
+        let inverse = self.secret.invert();
+        points
+            .iter()
+            .zip(&self.keys)
+            .map(|(point, key)| key * inverse * point)
+            .collect()
+    }
+
+    // The peer only sends a key for a card we are drawing, a card it reveals from its
+    // hand or the deck, or a card we revealed whose key it still owes us.
+    fn expects_key(&self, position: u32) -> bool {
+        if self.remote_keys.contains_key(&position) {
+            return false;
+        }
+        match self.owners.get(position as usize) {
+            Some(CardOwner::Deck | CardOwner::Remote) => true,
+            Some(CardOwner::Public) => self.shared.contains(&position),
+            Some(CardOwner::Local) | None => false,
+        }
+    }
+
+    fn open(&mut self, deck: Uuid, position: u32, remote_key: Scalar) -> Option<u32> {
+        let index = position as usize;
+        let point = (self.keys[index] * remote_key).invert() * self.stages[3][index];
+        let card = (0..self.size).find(|card| card_point(deck, *card) == point)?;
+        self.remote_keys.insert(position, remote_key);
+        self.cards[index] = Some(card);
+        Some(card)
+    }
+
+    fn verify(&self, deck: Uuid, secret: Scalar, keys: &[Scalar]) -> Result<(), &'static str> {
+        if keys.len() != self.size as usize {
+            return Err("revealed the wrong number of card keys");
+        }
+        if self
+            .remote_keys
+            .iter()
+            .any(|(position, key)| keys[*position as usize] != *key)
+        {
+            return Err("revealed card keys that differ from the ones used in play");
+        }
+
+        // The initiator encrypted the plain cards and locked the second stage. The other
+        // player encrypted the first stage and locked the third.
+        let (source, encrypted, unlocked, locked) = if self.initiator {
+            let source = &self.stages[0];
+            (
+                source.clone(),
+                &self.stages[1],
+                &self.stages[2],
+                &self.stages[3],
+            )
+        } else {
+            let source = (0..self.size).map(|card| card_point(deck, card)).collect();
+            (source, &self.stages[0], &self.stages[1], &self.stages[2])
+        };
+
+        let mut expected: Vec<Point> = source
+            .iter()
+            .map(|point| (secret * point).compress().to_bytes())
+            .collect();
+        let mut actual: Vec<Point> = encrypted
+            .iter()
+            .map(|point| point.compress().to_bytes())
+            .collect();
+        expected.sort();
+        actual.sort();
+        if expected != actual {
+            return Err("shuffled a deck that is not the encrypted original");
+        }
+
+        let inverse = secret.invert();
+        let relocked = unlocked
+            .iter()
+            .zip(keys)
+            .map(|(point, key)| key * inverse * point);
+        if !relocked.eq(locked.iter().copied()) {
+            return Err("locked cards with keys other than the revealed ones");
+        }
+        Ok(())
+    }
+}
+
+fn card_point(deck: Uuid, card: u32) -> RistrettoPoint {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"bevy_veilid card");
+    hasher.update(deck.as_bytes());
+    hasher.update(&card.to_le_bytes());
+    let mut bytes = [0u8; 64];
+    hasher.finalize_xof().fill(&mut bytes);
+    RistrettoPoint::from_uniform_bytes(&bytes)
+}
+
+fn random_scalar() -> Scalar {
+    let mut bytes = [0u8; 64];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+fn compress(points: &[RistrettoPoint]) -> Vec<Point> {
+    points
+        .iter()
+        .map(|point| point.compress().to_bytes())
+        .collect()
+}
+
+fn decompress(points: &[Point]) -> Option<Vec<RistrettoPoint>> {
+    points
+        .iter()
+        .map(|point| CompressedRistretto(*point).decompress())
+        .collect()
+}
+
+fn scalar(bytes: Point) -> Option<Scalar> {
+    Scalar::from_canonical_bytes(bytes).into()
+}
+
+/// Shuffled decks by id.
+#[derive(Resource, Default)]
+pub struct Decks {
+    decks: HashMap<Uuid, Deck>,
+}
+
+impl Decks {
+    /// Both players have shuffled and locked the deck.
+    pub fn is_ready(&self, deck: Uuid) -> bool {
+        self.decks.get(&deck).is_some_and(Deck::is_ready)
+    }
+
+    pub fn size(&self, deck: Uuid) -> Option<u32> {
+        self.decks.get(&deck).map(|d| d.size)
+    }
+
+    pub fn owner(&self, deck: Uuid, position: u32) -> Option<CardOwner> {
+        self.decks
+            .get(&deck)?
+            .owners
+            .get(position as usize)
+            .copied()
+    }
+
+    /// The card at `position`, if it was revealed to the local player.
+    pub fn card(&self, deck: Uuid, position: u32) -> Option<u32> {
+        *self.decks.get(&deck)?.cards.get(position as usize)?
+    }
+
+    /// Positions and cards of the local player's hand.
+    pub fn hand(&self, deck: Uuid) -> Vec<(u32, u32)> {
+        let Some(deck) = self.decks.get(&deck) else {
+            return Vec::new();
+        };
+        (0..deck.size)
+            .filter(|p| deck.owners[*p as usize] == CardOwner::Local)
+            .filter_map(|p| Some((p, deck.cards[p as usize]?)))
+            .collect()
+    }
+
+    /// Positions still in the deck.
+    pub fn remaining(&self, deck: Uuid) -> Vec<u32> {
+        let Some(deck) = self.decks.get(&deck) else {
+            return Vec::new();
+        };
+        (0..deck.size)
+            .filter(|p| {
+                deck.owners[*p as usize] == CardOwner::Deck && !deck.pending_draws.contains(p)
+            })
+            .collect()
+    }
+
+    pub fn remove(&mut self, deck: Uuid) {
+        self.decks.remove(&deck);
+    }
+
+    pub fn clear(&mut self) {
+        self.decks.clear();
+    }
+
+    // A deck is still being shuffled or a draw waits for the peer's keys
+    pub(crate) fn is_exchanging(&self) -> bool {
+        self.decks
+            .values()
+            .any(|d| !d.is_ready() || !d.pending_draws.is_empty())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum DeckMessage {
+    Shuffle {
+        deck: Uuid,
+        size: u32,
+        cards: Vec<Point>,
+    },
+    Shuffled {
+        deck: Uuid,
+        cards: Vec<Point>,
+    },
+    Locked {
+        deck: Uuid,
+        cards: Vec<Point>,
+    },
+    Ready {
+        deck: Uuid,
+        cards: Vec<Point>,
+    },
+    Draw {
+        deck: Uuid,
+        position: u32,
+    },
+    Key {
+        deck: Uuid,
+        position: u32,
+        key: Point,
+    },
+    Verify {
+        deck: Uuid,
+        secret: Point,
+        keys: Vec<Point>,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Starts shuffling a deck of `size` cards, numbered from 0, with `dht_key`.
+#[derive(Event)]
+pub struct EventShuffleDeck {
+    pub deck: Uuid,
+    pub size: u32,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+impl EventShuffleDeck {
+    pub fn new(size: u32, dht_key: CryptoTyped<CryptoKey>) -> EventShuffleDeck {
+        EventShuffleDeck {
+            deck: Uuid::new_v4(),
+            size,
+            dht_key,
+        }
+    }
+}
+
+/// Takes the card at `position` into the local player's hand. Only the local player
+/// learns the card.
+#[derive(Event)]
+pub struct EventDrawCard {
+    pub deck: Uuid,
+    pub position: u32,
+}
+
+/// Reveals a card from the local player's hand, or a card still in the deck, to both
+/// players.
+#[derive(Event)]
+pub struct EventPlayCard {
+    pub deck: Uuid,
+    pub position: u32,
+}
+
+/// Reveals all of the local player's keys so both players can check that the other
+/// shuffled honestly. This exposes every card, so send it when the game is over.
+#[derive(Event)]
+pub struct EventVerifyDeck {
+    pub deck: Uuid,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventDeckReady {
+    pub deck: Uuid,
+    pub size: u32,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// The other player drew the card at `position` without revealing it.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventCardDrawn {
+    pub deck: Uuid,
+    pub position: u32,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// The card at `position` became known to the local player. `owner` is `None` for cards
+/// revealed from the deck.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventCardRevealed {
+    pub deck: Uuid,
+    pub position: u32,
+    pub card: u32,
+    pub owner: Option<CryptoTyped<CryptoKey>>,
+}
+
+/// Both players tried to draw the card at `position` at the same time. Neither got it and
+/// the card is back in the deck.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventDrawConflict {
+    pub deck: Uuid,
+    pub position: u32,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// The other player revealed its keys and asks for ours. Answer with [`EventVerifyDeck`]
+/// when the game allows it, since that exposes every card.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventDeckVerificationRequested {
+    pub deck: Uuid,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Outcome of checking the other player's shuffle once it revealed its keys.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventDeckVerified {
+    pub deck: Uuid,
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub valid: bool,
+}
+
+// -------
+// Systems
+// -------
+
+fn send(ew_send_protocol: &mut EventWriter<EventSendProtocol>, deck: &Deck, message: DeckMessage) {
+    ew_send_protocol.send(EventSendProtocol {
+        message: ProtocolMessage::Deck(message),
+        dht_key: deck.dht_key,
+    });
+}
+
+fn verify_message(id: Uuid, deck: &Deck) -> DeckMessage {
+    DeckMessage::Verify {
+        deck: id,
+        secret: deck.secret.to_bytes(),
+        keys: deck.keys.iter().map(Scalar::to_bytes).collect(),
+    }
+}
+
+pub(crate) fn on_deck_commands(
+    mut er_shuffle_deck: EventReader<EventShuffleDeck>,
+    mut er_draw_card: EventReader<EventDrawCard>,
+    mut er_play_card: EventReader<EventPlayCard>,
+    mut er_verify_deck: EventReader<EventVerifyDeck>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_error: EventWriter<EventError>,
+    mut decks: ResMut<Decks>,
+) {
+    for e in er_shuffle_deck.read() {
+        if e.size > MAX_DECK_SIZE {
+            ew_error.send(EventError(anyhow!(
+                "deck of {} cards is larger than {MAX_DECK_SIZE}",
+                e.size
+            )));
+            continue;
+        }
+        let mut deck = Deck::new(e.dht_key, e.size, true);
+        let cards: Vec<_> = (0..e.size).map(|card| card_point(e.deck, card)).collect();
+        let shuffled = deck.encrypt_and_shuffle(&cards);
+        send(
+            &mut ew_send_protocol,
+            &deck,
+            DeckMessage::Shuffle {
+                deck: e.deck,
+                size: e.size,
+                cards: compress(&shuffled),
+            },
+        );
+        deck.stages.push(shuffled);
+        decks.decks.insert(e.deck, deck);
+    }
+
+    for e in er_draw_card.read() {
+        let Some(deck) = decks.decks.get_mut(&e.deck).filter(|d| d.is_ready()) else {
+            ew_error.send(EventError(anyhow!("deck {} is not ready", e.deck)));
+            continue;
+        };
+        let available = deck.owners.get(e.position as usize) == Some(&CardOwner::Deck)
+            && !deck.pending_draws.contains(&e.position);
+        if !available {
+            ew_error.send(EventError(anyhow!(
+                "card {} is not in deck {}",
+                e.position,
+                e.deck
+            )));
+            continue;
+        }
+
+        deck.pending_draws.insert(e.position);
+        send(
+            &mut ew_send_protocol,
+            deck,
+            DeckMessage::Draw {
+                deck: e.deck,
+                position: e.position,
+            },
+        );
+    }
+
+    for e in er_play_card.read() {
+        let Some(deck) = decks.decks.get_mut(&e.deck).filter(|d| d.is_ready()) else {
+            ew_error.send(EventError(anyhow!("deck {} is not ready", e.deck)));
+            continue;
+        };
+        let index = e.position as usize;
+        match deck.owners.get(index) {
+            Some(CardOwner::Local) => deck.owners[index] = CardOwner::Public,
+            Some(CardOwner::Deck) if !deck.pending_draws.contains(&e.position) => {
+                deck.owners[index] = CardOwner::Public;
+            }
+            _ => {
+                ew_error.send(EventError(anyhow!(
+                    "card {} of deck {} can't be played",
+                    e.position,
+                    e.deck
+                )));
+                continue;
+            }
+        }
+
+        deck.shared.insert(e.position);
+        send(
+            &mut ew_send_protocol,
+            deck,
+            DeckMessage::Key {
+                deck: e.deck,
+                position: e.position,
+                key: deck.keys[index].to_bytes(),
+            },
+        );
+    }
+
+    for e in er_verify_deck.read() {
+        let Some(deck) = decks.decks.get_mut(&e.deck).filter(|d| d.is_ready()) else {
+            ew_error.send(EventError(anyhow!("deck {} is not ready", e.deck)));
+            continue;
+        };
+        if !deck.verification_sent {
+            deck.verification_sent = true;
+            send(&mut ew_send_protocol, deck, verify_message(e.deck, deck));
+        }
+    }
+}
+
+pub(crate) fn on_ev_receive_deck(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_deck_ready: EventWriter<EventDeckReady>,
+    mut ew_card_drawn: EventWriter<EventCardDrawn>,
+    mut ew_card_revealed: EventWriter<EventCardRevealed>,
+    mut ew_draw_conflict: EventWriter<EventDrawConflict>,
+    mut ew_verification_requested: EventWriter<EventDeckVerificationRequested>,
+    mut ew_deck_verified: EventWriter<EventDeckVerified>,
+    mut ew_cheat_detected: EventWriter<EventCheatDetected>,
+    mut decks: ResMut<Decks>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Deck(message) = &e.message else {
+            continue;
+        };
+        let mut cheat = |reason: &str| {
+            ew_cheat_detected.send(EventCheatDetected {
+                dht_key: e.dht_key,
+                round: 0,
+                reason: reason.to_string(),
+            });
+        };
+
+        match message {
+            DeckMessage::Shuffle {
+                deck: id,
+                size,
+                cards,
+            } => {
+                if *size > MAX_DECK_SIZE || decks.decks.contains_key(id) {
+                    continue;
+                }
+                let Some(cards) = decompress(cards).filter(|c| c.len() == *size as usize) else {
+                    cheat("sent a malformed deck");
+                    continue;
+                };
+
+                let mut deck = Deck::new(e.dht_key, *size, false);
+                let shuffled = deck.encrypt_and_shuffle(&cards);
+                send(
+                    &mut ew_send_protocol,
+                    &deck,
+                    DeckMessage::Shuffled {
+                        deck: *id,
+                        cards: compress(&shuffled),
+                    },
+                );
+                deck.stages.push(cards);
+                deck.stages.push(shuffled);
+                decks.decks.insert(*id, deck);
+            }
+            DeckMessage::Shuffled { deck: id, cards } | DeckMessage::Locked { deck: id, cards } => {
+                let Some(deck) = decks.decks.get_mut(id).filter(|d| d.dht_key == e.dht_key) else {
+                    continue;
+                };
+                // The initiator expects the second stage, the other player the third
+                let expected_stage = if deck.initiator { 1 } else { 2 };
+                if deck.stages.len() != expected_stage {
+                    continue;
+                }
+                let Some(cards) = decompress(cards).filter(|c| c.len() == deck.size as usize)
+                else {
+                    cheat("sent a malformed deck");
+                    continue;
+                };
+
+                let locked = deck.lock(&cards);
+                let message = if deck.initiator {
+                    DeckMessage::Locked {
+                        deck: *id,
+                        cards: compress(&locked),
+                    }
+                } else {
+                    DeckMessage::Ready {
+                        deck: *id,
+                        cards: compress(&locked),
+                    }
+                };
+                send(&mut ew_send_protocol, deck, message);
+                deck.stages.push(cards);
+                deck.stages.push(locked);
+
+                if deck.is_ready() {
+                    ew_deck_ready.send(EventDeckReady {
+                        deck: *id,
+                        size: deck.size,
+                        dht_key: deck.dht_key,
+                    });
+                }
+            }
+            DeckMessage::Ready { deck: id, cards } => {
+                let Some(deck) = decks.decks.get_mut(id).filter(|d| d.dht_key == e.dht_key) else {
+                    continue;
+                };
+                if !deck.initiator || deck.stages.len() != 3 {
+                    continue;
+                }
+                let Some(cards) = decompress(cards).filter(|c| c.len() == deck.size as usize)
+                else {
+                    cheat("sent a malformed deck");
+                    continue;
+                };
+
+                deck.stages.push(cards);
+                ew_deck_ready.send(EventDeckReady {
+                    deck: *id,
+                    size: deck.size,
+                    dht_key: deck.dht_key,
+                });
+            }
+            DeckMessage::Draw { deck: id, position } => {
+                let Some(deck) = decks
+                    .decks
+                    .get_mut(id)
+                    .filter(|d| d.dht_key == e.dht_key && d.is_ready())
+                else {
+                    continue;
+                };
+                let index = *position as usize;
+                if deck.owners.get(index) != Some(&CardOwner::Deck) {
+                    cheat("drew a card that is not in the deck");
+                    continue;
+                }
+                // Both players drew the same card at once, so neither gets it
+                if deck.pending_draws.remove(position) {
+                    ew_draw_conflict.send(EventDrawConflict {
+                        deck: *id,
+                        position: *position,
+                        dht_key: e.dht_key,
+                    });
+                    continue;
+                }
+
+                deck.owners[index] = CardOwner::Remote;
+                send(
+                    &mut ew_send_protocol,
+                    deck,
+                    DeckMessage::Key {
+                        deck: *id,
+                        position: *position,
+                        key: deck.keys[index].to_bytes(),
+                    },
+                );
+                ew_card_drawn.send(EventCardDrawn {
+                    deck: *id,
+                    position: *position,
+                    dht_key: e.dht_key,
+                });
+            }
+            DeckMessage::Key {
+                deck: id,
+                position,
+                key,
+            } => {
+                let Some(deck) = decks
+                    .decks
+                    .get_mut(id)
+                    .filter(|d| d.dht_key == e.dht_key && d.is_ready())
+                else {
+                    continue;
+                };
+                if !deck.expects_key(*position) {
+                    cheat("sent a key for a card that is not being revealed");
+                    continue;
+                }
+                let index = *position as usize;
+                let owner = deck.owners[index];
+                let Some(key) = scalar(*key) else {
+                    cheat("sent a malformed card key");
+                    continue;
+                };
+
+                let revealed_owner = if deck.pending_draws.remove(position) {
+                    deck.owners[index] = CardOwner::Local;
+                    Some(app.our_dht_key)
+                } else {
+                    match owner {
+                        CardOwner::Remote => {
+                            deck.owners[index] = CardOwner::Public;
+                            Some(e.dht_key)
+                        }
+                        CardOwner::Deck | CardOwner::Public => {
+                            deck.owners[index] = CardOwner::Public;
+                            if deck.shared.insert(*position) {
+                                send(
+                                    &mut ew_send_protocol,
+                                    deck,
+                                    DeckMessage::Key {
+                                        deck: *id,
+                                        position: *position,
+                                        key: deck.keys[index].to_bytes(),
+                                    },
+                                );
+                            }
+                            None
+                        }
+                        CardOwner::Local => continue,
+                    }
+                };
+
+                let Some(card) = deck.open(*id, *position, key) else {
+                    cheat("sent a card key that opens no card");
+                    continue;
+                };
+                ew_card_revealed.send(EventCardRevealed {
+                    deck: *id,
+                    position: *position,
+                    card,
+                    owner: revealed_owner,
+                });
+            }
+            DeckMessage::Verify {
+                deck: id,
+                secret,
+                keys,
+            } => {
+                let Some(deck) = decks
+                    .decks
+                    .get_mut(id)
+                    .filter(|d| d.dht_key == e.dht_key && d.is_ready())
+                else {
+                    continue;
+                };
+                // Our keys stay private until the local game sends EventVerifyDeck
+                if !deck.verification_sent {
+                    ew_verification_requested.send(EventDeckVerificationRequested {
+                        deck: *id,
+                        dht_key: e.dht_key,
+                    });
+                }
+
+                let secret = scalar(*secret);
+                let keys: Option<Vec<_>> = keys.iter().map(|key| scalar(*key)).collect();
+                let result = match (secret, keys) {
+                    (Some(secret), Some(keys)) => deck.verify(*id, secret, &keys),
+                    _ => Err("revealed malformed keys"),
+                };
+
+                if let Err(reason) = result {
+                    cheat(reason);
+                }
+                ew_deck_verified.send(EventDeckVerified {
+                    deck: *id,
+                    dht_key: e.dht_key,
+                    valid: result.is_ok(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn shuffled(size: u32) -> (Uuid, Deck, Deck) {
+        let id = Uuid::new_v4();
+        let dht_key = CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([1; 32]));
+        let mut initiator = Deck::new(dht_key, size, true);
+        let mut other = Deck::new(dht_key, size, false);
+
+        let cards: Vec<_> = (0..size).map(|card| card_point(id, card)).collect();
+        let first = initiator.encrypt_and_shuffle(&cards);
+        initiator.stages.push(first.clone());
+
+        let second = other.encrypt_and_shuffle(&first);
+        other.stages.push(first);
+        other.stages.push(second.clone());
+
+        let third = initiator.lock(&second);
+        initiator.stages.push(second);
+        initiator.stages.push(third.clone());
+
+        let fourth = other.lock(&third);
+        other.stages.push(third);
+        other.stages.push(fourth.clone());
+        initiator.stages.push(fourth);
+
+        assert!(initiator.is_ready() && other.is_ready());
+        (id, initiator, other)
+    }
+
+    #[test]
+    fn both_players_open_the_same_cards() {
+        let (id, mut initiator, mut other) = shuffled(8);
+
+        let mut cards = vec![];
+        for position in 0..8 {
+            let card = initiator
+                .open(id, position, other.keys[position as usize])
+                .unwrap();
+            let same = other
+                .open(id, position, initiator.keys[position as usize])
+                .unwrap();
+            assert_eq!(card, same);
+            cards.push(card);
+        }
+        cards.sort();
+        assert_eq!(cards, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn wrong_key_opens_no_card() {
+        let (id, mut initiator, _) = shuffled(8);
+        assert!(initiator.open(id, 0, random_scalar()).is_none());
+    }
+
+    #[test]
+    fn expects_keys_only_for_cards_being_revealed() {
+        let (id, mut initiator, other) = shuffled(8);
+        initiator.pending_draws.insert(0);
+        initiator.owners[1] = CardOwner::Remote;
+        initiator.owners[2] = CardOwner::Local;
+        initiator.owners[3] = CardOwner::Public;
+        initiator.owners[4] = CardOwner::Public;
+        initiator.shared.insert(4);
+
+        assert!(initiator.expects_key(0));
+        assert!(initiator.expects_key(1));
+        assert!(!initiator.expects_key(2));
+        assert!(!initiator.expects_key(3));
+        assert!(initiator.expects_key(4));
+        assert!(initiator.expects_key(5));
+        assert!(!initiator.expects_key(8));
+
+        initiator.open(id, 4, other.keys[4]).unwrap();
+        assert!(!initiator.expects_key(4));
+    }
+
+    #[test]
+    fn honest_shuffles_verify() {
+        let (id, initiator, other) = shuffled(8);
+        assert!(initiator.verify(id, other.secret, &other.keys).is_ok());
+        assert!(other.verify(id, initiator.secret, &initiator.keys).is_ok());
+    }
+
+    #[test]
+    fn detects_wrong_secret() {
+        let (id, initiator, other) = shuffled(8);
+        assert!(initiator.verify(id, random_scalar(), &other.keys).is_err());
+    }
+
+    #[test]
+    fn detects_wrong_keys() {
+        let (id, initiator, other) = shuffled(8);
+        let mut keys = other.keys.clone();
+        keys.swap(0, 1);
+        assert!(initiator.verify(id, other.secret, &keys).is_err());
+        assert!(initiator.verify(id, other.secret, &keys[1..]).is_err());
+    }
+
+    #[test]
+    fn detects_keys_differing_from_play() {
+        let (id, mut initiator, other) = shuffled(8);
+        initiator.open(id, 3, other.keys[3]).unwrap();
+
+        let mut keys = other.keys.clone();
+        keys[3] = random_scalar();
+        assert_eq!(
+            initiator.verify(id, other.secret, &keys),
+            Err("revealed card keys that differ from the ones used in play")
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped, Signature};
+
+use crate::codec::CodecKind;
+use crate::compression::CompressionAlgorithm;
+use crate::fragment::Fragment;
+use crate::ordering::Sequence;
+use crate::{timestamp, VeilidSettings};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum PayloadKind {
+    #[default]
+    User,
+    Protocol,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Envelope {
+    #[serde(default)]
+    pub id: Uuid,
+    #[serde(default)]
+    pub kind: PayloadKind,
+    pub codec: CodecKind,
+    #[serde(default)]
+    pub compression: Option<CompressionAlgorithm>,
+    #[serde(default)]
+    pub fragment: Option<Fragment>,
+    #[serde(default)]
+    pub turn: Option<u32>,
+    #[serde(default)]
+    pub sent_at: u64,
+    #[serde(default)]
+    pub prev_hash: Option<[u8; 32]>,
+    #[serde(default)]
+    pub sequence: Option<Sequence>,
+    #[serde(with = "base64_payload")]
+    pub payload: Vec<u8>,
+    #[serde(default)]
+    pub signature: Option<Signature>,
+}
+
+impl Envelope {
+    pub fn new<V: Serialize>(
+        kind: PayloadKind,
+        value: &V,
+        codec: CodecKind,
+        settings: &VeilidSettings,
+    ) -> Result<Self, Error> {
+        let payload = codec.encode(value)?;
+        let (compression, payload) = match settings.compression.as_ref() {
+            Some(settings) if payload.len() >= settings.threshold => (
+                Some(settings.algorithm),
+                settings.algorithm.compress(settings.level, &payload)?,
+            ),
+            _ => (None, payload),
+        };
+
+        Ok(Self {
+            id: Uuid::new_v4(),
+            kind,
+            codec,
+            compression,
+            fragment: None,
+            turn: None,
+            sent_at: timestamp(),
+            prev_hash: None,
+            sequence: None,
+            payload,
+            signature: None,
+        })
+    }
+
+    pub fn into_fragments(self, max_fragment_size: usize) -> Vec<Envelope> {
+        if self.payload.len() <= max_fragment_size {
+            return vec![self];
+        }
+
+        let id = Uuid::new_v4();
+        let chunks: Vec<_> = self.payload.chunks(max_fragment_size.max(1)).collect();
+        let count = chunks.len() as u32;
+
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| Envelope {
+                id: Uuid::new_v4(),
+                kind: self.kind,
+                codec: self.codec,
+                compression: self.compression,
+                fragment: Some(Fragment {
+                    id,
+                    index: index as u32,
+                    count,
+                }),
+                turn: self.turn,
+                sent_at: self.sent_at,
+                prev_hash: self.prev_hash,
+                sequence: None,
+                payload: chunk.to_vec(),
+                signature: None,
+            })
+            .collect()
+    }
+
+    pub fn signed_data(
+        &self,
+        sender: CryptoTyped<CryptoKey>,
+        destination: CryptoTyped<CryptoKey>,
+    ) -> Result<Vec<u8>, Error> {
+        let unsigned = Envelope {
+            signature: None,
+            ..self.clone()
+        };
+        let mut data = format!("{sender}>{destination}").into_bytes();
+        data.extend(serde_json::to_vec(&unsigned)?);
+        Ok(data)
+    }
+
+    pub fn open<V: DeserializeOwned>(&self, limit: usize) -> Result<V, Error> {
+        match self.compression {
+            Some(algorithm) => self
+                .codec
+                .decode(&algorithm.decompress(&self.payload, limit)?),
+            None => self.codec.decode(&self.payload),
+        }
+    }
+}
+
+// Base64 in human-readable formats such as JSON, where bytes would become a list of
+// numbers, and raw bytes in binary formats.
+pub(crate) mod base64_payload {
+    use std::fmt;
+
+    use base64::engine::general_purpose::STANDARD_NO_PAD;
+    use base64::Engine;
+    use serde::de::{SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD_NO_PAD.encode(payload))
+        } else {
+            serializer.serialize_bytes(payload)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            return STANDARD_NO_PAD
+                .decode(encoded)
+                .map_err(serde::de::Error::custom);
+        }
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("bytes")
+        }
+
+        fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
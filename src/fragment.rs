@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::envelope::Envelope;
+use crate::{EventPeerError, VeilidSettings};
+
+/// Splitting of payloads that don't fit into a single Veilid app_call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentationSettings {
+    /// Largest payload, in bytes, sent in a single app_call.
+    pub max_fragment_size: usize,
+    /// Partially received messages are dropped if not completed within this time.
+    pub timeout: Duration,
+    /// Upper bound, in bytes, on payload data buffered for all partially received messages.
+    pub memory_cap: usize,
+}
+
+impl FragmentationSettings {
+    /// Most fragments a message may be split into.
+    pub fn max_fragments(&self) -> u32 {
+        (self.memory_cap / self.max_fragment_size.max(1)).clamp(1, u32::MAX as usize) as u32
+    }
+}
+
+impl Default for FragmentationSettings {
+    fn default() -> Self {
+        Self {
+            max_fragment_size: 30 * 1024,
+            timeout: Duration::from_secs(60),
+            memory_cap: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Fragment {
+    pub id: Uuid,
+    pub index: u32,
+    pub count: u32,
+}
+
+const SLOT_SIZE: usize = std::mem::size_of::<Option<Vec<u8>>>();
+
+struct PartialMessage {
+    envelope: Envelope,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    started: Instant,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct Reassembly {
+    partials: HashMap<(CryptoTyped<CryptoKey>, Uuid), PartialMessage>,
+    buffered: usize,
+}
+
+impl Reassembly {
+    pub fn insert(
+        &mut self,
+        sender: CryptoTyped<CryptoKey>,
+        envelope: Envelope,
+        settings: &FragmentationSettings,
+    ) -> Result<Option<Envelope>, Error> {
+        let Some(fragment) = envelope.fragment else {
+            return Ok(Some(envelope));
+        };
+
+        if fragment.count == 0 || fragment.index >= fragment.count {
+            return Err(anyhow!("malformed fragment {:?}", fragment));
+        }
+        if fragment.count > settings.max_fragments() {
+            return Err(anyhow!(
+                "dropping message {}: {} fragments exceed the limit of {}",
+                fragment.id,
+                fragment.count,
+                settings.max_fragments()
+            ));
+        }
+
+        let key = (sender, fragment.id);
+        let slots = match self.partials.contains_key(&key) {
+            true => 0,
+            false => fragment.count as usize * SLOT_SIZE,
+        };
+        if self.buffered + slots + envelope.payload.len() > settings.memory_cap {
+            self.remove(&key);
+            return Err(anyhow!(
+                "dropping message {}: fragment buffer exceeds {} bytes",
+                fragment.id,
+                settings.memory_cap
+            ));
+        }
+
+        self.buffered += slots;
+        let partial = self.partials.entry(key).or_insert_with(|| PartialMessage {
+            envelope: Envelope {
+                payload: Vec::new(),
+                fragment: None,
+                ..envelope.clone()
+            },
+            chunks: vec![None; fragment.count as usize],
+            received: 0,
+            started: Instant::now(),
+        });
+
+        if partial.chunks.len() != fragment.count as usize {
+            self.remove(&key);
+            return Err(anyhow!(
+                "fragment count mismatch for message {}",
+                fragment.id
+            ));
+        }
+
+        let chunk = &mut partial.chunks[fragment.index as usize];
+        if chunk.is_none() {
+            self.buffered += envelope.payload.len();
+            *chunk = Some(envelope.payload);
+            partial.received += 1;
+        }
+
+        if partial.received < fragment.count {
+            return Ok(None);
+        }
+
+        let mut partial = self.remove(&key).unwrap();
+        partial.envelope.payload = partial.chunks.into_iter().flatten().flatten().collect();
+        Ok(Some(partial.envelope))
+    }
+
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(CryptoTyped<CryptoKey>, Uuid)> {
+        let expired: Vec<_> = self
+            .partials
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() > timeout)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired.iter() {
+            self.remove(key);
+        }
+
+        expired
+    }
+
+    pub(crate) fn touch(&mut self) {
+        let now = Instant::now();
+        for partial in self.partials.values_mut() {
+            partial.started = now;
+        }
+    }
+
+    fn remove(&mut self, key: &(CryptoTyped<CryptoKey>, Uuid)) -> Option<PartialMessage> {
+        let partial = self.partials.remove(key)?;
+        self.buffered -= partial.chunks.len() * SLOT_SIZE
+            + partial
+                .chunks
+                .iter()
+                .flatten()
+                .map(|chunk| chunk.len())
+                .sum::<usize>();
+        Some(partial)
+    }
+}
+
+pub(crate) fn expire_partial_messages(
+    mut reassembly: ResMut<Reassembly>,
+    mut ew_peer_error: EventWriter<EventPeerError>,
+    settings: Res<VeilidSettings>,
+) {
+    for (dht_key, id) in reassembly.expire(settings.fragmentation.timeout) {
+        ew_peer_error.send(EventPeerError {
+            dht_key,
+            reason: format!("timed out waiting for fragments of message {id}"),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::codec::CodecKind;
+    use crate::envelope::PayloadKind;
+
+    fn peer() -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([1; 32]))
+    }
+
+    fn fragments(len: usize, max_fragment_size: usize) -> Vec<Envelope> {
+        let payload = vec![7u8; len];
+        Envelope::new(
+            PayloadKind::User,
+            &payload,
+            CodecKind::Json,
+            &VeilidSettings::default(),
+        )
+        .unwrap()
+        .into_fragments(max_fragment_size)
+    }
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let settings = FragmentationSettings::default();
+        let mut reassembly = Reassembly::default();
+        let mut pieces = fragments(1000, 64);
+        let original: Vec<u8> = pieces.iter().flat_map(|p| p.payload.clone()).collect();
+        pieces.reverse();
+
+        let last = pieces.pop().unwrap();
+        for piece in pieces {
+            assert!(reassembly
+                .insert(peer(), piece, &settings)
+                .unwrap()
+                .is_none());
+        }
+        let envelope = reassembly.insert(peer(), last, &settings).unwrap().unwrap();
+        assert_eq!(envelope.payload, original);
+        assert!(envelope.fragment.is_none());
+        assert_eq!(reassembly.buffered, 0);
+    }
+
+    #[test]
+    fn rejects_malformed_counts() {
+        let settings = FragmentationSettings::default();
+        let mut reassembly = Reassembly::default();
+        let mut piece = fragments(1000, 64).remove(0);
+
+        piece.fragment.as_mut().unwrap().count = 0;
+        assert!(reassembly.insert(peer(), piece.clone(), &settings).is_err());
+
+        let fragment = piece.fragment.as_mut().unwrap();
+        fragment.count = 2;
+        fragment.index = 2;
+        assert!(reassembly.insert(peer(), piece, &settings).is_err());
+        assert_eq!(reassembly.buffered, 0);
+    }
+
+    #[test]
+    fn rejects_huge_counts() {
+        let settings = FragmentationSettings::default();
+        let mut reassembly = Reassembly::default();
+        let mut piece = fragments(1000, 64).remove(0);
+        piece.fragment.as_mut().unwrap().count = u32::MAX;
+
+        assert!(reassembly.insert(peer(), piece, &settings).is_err());
+        assert!(reassembly.partials.is_empty());
+    }
+
+    #[test]
+    fn rejects_changed_counts() {
+        let settings = FragmentationSettings::default();
+        let mut reassembly = Reassembly::default();
+        let mut pieces = fragments(1000, 64);
+        let mut second = pieces.remove(1);
+        second.fragment.as_mut().unwrap().count += 1;
+
+        assert!(reassembly
+            .insert(peer(), pieces.remove(0), &settings)
+            .unwrap()
+            .is_none());
+        assert!(reassembly.insert(peer(), second, &settings).is_err());
+        assert_eq!(reassembly.buffered, 0);
+    }
+
+    #[test]
+    fn enforces_memory_cap() {
+        let settings = FragmentationSettings {
+            max_fragment_size: 64,
+            memory_cap: 256,
+            ..Default::default()
+        };
+        let mut reassembly = Reassembly::default();
+        let pieces = fragments(1000, 64);
+
+        let result: Result<Vec<_>, _> = pieces
+            .into_iter()
+            .map(|piece| reassembly.insert(peer(), piece, &settings))
+            .collect();
+        assert!(result.is_err());
+        assert_eq!(reassembly.buffered, 0);
+    }
+
+    #[test]
+    fn expires_stale_messages() {
+        let settings = FragmentationSettings::default();
+        let mut reassembly = Reassembly::default();
+        let piece = fragments(1000, 64).remove(0);
+        let id = piece.fragment.unwrap().id;
+        reassembly.insert(peer(), piece, &settings).unwrap();
+
+        assert!(reassembly.expire(Duration::from_secs(60)).is_empty());
+        assert_eq!(reassembly.expire(Duration::ZERO), vec![(peer(), id)]);
+        assert_eq!(reassembly.buffered, 0);
+    }
+}
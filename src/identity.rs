@@ -0,0 +1,400 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::utils::CRYPTO_KIND;
+use veilid_duplex::veilid::VeilidDuplex;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped, DHTSchema, KeyPair};
+
+use crate::deck::Decks;
+use crate::outcome::GameResult;
+use crate::signing::{ReceivedMessages, RecordOwners};
+use crate::simultaneous::OpenRounds;
+use crate::transport::{new_private_route, Routes};
+use crate::turn::TurnState;
+use crate::{
+    spawn_network_loop, timestamp, EventError, NetworkLoop, TasksRutime, VeilidApp, VeilidSettings,
+};
+
+/// Who the local player is across runs, see [`VeilidSettings::identity`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum IdentitySettings {
+    /// A new node keypair and DHT key for every run.
+    #[default]
+    Anonymous,
+    /// Keys stored at `path` and reused on every run. On Unix the file has mode 0600.
+    Persistent { path: PathBuf },
+}
+
+impl IdentitySettings {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            IdentitySettings::Anonymous => None,
+            IdentitySettings::Persistent { path } => Some(path),
+        }
+    }
+}
+
+/// The keys of a persistent identity: the node keypair and the DHT record peers reach us
+/// through.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Identity {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: u64,
+    node_keypair: KeyPair,
+    dht_keypair: KeyPair,
+}
+
+impl Identity {
+    pub(crate) fn of(app: &VeilidDuplex) -> Self {
+        Self {
+            dht_key: app.our_dht_key,
+            created_at: timestamp(),
+            node_keypair: app.node_keypair,
+            dht_keypair: app.dht_keypair,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_private(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    /// Reads the identity at `path`, or `None` if there is none yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>, Error> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn connect(self) -> Result<VeilidDuplex, Error> {
+        VeilidDuplex::new(
+            Some(self.node_keypair),
+            Some((self.dht_key, self.dht_keypair)),
+        )
+        .await
+    }
+}
+
+pub(crate) fn write_private(path: impl AsRef<Path>, contents: &[u8]) -> Result<(), Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, so an existing one is locked down as well
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    Ok(file.write_all(contents)?)
+}
+
+pub(crate) async fn start_node(
+    path: Option<PathBuf>,
+) -> Result<(VeilidDuplex, Option<bool>), Error> {
+    let Some(path) = path else {
+        return Ok((VeilidDuplex::new(None, None).await?, None));
+    };
+    if let Some(identity) = Identity::load(&path)? {
+        return Ok((identity.connect().await?, Some(false)));
+    }
+
+    let app = VeilidDuplex::new(None, None).await?;
+    Identity::of(&app).save(&path)?;
+    Ok((app, Some(true)))
+}
+
+struct Persona {
+    route: CryptoKey,
+    dht_key: CryptoTyped<CryptoKey>,
+    dht_keypair: KeyPair,
+}
+
+async fn create_persona(app: &VeilidDuplex) -> Result<Persona, Error> {
+    let (route, blob) = new_private_route(app).await?;
+
+    let result = async {
+        let record = app
+            .routing_context
+            .create_dht_record(DHTSchema::dflt(1)?, Some(CRYPTO_KIND))
+            .await?;
+        let dht_key = *record.key();
+        let secret = record
+            .owner_secret()
+            .ok_or_else(|| anyhow!("persona record has no owner secret"))?;
+        let dht_keypair = KeyPair::new(*record.owner(), *secret);
+        app.routing_context
+            .set_dht_value(dht_key, 0, blob, None)
+            .await?;
+        app.routing_context.close_dht_record(dht_key).await?;
+        Ok::<_, Error>((dht_key, dht_keypair))
+    }
+    .await;
+
+    match result {
+        Ok((dht_key, dht_keypair)) => Ok(Persona {
+            route,
+            dht_key,
+            dht_keypair,
+        }),
+        Err(err) => {
+            let _ = app.api.release_private_route(route).await;
+            Err(err)
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct PersonaRotation {
+    in_progress: bool,
+}
+
+// ------
+// Events
+// ------
+
+/// Gives the running node a new DHT key and private route, so peers can't link it to the
+/// old ones. Messages already on their way to the old key are lost. Refused while a
+/// simultaneous round, a deck exchange or the game result is still open.
+#[derive(Event)]
+pub struct EventRotatePersona;
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventPersonaRotated {
+    pub old: CryptoTyped<CryptoKey>,
+    pub new: CryptoTyped<CryptoKey>,
+}
+
+/// Replaces the stored identity with a new one on the next run. The running node keeps
+/// its keys.
+#[derive(Event)]
+pub struct EventRotateIdentity;
+
+/// Deletes the stored identity and plays anonymously from now on.
+#[derive(Event)]
+pub struct EventDiscardIdentity;
+
+/// The persistent identity is in use. `created` is set on the run that stored it.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventIdentityReady {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub created: bool,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventIdentityRotated;
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventIdentityDiscarded;
+
+// -------
+// Systems
+// -------
+
+fn remove_identity(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn on_identity_commands(
+    mut er_rotate_identity: EventReader<EventRotateIdentity>,
+    mut er_discard_identity: EventReader<EventDiscardIdentity>,
+    mut ew_identity_rotated: EventWriter<EventIdentityRotated>,
+    mut ew_identity_discarded: EventWriter<EventIdentityDiscarded>,
+    mut ew_error: EventWriter<EventError>,
+    mut settings: ResMut<VeilidSettings>,
+) {
+    for _ in er_rotate_identity.read() {
+        let Some(path) = settings.identity.path() else {
+            continue;
+        };
+        match remove_identity(path) {
+            Ok(()) => {
+                ew_identity_rotated.send(EventIdentityRotated);
+            }
+            Err(err) => {
+                ew_error.send(EventError(err));
+            }
+        }
+    }
+
+    for _ in er_discard_identity.read() {
+        let Some(path) = settings.identity.path() else {
+            continue;
+        };
+        match remove_identity(path) {
+            Ok(()) => {
+                settings.identity = IdentitySettings::Anonymous;
+                ew_identity_discarded.send(EventIdentityDiscarded);
+            }
+            Err(err) => {
+                ew_error.send(EventError(err));
+            }
+        }
+    }
+}
+
+pub(crate) fn on_ev_rotate_persona(
+    mut er_rotate_persona: EventReader<EventRotatePersona>,
+    mut ew_error: EventWriter<EventError>,
+    mut rotation: ResMut<PersonaRotation>,
+    open_rounds: Res<OpenRounds>,
+    decks: Res<Decks>,
+    result: Res<GameResult>,
+    veilid_app: Res<VeilidApp>,
+    settings: Res<VeilidSettings>,
+    runtime: ResMut<TasksRutime>,
+) {
+    if er_rotate_persona.read().count() == 0 || rotation.in_progress {
+        return;
+    }
+    // The peer still expects these under the old key
+    let busy = if open_rounds.any() {
+        Some("a simultaneous move round is open")
+    } else if decks.is_exchanging() {
+        Some("a deck is being shuffled or drawn from")
+    } else if result.is_pending() {
+        Some("the game result is not settled")
+    } else {
+        None
+    };
+    if let Some(reason) = busy {
+        ew_error.send(EventError(anyhow!("can't rotate the persona, {reason}")));
+        return;
+    }
+    let Some(veilid_app) = veilid_app.app.clone() else {
+        return;
+    };
+    rotation.in_progress = true;
+    // The stored identity is still wanted on the next run, so only anonymous records go.
+    let delete_old = settings.identity == IdentitySettings::Anonymous;
+
+    runtime.spawn_background_task(move |mut ctx| async move {
+        let result = create_persona(&veilid_app).await;
+        let old_route = veilid_app.our_route;
+        let old = veilid_app.our_dht_key;
+        let rotated = result.is_ok();
+
+        ctx.run_on_main_thread(move |ctx| {
+            let world = ctx.world;
+            world.resource_mut::<PersonaRotation>().in_progress = false;
+            let persona = match result {
+                Ok(persona) => persona,
+                Err(err) => {
+                    world.send_event(EventError(err));
+                    return;
+                }
+            };
+
+            let new = persona.dht_key;
+            let mut veilid_app = world.resource_mut::<VeilidApp>();
+            let Some(app) = veilid_app.app.as_mut() else {
+                return;
+            };
+            app.our_route = persona.route;
+            app.our_dht_key = persona.dht_key;
+            app.dht_keypair = persona.dht_keypair;
+            // The running loop holds a copy of the old persona and would re-pin its route
+            // to the old key, so it is replaced.
+            let app = app.clone();
+            spawn_network_loop(
+                world.resource::<TasksRutime>(),
+                app,
+                world.resource::<Routes>().clone(),
+                world.resource::<RecordOwners>().clone(),
+                world.resource::<ReceivedMessages>().clone(),
+                world.resource::<NetworkLoop>(),
+            );
+            world.resource_mut::<TurnState>().replace_player(old, new);
+            world.send_event(EventPersonaRotated { old, new });
+        })
+        .await;
+
+        if rotated {
+            let _ = veilid_app.api.release_private_route(old_route).await;
+            if delete_old {
+                let _ = veilid_app.routing_context.delete_dht_record(old).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn key(byte: u8) -> CryptoKey {
+        CryptoKey::new([byte; 32])
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("bevy_veilid-{}", Uuid::new_v4()))
+            .join(name)
+    }
+
+    #[test]
+    fn saves_and_loads_an_identity() {
+        let path = temp_path("identity.json");
+        let identity = Identity {
+            dht_key: CryptoTyped::new(CRYPTO_KIND_VLD0, key(1)),
+            created_at: 42,
+            node_keypair: KeyPair::new(key(2), key(3)),
+            dht_keypair: KeyPair::new(key(4), key(5)),
+        };
+        identity.save(&path).unwrap();
+
+        let loaded = Identity::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.dht_key, identity.dht_key);
+        assert_eq!(loaded.created_at, 42);
+        assert_eq!(loaded.node_keypair, identity.node_keypair);
+        assert_eq!(loaded.dht_keypair, identity.dht_keypair);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn loads_nothing_without_a_file() {
+        assert!(Identity::load(temp_path("identity.json"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_a_damaged_file() {
+        let path = temp_path("identity.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "{\"dht_key\":").unwrap();
+        assert!(Identity::load(&path).is_err());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_files_readable_by_their_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("keys");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        write_private(&path, b"first").unwrap();
+        assert_eq!(mode(&path), 0o600);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"second").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
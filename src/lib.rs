@@ -1,14 +1,135 @@
 #![allow(clippy::too_many_arguments, clippy::type_complexity)]
-#![feature(async_closure)]
 
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use bevy::prelude::*;
 
 #[cfg(not(target_arch = "wasm32"))]
 use copypasta::*;
 
+mod assets;
+mod clock;
+mod codec;
+mod compression;
+mod content;
+mod deck;
+mod envelope;
+mod fragment;
+mod identity;
+mod lockstep;
+mod mailbox;
+mod ordering;
+mod outcome;
+mod pause;
+mod protocol;
+mod random;
+mod rematch;
+mod replay;
+mod resync;
+mod save;
+mod signing;
+mod simultaneous;
+mod takeback;
+mod transcript;
+mod transfer;
+mod transport;
+mod turn;
+mod validation;
+
+pub use assets::{TranscriptLoader, VeilidAssetPlugin, VeilidReplayLoader};
+use clock::*;
+pub use clock::{EventClockTick, EventTimeExpired, TimeControl, TurnClock};
+pub use codec::*;
+pub use compression::*;
+use content::*;
+pub use content::{ContentDifference, ContentHash, ContentManifest, EventContentChecked};
+use deck::*;
+pub use deck::{
+    CardOwner, Decks, EventCardDrawn, EventCardRevealed, EventDeckReady,
+    EventDeckVerificationRequested, EventDeckVerified, EventDrawCard, EventDrawConflict,
+    EventPlayCard, EventShuffleDeck, EventVerifyDeck,
+};
+use envelope::{Envelope, PayloadKind};
+pub use erased_serde;
+pub use fragment::FragmentationSettings;
+use fragment::*;
+use identity::*;
+pub use identity::{
+    EventDiscardIdentity, EventIdentityDiscarded, EventIdentityReady, EventIdentityRotated,
+    EventPersonaRotated, EventRotateIdentity, EventRotatePersona, Identity, IdentitySettings,
+};
+use lockstep::*;
+pub use lockstep::{EventDesync, Lockstep, LockstepLog, StateHasher, TurnHashes};
+use mailbox::*;
+pub use mailbox::{
+    Correspondence, CorrespondenceSettings, EventCloseMailbox, EventMailboxOpened,
+    EventOpenMailbox, EventPeerMailboxClosed, EventPeerMailboxOpened,
+};
+use ordering::*;
+use outcome::*;
+pub use outcome::{
+    Conclusion, ConclusionReason, EventAcceptDraw, EventConfirmResult, EventDeclineDraw,
+    EventDrawDeclined, EventDrawOffered, EventGameConcluded, EventOfferDraw, EventResign,
+    EventResultDisputed, EventResultProposed, GameOutcome, GameResult, ResultSignature,
+};
+use pause::*;
+pub use pause::{
+    EventAcceptPauseRequest, EventDeclinePauseRequest, EventPauseDeclined, EventPauseRequested,
+    EventPaused, EventRequestPause, EventRequestResume, EventResumed, Pause,
+};
+use protocol::*;
+use random::*;
+pub use random::{EventSharedRandom, SessionRng, SharedRandom};
+use rematch::*;
+pub use rematch::{
+    EventAcceptRematch, EventDeclineRematch, EventProposeRematch, EventRematchDeclined,
+    EventRematchProposed, EventRematchStarted, Rematch,
+};
+use replay::*;
+pub use replay::{
+    EventReplayFinished, EventReplayRestarted, ReplayMessage, ReplayPlayer, ReplayPlugin,
+    ReplayRecorder, VeilidReplay,
+};
+use resync::*;
+pub use resync::{EventRequestResync, EventResynced, SnapshotId, SnapshotRegistry};
+use save::*;
+pub use save::{
+    EventGameLoaded, EventGameSaved, EventLoadGame, EventResumeSession, EventSaveGame,
+    EventSessionResumeFailed, EventSessionResumed, SavedGame,
+};
+use signing::{verify_envelope, ReceivedMessages, RecordOwners};
+use simultaneous::OpenRounds;
+pub use simultaneous::{
+    EventCheatDetected, EventCommitMove, EventMoveCommitted, EventMovesRevealed, SimultaneousMove,
+    SimultaneousMovePlugin,
+};
+use takeback::*;
+pub use takeback::{
+    EventAcceptTakeback, EventDeclineTakeback, EventRequestTakeback, EventTakebackDeclined,
+    EventTakebackRequested, EventTakenBack, Reversible, Takeback,
+};
+use transcript::*;
+pub use transcript::{
+    EventSignTranscript, EventTranscriptMismatch, EventTranscriptSigned, Transcript,
+    TranscriptEntry, TranscriptHash, TranscriptSignature,
+};
+use transfer::*;
+pub use transfer::{
+    EventCancelTransfer, EventResumeTransfer, EventSendTransfer, EventTransferComplete,
+    EventTransferFailed, EventTransferProgress, TransferDirection, TransferSettings,
+};
+use transport::*;
+use turn::*;
+pub use turn::{
+    EventEndTurn, EventOutOfTurn, EventTurnEnded, EventTurnStarted, OutOfTurnPolicy, TurnSettings,
+    TurnState,
+};
+use validation::*;
+pub use validation::{EventInvalidMove, EventMoveRejected, MoveValidation, MoveValidator};
+
 #[cfg(not(target_arch = "wasm32"))]
 mod tokio_tasks;
 #[cfg(not(target_arch = "wasm32"))]
@@ -53,6 +174,25 @@ pub struct VeilidApp {
     pub other_peer_dht: Option<CryptoTyped<CryptoKey>>,
 }
 
+/// Plugin configuration. Insert before adding [`VeilidPlugin`] to override the defaults.
+#[derive(Resource, Clone, Default)]
+pub struct VeilidSettings {
+    /// Wire format used for outgoing payloads.
+    pub codec: CodecKind,
+    /// Compress outgoing payloads above a size threshold. Disabled by default.
+    pub compression: Option<CompressionSettings>,
+    /// Splitting and reassembly of payloads above the Veilid message size limit.
+    pub fragmentation: FragmentationSettings,
+    /// Chunking, timeouts and size limits of [`EventSendTransfer`] transfers.
+    pub transfer: TransferSettings,
+    /// How [`TurnState`] treats messages sent and received out of turn.
+    pub turns: TurnSettings,
+    /// Mailbox size and polling of correspondence games, see [`EventOpenMailbox`].
+    pub correspondence: CorrespondenceSettings,
+    /// Anonymous by default.
+    pub identity: IdentitySettings,
+}
+
 #[derive(Resource, PartialEq, Eq, Clone, Copy)]
 pub enum VeilidPluginStatus {
     Initializing,
@@ -117,6 +257,37 @@ pub struct EventConnectedPeer {
 #[derive(Event)]
 pub struct EventError(pub Error);
 
+/// A message from `dht_key` was dropped because its signature didn't check out, or it was
+/// a replay of an earlier message. It may not come from `dht_key` at all.
+#[derive(Event, Debug, Clone)]
+pub struct EventUnverifiedMessage {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub reason: String,
+}
+
+/// `dht_key` sent something we had to drop, or a message from it never arrived. Unlike
+/// [`EventError`] this doesn't put the plugin into [`VeilidPluginStatus::Error`].
+#[derive(Event, Debug, Clone)]
+pub struct EventPeerError {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub reason: String,
+}
+
+#[derive(Event)]
+pub(crate) struct EventReceiveEnvelope {
+    pub envelope: Envelope,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event, Clone)]
+pub(crate) struct EventIncomingMessage<T> {
+    pub message: T,
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub turn: Option<u32>,
+    pub sent_at: u64,
+    pub entry: Option<TranscriptEntry>,
+}
+
 // -------
 // Systems
 // -------
@@ -162,9 +333,10 @@ fn on_ev_veilid_message_sent(
     }
 }
 
-fn initialize_veilid_app(runtime: ResMut<TasksRutime>) {
+fn initialize_veilid_app(runtime: ResMut<TasksRutime>, settings: Res<VeilidSettings>) {
+    let identity_path = settings.identity.path().map(|path| path.to_path_buf());
     runtime.spawn_background_task(|mut ctx| async move {
-        let result = VeilidDuplex::new().await;
+        let result = start_node(identity_path).await;
         if result.is_err() {
             ctx.run_on_main_thread(move |ctx| {
                 let world = ctx.world;
@@ -174,7 +346,7 @@ fn initialize_veilid_app(runtime: ResMut<TasksRutime>) {
             return;
         }
 
-        let app = result.unwrap();
+        let (app, identity_created) = result.unwrap();
 
         ctx.run_on_main_thread(move |ctx| {
             let world = ctx.world;
@@ -183,82 +355,260 @@ fn initialize_veilid_app(runtime: ResMut<TasksRutime>) {
                 other_peer_dht: None,
             });
             world.send_event(EventVeilidInitialized);
+            if let Some(created) = identity_created {
+                world.send_event(EventIdentityReady {
+                    dht_key: app.our_dht_key,
+                    created,
+                });
+            }
         })
         .await;
     });
 }
 
-#[derive(Clone)]
-struct ChatAppLogic {
-    ctx: TaskContext,
+async fn receive_frame(
+    ctx: &mut TaskContext,
+    app: &VeilidDuplex,
+    owners: &RecordOwners,
+    received: &ReceivedMessages,
+    frame: &[u8],
+) {
+    let result = match decode_frame(frame) {
+        Ok((sender, envelope)) => verify_envelope(app, owners, received, sender, &envelope)
+            .await
+            .map_err(|err| (Some(sender), err))
+            .map(|()| (sender, envelope)),
+        Err(err) => Err((None, err)),
+    };
+
+    ctx.run_on_main_thread(move |ctx| {
+        let world = ctx.world;
+        match result {
+            Ok((sender, envelope)) => {
+                world.send_event(EventReceiveEnvelope {
+                    envelope,
+                    dht_key: sender,
+                });
+            }
+            Err((Some(sender), err)) => {
+                world.send_event(EventUnverifiedMessage {
+                    dht_key: sender,
+                    reason: err.to_string(),
+                });
+            }
+            Err((None, err)) => {
+                world.send_event(EventError(err));
+            }
+        }
+    })
+    .await;
 }
 
-impl ChatAppLogic {
-    pub fn new(ctx: TaskContext) -> Self {
-        Self { ctx }
+fn on_ev_receive_envelope<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    mut er_receive_envelope: EventReader<EventReceiveEnvelope>,
+    mut ew_incoming_message: EventWriter<EventIncomingMessage<T>>,
+    mut ew_receive_protocol: EventWriter<EventReceiveProtocol>,
+    mut ew_peer_error: EventWriter<EventPeerError>,
+    mut reorder: ResMut<Reorder>,
+    mut reassembly: ResMut<Reassembly>,
+    pause: Res<Pause>,
+    settings: Res<VeilidSettings>,
+) {
+    let mut ordered = vec![];
+    for e in er_receive_envelope.read() {
+        match reorder.insert(e.dht_key, e.envelope.clone(), &settings.fragmentation) {
+            Ok(envelopes) => ordered.extend(envelopes.into_iter().map(|env| (e.dht_key, env))),
+            Err(err) => {
+                ew_peer_error.send(EventPeerError {
+                    dht_key: e.dht_key,
+                    reason: err.to_string(),
+                });
+            }
+        }
+    }
+    if !pause.is_paused() {
+        for (dht_key, skipped, envelopes) in reorder.expire(settings.fragmentation.timeout) {
+            ew_peer_error.send(EventPeerError {
+                dht_key,
+                reason: format!("{skipped} messages never arrived"),
+            });
+            ordered.extend(envelopes.into_iter().map(|env| (dht_key, env)));
+        }
     }
-}
-
-impl<T: DeserializeOwned + Serialize + Sync + Send + Clone + 'static> AppLogic<T> for ChatAppLogic {
-    async fn on_message(&mut self, message: AppMessage<T>) {
-        let message = message.clone();
 
-        self.ctx
-            .run_on_main_thread(move |ctx| {
-                let world = ctx.world;
+    for (dht_key, envelope) in ordered {
+        let envelope = match reassembly.insert(dht_key, envelope, &settings.fragmentation) {
+            Ok(Some(envelope)) => envelope,
+            Ok(None) => continue,
+            Err(err) => {
+                ew_peer_error.send(EventPeerError {
+                    dht_key,
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+        };
 
-                world.send_event(EventReceiveMessage {
-                    message: message.data,
-                    dht_key: message.dht_record,
+        let limit = settings.fragmentation.memory_cap;
+        let result = match envelope.kind {
+            PayloadKind::User => envelope.open::<T>(limit).map(|message| {
+                ew_incoming_message.send(EventIncomingMessage {
+                    message,
+                    dht_key,
+                    turn: envelope.turn,
+                    sent_at: envelope.sent_at,
+                    entry: envelope
+                        .prev_hash
+                        .map(|prev| TranscriptEntry::new(dht_key, &envelope, prev)),
                 });
-            })
-            .await;
+            }),
+            PayloadKind::Protocol => envelope.open::<ProtocolMessage>(limit).map(|message| {
+                ew_receive_protocol.send(EventReceiveProtocol { message, dht_key });
+            }),
+        };
+
+        if let Err(err) = result {
+            ew_peer_error.send(EventPeerError {
+                dht_key,
+                reason: err.to_string(),
+            });
+        }
     }
 }
 
-fn event_on_veilid_initialized<
+fn on_ev_incoming_message<
     T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
 >(
-    mut veilid_plugin_status: ResMut<VeilidPluginStatus>,
-    mut e_veilid_initialized: EventReader<EventVeilidInitialized>,
-    runtime: ResMut<TasksRutime>,
-    veilid_app: Res<VeilidApp>,
+    mut er_incoming_message: EventReader<EventIncomingMessage<T>>,
+    mut er_rematch_started: EventReader<EventRematchStarted>,
+    mut ew_receive_message: EventWriter<EventReceiveMessage<T>>,
+    mut ew_out_of_turn: EventWriter<EventOutOfTurn>,
+    mut ew_transcript_mismatch: EventWriter<EventTranscriptMismatch>,
+    mut turn_state: ResMut<TurnState>,
+    mut transcript: ResMut<Transcript>,
+    mut held: Local<Vec<EventIncomingMessage<T>>>,
+    pause: Res<Pause>,
+    clock: Res<TurnClock>,
+    settings: Res<VeilidSettings>,
 ) {
-    for _e in e_veilid_initialized.read() {
-        *veilid_plugin_status = VeilidPluginStatus::Initialized;
+    if er_rematch_started.read().count() > 0 {
+        held.clear();
+    }
+    // Moves the peer sent before it saw the pause wait for the resume
+    held.extend(er_incoming_message.read().cloned());
+    if pause.is_paused() {
+        return;
+    }
+
+    for e in held.drain(..) {
+        // A move that doesn't follow our history is dropped, not recorded
+        if let Some(entry) = &e.entry {
+            if entry.prev != transcript.head() {
+                ew_transcript_mismatch.send(EventTranscriptMismatch {
+                    dht_key: e.dht_key,
+                    local: transcript.head(),
+                    remote: entry.prev,
+                });
+                continue;
+            }
+        }
+
+        if turn_state.is_active() {
+            let in_turn = turn_state.is_turn_of(e.dht_key) && e.turn == Some(turn_state.turn());
+            if !in_turn {
+                let rejected = settings.turns.out_of_turn == OutOfTurnPolicy::Reject;
+                ew_out_of_turn.send(EventOutOfTurn {
+                    dht_key: e.dht_key,
+                    turn: turn_state.turn(),
+                    rejected,
+                });
+                if rejected {
+                    continue;
+                }
+            } else if settings.turns.end_turn_on_message {
+                let at = clock.remote_time(e.sent_at, turn_state.started_at());
+                turn_state.advance_at(at);
+            }
+        }
 
-        let mut veilid_app = veilid_app.app.clone().unwrap();
-        runtime.spawn_background_task(|ctx| async move {
-            let app_logic = ChatAppLogic::new(ctx.clone());
-            let _ = veilid_app.network_loop::<T, ChatAppLogic>(app_logic).await;
+        if let Some(entry) = e.entry {
+            transcript.push(entry);
+        }
+
+        ew_receive_message.send(EventReceiveMessage {
+            message: e.message,
+            dht_key: e.dht_key,
         });
-        break;
     }
 }
 
-fn veilid_network_loop_cycle<
-    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
->(
+#[derive(Resource, Clone, Default)]
+pub(crate) struct NetworkLoop(Arc<AtomicUsize>);
+
+pub(crate) fn spawn_network_loop(
+    runtime: &TasksRutime,
+    veilid_app: VeilidDuplex,
+    routes: Routes,
+    owners: RecordOwners,
+    received: ReceivedMessages,
+    network_loop: &NetworkLoop,
+) {
+    let current = network_loop.0.clone();
+    let generation = current.fetch_add(1, Ordering::SeqCst) + 1;
+
+    runtime.spawn_background_task(move |mut ctx| async move {
+        let mut veilid_app = veilid_app;
+        while current.load(Ordering::SeqCst) == generation {
+            let Ok(update) = veilid_app.receiver.try_recv() else {
+                tools::sleep(10).await;
+                continue;
+            };
+
+            match update {
+                VeilidUpdate::AppCall(call) => {
+                    let _ = veilid_app
+                        .api
+                        .app_call_reply(call.id(), b"ACK".to_vec())
+                        .await;
+                    receive_frame(&mut ctx, &veilid_app, &owners, &received, call.message()).await;
+                }
+                VeilidUpdate::RouteChange(change) => {
+                    if let Err(err) = on_route_change(&mut veilid_app, &routes, &change).await {
+                        ctx.run_on_main_thread(move |ctx| ctx.world.send_event(EventError(err)))
+                            .await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+fn event_on_veilid_initialized(
+    mut veilid_plugin_status: ResMut<VeilidPluginStatus>,
+    mut e_veilid_initialized: EventReader<EventVeilidInitialized>,
     runtime: ResMut<TasksRutime>,
-    veilid_plugin_status: Res<VeilidPluginStatus>,
     veilid_app: Res<VeilidApp>,
+    routes: Res<Routes>,
+    owners: Res<RecordOwners>,
+    received: Res<ReceivedMessages>,
+    network_loop: Res<NetworkLoop>,
 ) {
-    if *veilid_plugin_status.into_inner() == VeilidPluginStatus::Initializing {
-        return;
-    }
-
-    let veilid_app = veilid_app.app.clone();
-    if veilid_app.is_none() {
+    if e_veilid_initialized.read().count() == 0 {
         return;
     }
-    let mut veilid_app = veilid_app.unwrap();
-
-    runtime.spawn_background_task(|ctx| async move {
-        let app_logic = ChatAppLogic::new(ctx.clone());
-        let _ = veilid_app
-            .network_loop_cycle::<T, ChatAppLogic>(app_logic)
-            .await;
-    });
+    *veilid_plugin_status = VeilidPluginStatus::Initialized;
+
+    spawn_network_loop(
+        &runtime,
+        veilid_app.app.clone().unwrap(),
+        routes.clone(),
+        owners.clone(),
+        received.clone(),
+        &network_loop,
+    );
 }
 
 fn on_ev_send_message<
@@ -266,45 +616,73 @@ fn on_ev_send_message<
 >(
     mut er_send_message: EventReader<EventSendMessage<T>>,
     mut ew_awaiting_peer: EventWriter<EventAwaitingPeer>,
+    mut ew_error: EventWriter<EventError>,
+    mut ew_out_of_turn: EventWriter<EventOutOfTurn>,
+    mut turn_state: ResMut<TurnState>,
+    mut transcript: ResMut<Transcript>,
+    mut correspondence: ResMut<Correspondence>,
+    mut outbound: ResMut<Outbound>,
+    peer_codecs: Res<PeerCodecs>,
+    pause: Res<Pause>,
     veilid_app: Res<VeilidApp>,
-    runtime: ResMut<TasksRutime>,
+    settings: Res<VeilidSettings>,
 ) {
     if veilid_app.app.is_none() {
         return;
     }
 
     let veilid_app = veilid_app.app.clone().unwrap();
-    let origin_dht_key = veilid_app.our_dht_key;
+    let max_fragment_size = settings.fragmentation.max_fragment_size;
 
     for e in er_send_message.read() {
-        let veilid_app = veilid_app.clone();
-        let destination_dht_key = e.dht_key;
+        if pause.is_paused() {
+            ew_error.send(EventError(anyhow!("can't send while the game is paused")));
+            continue;
+        }
+        let mut turn = None;
+        if turn_state.is_active() {
+            if turn_state.is_turn_of(veilid_app.our_dht_key) {
+                turn = Some(turn_state.turn());
+            } else {
+                let rejected = settings.turns.out_of_turn == OutOfTurnPolicy::Reject;
+                ew_out_of_turn.send(EventOutOfTurn {
+                    dht_key: veilid_app.our_dht_key,
+                    turn: turn_state.turn(),
+                    rejected,
+                });
+                if rejected {
+                    continue;
+                }
+            }
+        }
 
-        let app_message: AppMessage<T> = AppMessage {
-            data: e.message.clone(),
-            dht_record: origin_dht_key,
-            uuid: "".to_string(),
+        let codec = peer_codecs.choose(e.dht_key, settings.codec);
+        let mut envelope = match Envelope::new(PayloadKind::User, &e.message, codec, &settings) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                ew_error.send(EventError(err));
+                continue;
+            }
         };
+        envelope.turn = turn;
+        envelope.prev_hash = Some(transcript.head());
+        let entry = TranscriptEntry::new(veilid_app.our_dht_key, &envelope, transcript.head());
+        transcript.push(entry);
+
+        if turn.is_some() && settings.turns.end_turn_on_message {
+            turn_state.advance_at(envelope.sent_at);
+        }
+
         let uuid = e.uuid;
         let dht_key = e.dht_key;
 
-        ew_awaiting_peer.send(EventAwaitingPeer);
-
-        runtime.spawn_background_task(move |mut ctx| async move {
-            let result = veilid_app
-                .send_message(app_message, destination_dht_key)
-                .await;
+        if correspondence.mailbox(dht_key).is_some() {
+            correspondence.post(dht_key, envelope, Some(uuid), max_fragment_size);
+            continue;
+        }
 
-            ctx.run_on_main_thread(move |ctx| {
-                let world = ctx.world;
-                if result.is_err() {
-                    world.send_event(EventError(result.err().unwrap()));
-                } else {
-                    world.send_event(EventMessageSent { uuid, dht_key });
-                }
-            })
-            .await;
-        });
+        ew_awaiting_peer.send(EventAwaitingPeer);
+        outbound.push(dht_key, envelope, Some(uuid), max_fragment_size);
     }
 }
 
@@ -324,13 +702,46 @@ impl<T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + C
         app.add_plugins(TasksPlugin::default());
 
         app.init_resource::<VeilidApp>();
+        app.init_resource::<VeilidSettings>();
+        app.init_resource::<Reassembly>();
+        app.init_resource::<Transfers>();
+        app.init_resource::<ContentManifest>();
+        app.init_resource::<CheckedPeers>();
+        app.init_resource::<TurnState>();
+        app.init_resource::<TurnClock>();
+        app.init_resource::<LockstepLog>();
+        app.init_resource::<SnapshotRegistry>();
+        app.init_resource::<Resyncs>();
+        app.init_resource::<SharedRandom>();
+        app.init_resource::<Decks>();
+        app.init_resource::<OpenRounds>();
+        app.init_resource::<Transcript>();
+        app.init_resource::<GameResult>();
+        app.init_resource::<Rematch>();
+        app.init_resource::<Takebacks>();
+        app.init_resource::<Pause>();
+        app.init_resource::<SavedGames>();
+        app.init_resource::<Correspondence>();
+        app.init_resource::<PersonaRotation>();
+        app.init_resource::<RecordOwners>();
+        app.init_resource::<ReceivedMessages>();
+        app.init_resource::<Outbound>();
+        app.init_resource::<Reorder>();
+        app.init_resource::<NetworkLoop>();
+        app.init_resource::<Routes>();
+        app.init_resource::<PeerCodecs>();
         app.add_systems(Startup, initialize_veilid_app);
         app.add_systems(
             Update,
             (
                 on_ev_send_message::<T>,
-                event_on_veilid_initialized::<T>,
-                veilid_network_loop_cycle::<T>,
+                (
+                    on_ev_receive_envelope::<T>,
+                    validate_incoming_messages::<T>,
+                    on_ev_incoming_message::<T>,
+                )
+                    .chain(),
+                event_on_veilid_initialized,
             ),
         );
         app.add_systems(
@@ -340,8 +751,113 @@ impl<T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + C
                 on_ev_awaiting_peer,
                 on_ev_error,
                 on_ev_veilid_message_sent,
+                expire_partial_messages.run_if(is_not_paused),
+            ),
+        );
+        // Subsystems
+        app.add_systems(
+            Update,
+            (on_ev_send_protocol, send_outbound)
+                .chain()
+                .after(on_ev_send_message::<T>),
+        );
+        app.add_systems(
+            Update,
+            (
+                on_ev_send_transfer,
+                on_ev_receive_transfer,
+                on_ev_resume_transfer,
+                on_ev_cancel_transfer,
+                detect_stalled_transfers.run_if(is_not_paused),
             ),
         );
+        app.add_systems(Update, (announce_codecs_on_connect, on_ev_receive_codecs));
+        app.add_systems(
+            Update,
+            (
+                clear_checked_peers,
+                send_manifest_on_connect,
+                on_ev_receive_manifest,
+            )
+                .chain(),
+        );
+        app.add_systems(
+            Update,
+            (
+                on_ev_end_turn,
+                on_ev_receive_turn,
+                emit_turn_events,
+                charge_clock_on_turn_end,
+                tick_clock,
+                schedule_state_hashes,
+            )
+                .chain(),
+        );
+        app.add_systems(Update, on_ev_receive_state_hash);
+        app.add_systems(Last, compute_state_hashes);
+        app.add_systems(
+            Update,
+            (on_ev_request_resync, on_ev_receive_resync, process_resyncs).chain(),
+        );
+        app.add_systems(Update, on_shared_random);
+        app.add_systems(Update, (on_deck_commands, on_ev_receive_deck));
+        app.add_systems(Update, on_ev_receive_rejection);
+        app.add_systems(
+            Update,
+            (
+                on_ev_sign_transcript,
+                on_ev_receive_transcript,
+                on_ev_peer_signature_verified,
+            ),
+        );
+        app.add_systems(
+            Update,
+            (
+                on_result_commands,
+                on_ev_receive_result,
+                on_ev_peer_result_verified,
+            ),
+        );
+        app.add_systems(
+            Update,
+            (
+                (on_rematch_commands, on_ev_receive_rematch),
+                start_rematches,
+            )
+                .chain(),
+        );
+        app.add_systems(
+            Update,
+            (
+                (on_takeback_commands, on_ev_receive_takeback),
+                process_takebacks::<T>,
+            )
+                .chain(),
+        );
+        app.add_systems(Last, (capture_takeback_snapshots::<T>, record_replay::<T>));
+        app.add_systems(
+            Update,
+            ((on_pause_commands, on_ev_receive_pause), apply_pauses).chain(),
+        );
+        app.add_systems(
+            Update,
+            (
+                (on_save_commands, process_saved_games).chain(),
+                on_ev_resume_session,
+                on_ev_receive_session,
+            ),
+        );
+        app.add_systems(
+            Update,
+            (
+                on_ev_open_mailbox,
+                on_ev_close_mailbox,
+                on_ev_receive_mailbox,
+                write_mailboxes.after(on_ev_send_protocol),
+                poll_mailboxes,
+            ),
+        );
+        app.add_systems(Update, (on_identity_commands, on_ev_rotate_persona));
         // Clipboard QoL
         app.add_systems(Update, on_read_from_clipboard);
         app.add_event::<EventConnectedPeer>();
@@ -351,6 +867,98 @@ impl<T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + C
         app.add_event::<EventReceiveMessage<T>>();
         app.add_event::<EventSendMessage<T>>();
         app.add_event::<EventMessageSent>();
+        app.add_event::<EventReceiveEnvelope>();
+        app.add_event::<EventUnverifiedMessage>();
+        app.add_event::<EventPeerError>();
+        app.add_event::<EventSendProtocol>();
+        app.add_event::<EventReceiveProtocol>();
+        app.add_event::<EventSendTransfer>();
+        app.add_event::<EventResumeTransfer>();
+        app.add_event::<EventCancelTransfer>();
+        app.add_event::<EventTransferProgress>();
+        app.add_event::<EventTransferComplete>();
+        app.add_event::<EventTransferFailed>();
+        app.add_event::<EventContentChecked>();
+        app.add_event::<EventIncomingMessage<T>>();
+        app.add_event::<EventTurnStarted>();
+        app.add_event::<EventTurnEnded>();
+        app.add_event::<EventEndTurn>();
+        app.add_event::<EventOutOfTurn>();
+        app.add_event::<EventClockTick>();
+        app.add_event::<EventTimeExpired>();
+        app.add_event::<EventDesync>();
+        app.add_event::<EventRequestResync>();
+        app.add_event::<EventResynced>();
+        app.add_event::<EventSharedRandom>();
+        app.add_event::<EventCheatDetected>();
+        app.add_event::<EventShuffleDeck>();
+        app.add_event::<EventDrawCard>();
+        app.add_event::<EventPlayCard>();
+        app.add_event::<EventVerifyDeck>();
+        app.add_event::<EventDeckReady>();
+        app.add_event::<EventCardDrawn>();
+        app.add_event::<EventCardRevealed>();
+        app.add_event::<EventDeckVerified>();
+        app.add_event::<EventDrawConflict>();
+        app.add_event::<EventDeckVerificationRequested>();
+        app.add_event::<EventInvalidMove>();
+        app.add_event::<EventMoveRejected>();
+        app.add_event::<EventSignTranscript>();
+        app.add_event::<EventTranscriptSigned>();
+        app.add_event::<EventTranscriptMismatch>();
+        app.add_event::<EventPeerSignatureVerified>();
+        app.add_event::<EventResign>();
+        app.add_event::<EventOfferDraw>();
+        app.add_event::<EventAcceptDraw>();
+        app.add_event::<EventDeclineDraw>();
+        app.add_event::<EventConfirmResult>();
+        app.add_event::<EventDrawOffered>();
+        app.add_event::<EventDrawDeclined>();
+        app.add_event::<EventResultProposed>();
+        app.add_event::<EventResultDisputed>();
+        app.add_event::<EventGameConcluded>();
+        app.add_event::<EventPeerResultVerified>();
+        app.add_event::<EventProposeRematch>();
+        app.add_event::<EventAcceptRematch>();
+        app.add_event::<EventDeclineRematch>();
+        app.add_event::<EventRematchProposed>();
+        app.add_event::<EventRematchDeclined>();
+        app.add_event::<EventRematchStarted>();
+        app.add_event::<EventRematchAccepted>();
+        app.add_event::<EventRequestTakeback>();
+        app.add_event::<EventAcceptTakeback>();
+        app.add_event::<EventDeclineTakeback>();
+        app.add_event::<EventTakebackRequested>();
+        app.add_event::<EventTakebackDeclined>();
+        app.add_event::<EventTakenBack>();
+        app.add_event::<EventRequestPause>();
+        app.add_event::<EventRequestResume>();
+        app.add_event::<EventAcceptPauseRequest>();
+        app.add_event::<EventDeclinePauseRequest>();
+        app.add_event::<EventPauseRequested>();
+        app.add_event::<EventPauseDeclined>();
+        app.add_event::<EventPaused>();
+        app.add_event::<EventResumed>();
+        app.add_event::<EventPauseAgreed>();
+        app.add_event::<EventSaveGame>();
+        app.add_event::<EventLoadGame>();
+        app.add_event::<EventResumeSession>();
+        app.add_event::<EventGameSaved>();
+        app.add_event::<EventGameLoaded>();
+        app.add_event::<EventSessionResumed>();
+        app.add_event::<EventSessionResumeFailed>();
+        app.add_event::<EventOpenMailbox>();
+        app.add_event::<EventCloseMailbox>();
+        app.add_event::<EventMailboxOpened>();
+        app.add_event::<EventPeerMailboxOpened>();
+        app.add_event::<EventPeerMailboxClosed>();
+        app.add_event::<EventRotateIdentity>();
+        app.add_event::<EventDiscardIdentity>();
+        app.add_event::<EventIdentityReady>();
+        app.add_event::<EventIdentityRotated>();
+        app.add_event::<EventIdentityDiscarded>();
+        app.add_event::<EventRotatePersona>();
+        app.add_event::<EventPersonaRotated>();
         app.add_event::<EventReadFromClipboardDone>();
         app.add_event::<EventReadFromClipboard>();
         app.insert_resource(VeilidPluginStatus::Initializing);
@@ -361,6 +969,13 @@ impl<T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + C
 // Utils
 // -----
 
+pub(crate) fn timestamp() -> u64 {
+    bevy::utils::SystemTime::now()
+        .duration_since(bevy::utils::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 #[cfg(target_arch = "wasm32")]
 pub fn copy_to_clipboard(value: String, runtime: ResMut<TasksRutime>) {
     runtime.spawn_background_task(|mut ctx| async move {
@@ -379,8 +994,7 @@ pub fn copy_to_clipboard(value: String, runtime: ResMut<TasksRutime>) {
 pub fn copy_to_clipboard(value: String, _: ResMut<TasksRutime>) {
     // copy to clipboard
     let mut ctx = ClipboardContext::new().unwrap();
-    let msg = format!("{}", value);
-    ctx.set_contents(msg.to_owned()).unwrap();
+    ctx.set_contents(value).unwrap();
     ctx.get_contents().unwrap();
 }
 
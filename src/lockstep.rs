@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::turn::{EventTurnEnded, TurnState};
+use crate::VeilidApp;
+
+/// Hashes the parts of the local [`World`] that must be identical on both peers.
+pub trait StateHasher: Send + Sync + 'static {
+    fn hash(&self, world: &mut World) -> u64;
+}
+
+impl<F: Fn(&mut World) -> u64 + Send + Sync + 'static> StateHasher for F {
+    fn hash(&self, world: &mut World) -> u64 {
+        self(world)
+    }
+}
+
+/// Turns on desync detection. Requires an active [`TurnState`].
+#[derive(Resource)]
+pub struct Lockstep {
+    hasher: Box<dyn StateHasher>,
+}
+
+impl Lockstep {
+    pub fn new(hasher: impl StateHasher) -> Self {
+        Self {
+            hasher: Box::new(hasher),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TurnHashes {
+    pub local: Option<u64>,
+    pub remote: Option<u64>,
+}
+
+struct PendingHash {
+    turn: u32,
+    player: CryptoTyped<CryptoKey>,
+    local_move: bool,
+    due_frame: u64,
+}
+
+/// State hashes recorded per turn, local and as reported by the player who moved.
+#[derive(Resource, Default)]
+pub struct LockstepLog {
+    turns: BTreeMap<u32, TurnHashes>,
+    pending: Vec<PendingHash>,
+    frame: u64,
+}
+
+impl LockstepLog {
+    pub fn get(&self, turn: u32) -> Option<&TurnHashes> {
+        self.turns.get(&turn)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u32, &TurnHashes)> {
+        self.turns.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.turns.clear();
+        self.pending.clear();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum LockstepMessage {
+    StateHash { turn: u32, hash: u64 },
+}
+
+// ------
+// Events
+// ------
+
+/// The local state after `turn` differs from the state reported by `dht_key`, the player
+/// who moved.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventDesync {
+    pub turn: u32,
+    pub local: u64,
+    pub remote: u64,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+// -------
+// Systems
+// -------
+
+fn compare(log: &LockstepLog, turn: u32, dht_key: CryptoTyped<CryptoKey>) -> Option<EventDesync> {
+    let hashes = log.turns.get(&turn)?;
+    let (local, remote) = (hashes.local?, hashes.remote?);
+
+    (local != remote).then_some(EventDesync {
+        turn,
+        local,
+        remote,
+        dht_key,
+    })
+}
+
+pub(crate) fn schedule_state_hashes(
+    mut er_turn_ended: EventReader<EventTurnEnded>,
+    mut log: ResMut<LockstepLog>,
+    lockstep: Option<Res<Lockstep>>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let (Some(_), Some(app)) = (lockstep, veilid_app.app.as_ref()) else {
+        er_turn_ended.clear();
+        return;
+    };
+
+    for e in er_turn_ended.read() {
+        let local_move = e.player == app.our_dht_key;
+        let due_frame = if local_move { log.frame } else { log.frame + 1 };
+        log.pending.push(PendingHash {
+            turn: e.turn,
+            player: e.player,
+            local_move,
+            due_frame,
+        });
+    }
+}
+
+pub(crate) fn compute_state_hashes(world: &mut World) {
+    if !world.contains_resource::<Lockstep>() {
+        return;
+    }
+
+    let due: Vec<PendingHash> = {
+        let mut log = world.resource_mut::<LockstepLog>();
+        let frame = log.frame;
+        log.frame += 1;
+        let (due, pending) = log.pending.drain(..).partition(|p| p.due_frame <= frame);
+        log.pending = pending;
+        due
+    };
+    if due.is_empty() {
+        return;
+    }
+
+    let Some(our_dht_key) = world
+        .resource::<VeilidApp>()
+        .app
+        .as_ref()
+        .map(|app| app.our_dht_key)
+    else {
+        return;
+    };
+    let turn_state = world.resource::<TurnState>().clone();
+    let hash = world.resource_scope(|world, lockstep: Mut<Lockstep>| lockstep.hasher.hash(world));
+
+    for pending in due {
+        if pending.local_move {
+            world
+                .resource_mut::<LockstepLog>()
+                .turns
+                .entry(pending.turn)
+                .or_default()
+                .local = Some(hash);
+
+            for seat in turn_state.seats().iter().filter(|s| **s != our_dht_key) {
+                world.send_event(EventSendProtocol {
+                    message: ProtocolMessage::Lockstep(LockstepMessage::StateHash {
+                        turn: pending.turn,
+                        hash,
+                    }),
+                    dht_key: *seat,
+                });
+            }
+        } else if turn_state.turn() == pending.turn + 1 {
+            let mut log = world.resource_mut::<LockstepLog>();
+            log.turns.entry(pending.turn).or_default().local = Some(hash);
+            let desync = compare(&log, pending.turn, pending.player);
+            if let Some(desync) = desync {
+                world.send_event(desync);
+            }
+        }
+    }
+}
+
+pub(crate) fn on_ev_receive_state_hash(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_desync: EventWriter<EventDesync>,
+    mut log: ResMut<LockstepLog>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Lockstep(LockstepMessage::StateHash { turn, hash }) = e.message else {
+            continue;
+        };
+
+        log.turns.entry(turn).or_default().remote = Some(hash);
+        if let Some(desync) = compare(&log, turn, e.dht_key) {
+            ew_desync.send(desync);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn peer(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn setup() -> (World, Schedule) {
+        let mut world = World::new();
+        world.init_resource::<Events<EventReceiveProtocol>>();
+        world.init_resource::<Events<EventDesync>>();
+        world.init_resource::<LockstepLog>();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(on_ev_receive_state_hash);
+        (world, schedule)
+    }
+
+    fn receive(world: &mut World, turn: u32, hash: u64) {
+        world.send_event(EventReceiveProtocol {
+            message: ProtocolMessage::Lockstep(LockstepMessage::StateHash { turn, hash }),
+            dht_key: peer(2),
+        });
+    }
+
+    fn desyncs(world: &World) -> Vec<(u32, u64, u64)> {
+        let events = world.resource::<Events<EventDesync>>();
+        events
+            .iter_current_update_events()
+            .map(|e| (e.turn, e.local, e.remote))
+            .collect()
+    }
+
+    #[test]
+    fn compares_once_both_hashes_are_known() {
+        let (mut world, mut schedule) = setup();
+        receive(&mut world, 3, 7);
+        schedule.run(&mut world);
+        assert!(desyncs(&world).is_empty());
+
+        world
+            .resource_mut::<LockstepLog>()
+            .turns
+            .entry(4)
+            .or_default()
+            .local = Some(8);
+        receive(&mut world, 4, 8);
+        schedule.run(&mut world);
+        assert!(desyncs(&world).is_empty());
+
+        world
+            .resource_mut::<LockstepLog>()
+            .turns
+            .entry(5)
+            .or_default()
+            .local = Some(1);
+        receive(&mut world, 5, 2);
+        schedule.run(&mut world);
+        assert_eq!(desyncs(&world), vec![(5, 1, 2)]);
+    }
+
+    #[test]
+    fn reports_the_player_who_moved() {
+        let mut log = LockstepLog::default();
+        log.turns.insert(
+            2,
+            TurnHashes {
+                local: Some(1),
+                remote: Some(2),
+            },
+        );
+        let desync = compare(&log, 2, peer(3)).unwrap();
+        assert_eq!(desync.dht_key, peer(3));
+        assert!(compare(&log, 1, peer(3)).is_none());
+    }
+
+    #[test]
+    fn hashes_remote_moves_a_frame_later() {
+        let mut world = World::new();
+        world.init_resource::<VeilidApp>();
+        world.init_resource::<TurnState>();
+        world.insert_resource(Lockstep::new(|_: &mut World| 0));
+        let mut log = LockstepLog::default();
+        for (turn, local_move) in [(1, true), (2, false)] {
+            log.pending.push(PendingHash {
+                turn,
+                player: peer(2),
+                local_move,
+                due_frame: if local_move { 0 } else { 1 },
+            });
+        }
+        world.insert_resource(log);
+
+        compute_state_hashes(&mut world);
+        let log = world.resource::<LockstepLog>();
+        assert_eq!(log.frame, 1);
+        assert_eq!(log.pending.len(), 1);
+        assert_eq!(log.pending[0].turn, 2);
+
+        compute_state_hashes(&mut world);
+        assert!(world.resource::<LockstepLog>().pending.is_empty());
+    }
+
+    #[test]
+    fn clears_hashes_for_a_new_game() {
+        let mut log = LockstepLog::default();
+        log.turns.insert(1, TurnHashes::default());
+        log.pending.push(PendingHash {
+            turn: 2,
+            player: peer(2),
+            local_move: false,
+            due_frame: 0,
+        });
+        log.clear();
+        assert!(log.get(1).is_none());
+        assert!(log.pending.is_empty());
+    }
+}
@@ -0,0 +1,705 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::utils::CRYPTO_KIND;
+use veilid_duplex::veilid::VeilidDuplex;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped, DHTSchema, KeyPair, TypedKey};
+
+use crate::codec::PeerCodecs;
+use crate::envelope::{Envelope, PayloadKind};
+use crate::identity::write_private;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::signing::{sign, verify, RecordOwners};
+use crate::transport::{decode_frame, encode_frame};
+use crate::{
+    EventError, EventMessageSent, EventPeerError, EventReceiveEnvelope, EventUnverifiedMessage,
+    TasksRutime, VeilidApp, VeilidSettings,
+};
+
+// Most messages a mailbox may hold, ours or one announced by a peer.
+const MAX_CAPACITY: u16 = 1024;
+
+/// Settings for correspondence games, see [`EventOpenMailbox`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorrespondenceSettings {
+    /// How often the peers' mailboxes are checked for new messages.
+    pub poll_interval: Duration,
+    /// Messages a mailbox holds before older ones are overwritten, from 1 to 1024.
+    pub capacity: u16,
+}
+
+impl Default for CorrespondenceSettings {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(30),
+            capacity: 64,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Outbox {
+    peer: CryptoTyped<CryptoKey>,
+    mailbox: TypedKey,
+    keypair: KeyPair,
+    capacity: u16,
+    seq: u32,
+    #[serde(skip)]
+    queue: Vec<(Envelope, Option<Uuid>)>,
+    #[serde(skip)]
+    writing: bool,
+    #[serde(skip)]
+    closing: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Inbox {
+    peer: CryptoTyped<CryptoKey>,
+    mailbox: TypedKey,
+    capacity: u16,
+    read: u32,
+    #[serde(skip)]
+    polling: bool,
+}
+
+/// Mailboxes of correspondence games.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Correspondence {
+    outboxes: Vec<Outbox>,
+    inboxes: Vec<Inbox>,
+    #[serde(skip)]
+    opening: HashSet<CryptoTyped<CryptoKey>>,
+    #[serde(skip)]
+    last_poll: Option<Instant>,
+}
+
+impl Correspondence {
+    /// Our mailbox for `peer`, if messages to `peer` go by mail.
+    pub fn mailbox(&self, peer: CryptoTyped<CryptoKey>) -> Option<TypedKey> {
+        self.outbox(peer).map(|outbox| outbox.mailbox)
+    }
+
+    /// The mailbox `peer` writes its messages to us into.
+    pub fn peer_mailbox(&self, peer: CryptoTyped<CryptoKey>) -> Option<TypedKey> {
+        self.inboxes
+            .iter()
+            .find(|inbox| inbox.peer == peer)
+            .map(|inbox| inbox.mailbox)
+    }
+
+    /// Writes the mailboxes, readable by their owner only on Unix (mode 0600).
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        write_private(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn outbox(&self, peer: CryptoTyped<CryptoKey>) -> Option<&Outbox> {
+        self.outboxes
+            .iter()
+            .find(|outbox| outbox.peer == peer && !outbox.closing)
+    }
+
+    pub(crate) fn replace_peer(
+        &mut self,
+        old: CryptoTyped<CryptoKey>,
+        new: CryptoTyped<CryptoKey>,
+    ) {
+        for outbox in self.outboxes.iter_mut().filter(|o| o.peer == old) {
+            outbox.peer = new;
+        }
+        for inbox in self.inboxes.iter_mut().filter(|i| i.peer == old) {
+            inbox.peer = new;
+        }
+    }
+
+    pub(crate) fn post(
+        &mut self,
+        peer: CryptoTyped<CryptoKey>,
+        envelope: Envelope,
+        uuid: Option<Uuid>,
+        max_fragment_size: usize,
+    ) {
+        let Some(outbox) = self
+            .outboxes
+            .iter_mut()
+            .find(|outbox| outbox.peer == peer && !outbox.closing)
+        else {
+            return;
+        };
+
+        let fragments = envelope.into_fragments(max_fragment_size);
+        let last = fragments.len() - 1;
+        outbox.queue.extend(
+            fragments
+                .into_iter()
+                .enumerate()
+                .map(|(index, fragment)| (fragment, uuid.filter(|_| index == last))),
+        );
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum MailboxMessage {
+    Open { mailbox: TypedKey, capacity: u16 },
+    Close { mailbox: TypedKey },
+}
+
+// ------
+// Events
+// ------
+
+/// Creates a mailbox for `dht_key` and sends messages to it by mail from then on.
+#[derive(Event)]
+pub struct EventOpenMailbox {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Goes back to sending messages to `dht_key` over its route once the queued mail is
+/// written.
+#[derive(Event)]
+pub struct EventCloseMailbox {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Our mailbox for `dht_key` was created and announced.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventMailboxOpened {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub mailbox: TypedKey,
+}
+
+/// `dht_key` sends its messages to us by mail from now on.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventPeerMailboxOpened {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub mailbox: TypedKey,
+}
+
+/// `dht_key` sends its messages over its route again.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventPeerMailboxClosed {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+// -------
+// Systems
+// -------
+
+fn check_capacity(capacity: u16) -> Result<(), Error> {
+    if capacity == 0 || capacity > MAX_CAPACITY {
+        return Err(anyhow!(
+            "mailbox capacity {capacity} is not between 1 and {MAX_CAPACITY}"
+        ));
+    }
+    Ok(())
+}
+
+fn slot(seq: u32, capacity: u16) -> u32 {
+    1 + (seq - 1) % capacity as u32
+}
+
+async fn create_mailbox(app: &VeilidDuplex, capacity: u16) -> Result<(TypedKey, KeyPair), Error> {
+    check_capacity(capacity)?;
+    // Subkey 0 holds the sequence number of the latest message.
+    let subkeys = capacity
+        .checked_add(1)
+        .ok_or_else(|| anyhow!("mailbox capacity {capacity} is too large"))?;
+    let record = app
+        .routing_context
+        .create_dht_record(DHTSchema::dflt(subkeys)?, Some(CRYPTO_KIND))
+        .await?;
+    let key = *record.key();
+    let secret = record
+        .owner_secret()
+        .ok_or_else(|| anyhow!("mailbox record has no owner secret"))?;
+    let keypair = KeyPair::new(*record.owner(), *secret);
+    app.routing_context.close_dht_record(key).await?;
+    Ok((key, keypair))
+}
+
+// Letters are signed over the mailbox and their sequence number as well, so a letter
+// can't be written into another slot or mailbox and read again. Mail is exempt from
+// the replay window of live messages because it may be read days after it was sent.
+fn letter_data(
+    envelope: &Envelope,
+    sender: CryptoTyped<CryptoKey>,
+    destination: CryptoTyped<CryptoKey>,
+    mailbox: TypedKey,
+    seq: u32,
+) -> Result<Vec<u8>, Error> {
+    let mut data = format!("mail {mailbox}/{seq}:").into_bytes();
+    data.extend(envelope.signed_data(sender, destination)?);
+    Ok(data)
+}
+
+async fn write_mail(
+    app: &VeilidDuplex,
+    outbox: &Outbox,
+    envelopes: Vec<Envelope>,
+) -> Result<u32, Error> {
+    let routing_context = &app.routing_context;
+    routing_context
+        .open_dht_record(outbox.mailbox, Some(outbox.keypair))
+        .await?;
+
+    let mut latest = outbox.seq;
+    let mut result = Ok(());
+    for mut envelope in envelopes {
+        latest += 1;
+        result = async {
+            let data = letter_data(
+                &envelope,
+                app.our_dht_key,
+                outbox.peer,
+                outbox.mailbox,
+                latest,
+            )?;
+            envelope.signature = Some(sign(app, &data)?);
+            let letter = encode_frame(app.our_dht_key, envelope)?;
+            routing_context
+                .set_dht_value(outbox.mailbox, slot(latest, outbox.capacity), letter, None)
+                .await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = routing_context
+            .set_dht_value(outbox.mailbox, 0, latest.to_le_bytes().to_vec(), None)
+            .await
+            .map(|_| ())
+            .map_err(Error::from);
+    }
+
+    routing_context.close_dht_record(outbox.mailbox).await?;
+    result?;
+    Ok(latest)
+}
+
+async fn read_mail(
+    app: &VeilidDuplex,
+    inbox: &Inbox,
+) -> Result<(u32, u32, Vec<(u32, Vec<u8>)>), Error> {
+    let routing_context = &app.routing_context;
+    routing_context.open_dht_record(inbox.mailbox, None).await?;
+
+    let result = async {
+        let Some(header) = routing_context
+            .get_dht_value(inbox.mailbox, 0, true)
+            .await?
+        else {
+            return Ok((inbox.read, 0, vec![]));
+        };
+        let latest = u32::from_le_bytes(
+            header
+                .data()
+                .try_into()
+                .map_err(|_| anyhow!("malformed mailbox {}", inbox.mailbox))?,
+        );
+
+        let oldest = latest.saturating_sub(inbox.capacity as u32) + 1;
+        let first = (inbox.read + 1).max(oldest);
+        let mut letters = vec![];
+        for seq in first..=latest {
+            let value = routing_context
+                .get_dht_value(inbox.mailbox, slot(seq, inbox.capacity), true)
+                .await?
+                .ok_or_else(|| anyhow!("message {seq} is missing from {}", inbox.mailbox))?;
+            letters.push((seq, value.data().to_vec()));
+        }
+        Ok::<_, Error>((latest.max(inbox.read), first - inbox.read - 1, letters))
+    }
+    .await;
+
+    routing_context.close_dht_record(inbox.mailbox).await?;
+    result
+}
+
+async fn open_letter(
+    app: &VeilidDuplex,
+    owners: &RecordOwners,
+    inbox: &Inbox,
+    seq: u32,
+    letter: &[u8],
+) -> Result<Envelope, Error> {
+    let (sender, envelope) = decode_frame(letter)?;
+    if sender != inbox.peer {
+        return Err(anyhow!("message {seq} was written by {sender}"));
+    }
+    let signature = envelope
+        .signature
+        .as_ref()
+        .ok_or_else(|| anyhow!("message {seq} is not signed"))?;
+    let data = letter_data(&envelope, sender, app.our_dht_key, inbox.mailbox, seq)?;
+    let owner = owners.fetch(app, sender).await?;
+    verify(app, &owner, &data, signature)?;
+    Ok(envelope)
+}
+
+pub(crate) fn on_ev_open_mailbox(
+    mut er_open_mailbox: EventReader<EventOpenMailbox>,
+    veilid_app: Res<VeilidApp>,
+    settings: Res<VeilidSettings>,
+    runtime: ResMut<TasksRutime>,
+    mut correspondence: ResMut<Correspondence>,
+) {
+    let Some(veilid_app) = veilid_app.app.clone() else {
+        return;
+    };
+
+    for e in er_open_mailbox.read() {
+        if correspondence.mailbox(e.dht_key).is_some() || !correspondence.opening.insert(e.dht_key)
+        {
+            continue;
+        }
+
+        let veilid_app = veilid_app.clone();
+        let dht_key = e.dht_key;
+        let capacity = settings.correspondence.capacity;
+
+        runtime.spawn_background_task(move |mut ctx| async move {
+            let result = create_mailbox(&veilid_app, capacity).await;
+
+            ctx.run_on_main_thread(move |ctx| {
+                let world = ctx.world;
+                world
+                    .resource_mut::<Correspondence>()
+                    .opening
+                    .remove(&dht_key);
+                let (mailbox, keypair) = match result {
+                    Ok(created) => created,
+                    Err(err) => {
+                        world.send_event(EventError(err));
+                        return;
+                    }
+                };
+
+                world.send_event(EventSendProtocol {
+                    message: ProtocolMessage::Mailbox(MailboxMessage::Open { mailbox, capacity }),
+                    dht_key,
+                });
+                world
+                    .resource_mut::<Correspondence>()
+                    .outboxes
+                    .push(Outbox {
+                        peer: dht_key,
+                        mailbox,
+                        keypair,
+                        capacity,
+                        seq: 0,
+                        queue: vec![],
+                        writing: false,
+                        closing: false,
+                    });
+                world.send_event(EventMailboxOpened { dht_key, mailbox });
+            })
+            .await;
+        });
+    }
+}
+
+pub(crate) fn on_ev_close_mailbox(
+    mut er_close_mailbox: EventReader<EventCloseMailbox>,
+    mut ew_error: EventWriter<EventError>,
+    mut correspondence: ResMut<Correspondence>,
+    peer_codecs: Res<PeerCodecs>,
+    settings: Res<VeilidSettings>,
+) {
+    for e in er_close_mailbox.read() {
+        let Some(mailbox) = correspondence.mailbox(e.dht_key) else {
+            continue;
+        };
+        let message = ProtocolMessage::Mailbox(MailboxMessage::Close { mailbox });
+        let codec = peer_codecs.choose(e.dht_key, settings.codec);
+        let envelope = match Envelope::new(PayloadKind::Protocol, &message, codec, &settings) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                ew_error.send(EventError(err));
+                continue;
+            }
+        };
+        // Queued behind the mail already posted, so the peer reads it last.
+        correspondence.post(
+            e.dht_key,
+            envelope,
+            None,
+            settings.fragmentation.max_fragment_size,
+        );
+        for outbox in correspondence.outboxes.iter_mut() {
+            if outbox.mailbox == mailbox {
+                outbox.closing = true;
+            }
+        }
+    }
+}
+
+pub(crate) fn on_ev_receive_mailbox(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_peer_error: EventWriter<EventPeerError>,
+    mut ew_peer_mailbox_opened: EventWriter<EventPeerMailboxOpened>,
+    mut ew_peer_mailbox_closed: EventWriter<EventPeerMailboxClosed>,
+    mut correspondence: ResMut<Correspondence>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Mailbox(message) = &e.message else {
+            continue;
+        };
+
+        match *message {
+            MailboxMessage::Open { mailbox, capacity } => {
+                if let Err(err) = check_capacity(capacity) {
+                    ew_peer_error.send(EventPeerError {
+                        dht_key: e.dht_key,
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+                correspondence
+                    .inboxes
+                    .retain(|inbox| inbox.peer != e.dht_key);
+                correspondence.inboxes.push(Inbox {
+                    peer: e.dht_key,
+                    mailbox,
+                    capacity,
+                    read: 0,
+                    polling: false,
+                });
+                ew_peer_mailbox_opened.send(EventPeerMailboxOpened {
+                    dht_key: e.dht_key,
+                    mailbox,
+                });
+            }
+            MailboxMessage::Close { mailbox } => {
+                let count = correspondence.inboxes.len();
+                correspondence
+                    .inboxes
+                    .retain(|inbox| inbox.peer != e.dht_key || inbox.mailbox != mailbox);
+                if correspondence.inboxes.len() < count {
+                    ew_peer_mailbox_closed.send(EventPeerMailboxClosed { dht_key: e.dht_key });
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn write_mailboxes(
+    mut correspondence: ResMut<Correspondence>,
+    veilid_app: Res<VeilidApp>,
+    runtime: ResMut<TasksRutime>,
+) {
+    let Some(veilid_app) = veilid_app.app.clone() else {
+        return;
+    };
+
+    correspondence
+        .outboxes
+        .retain(|outbox| !outbox.closing || outbox.writing || !outbox.queue.is_empty());
+
+    for outbox in correspondence.outboxes.iter_mut() {
+        if outbox.writing || outbox.queue.is_empty() {
+            continue;
+        }
+
+        let queue = std::mem::take(&mut outbox.queue);
+        let envelopes = queue.iter().map(|(envelope, _)| envelope.clone()).collect();
+        outbox.writing = true;
+
+        let veilid_app = veilid_app.clone();
+        let snapshot = outbox.clone();
+
+        runtime.spawn_background_task(move |mut ctx| async move {
+            let result = write_mail(&veilid_app, &snapshot, envelopes).await;
+
+            ctx.run_on_main_thread(move |ctx| {
+                let world = ctx.world;
+                let mut correspondence = world.resource_mut::<Correspondence>();
+                let Some(outbox) = correspondence
+                    .outboxes
+                    .iter_mut()
+                    .find(|outbox| outbox.mailbox == snapshot.mailbox)
+                else {
+                    return;
+                };
+                outbox.writing = false;
+
+                let result = match result {
+                    Ok(latest) => {
+                        outbox.seq = latest;
+                        Ok(queue)
+                    }
+                    Err(err) => {
+                        // Tried again next frame, rewriting whatever made it.
+                        let rest = std::mem::replace(&mut outbox.queue, queue);
+                        outbox.queue.extend(rest);
+                        Err(err)
+                    }
+                };
+                match result {
+                    Ok(queue) => {
+                        for uuid in queue.into_iter().filter_map(|(_, uuid)| uuid) {
+                            world.send_event(EventMessageSent {
+                                uuid,
+                                dht_key: snapshot.peer,
+                            });
+                        }
+                    }
+                    Err(err) => {
+                        world.send_event(EventError(err));
+                    }
+                }
+            })
+            .await;
+        });
+    }
+}
+
+pub(crate) fn poll_mailboxes(
+    mut correspondence: ResMut<Correspondence>,
+    veilid_app: Res<VeilidApp>,
+    owners: Res<RecordOwners>,
+    settings: Res<VeilidSettings>,
+    runtime: ResMut<TasksRutime>,
+) {
+    let Some(veilid_app) = veilid_app.app.clone() else {
+        return;
+    };
+    if correspondence.inboxes.is_empty() {
+        return;
+    }
+    let now = Instant::now();
+    if correspondence
+        .last_poll
+        .is_some_and(|last| now.duration_since(last) < settings.correspondence.poll_interval)
+    {
+        return;
+    }
+    correspondence.last_poll = Some(now);
+
+    for inbox in correspondence.inboxes.iter_mut() {
+        if inbox.polling {
+            continue;
+        }
+        inbox.polling = true;
+
+        let veilid_app = veilid_app.clone();
+        let owners = owners.clone();
+        let snapshot = inbox.clone();
+
+        runtime.spawn_background_task(move |mut ctx| async move {
+            let result = match read_mail(&veilid_app, &snapshot).await {
+                Ok((latest, missed, letters)) => {
+                    let mut opened = vec![];
+                    for (seq, letter) in letters {
+                        opened
+                            .push(open_letter(&veilid_app, &owners, &snapshot, seq, &letter).await);
+                    }
+                    Ok((latest, missed, opened))
+                }
+                Err(err) => Err(err),
+            };
+
+            ctx.run_on_main_thread(move |ctx| {
+                let world = ctx.world;
+                let mut correspondence = world.resource_mut::<Correspondence>();
+                if let Some(inbox) = correspondence
+                    .inboxes
+                    .iter_mut()
+                    .find(|inbox| inbox.mailbox == snapshot.mailbox)
+                {
+                    inbox.polling = false;
+                    if let Ok((latest, _, _)) = &result {
+                        inbox.read = *latest;
+                    }
+                }
+
+                let (_, missed, letters) = match result {
+                    Ok(read) => read,
+                    Err(err) => {
+                        world.send_event(EventError(err));
+                        return;
+                    }
+                };
+
+                if missed > 0 {
+                    world.send_event(EventPeerError {
+                        dht_key: snapshot.peer,
+                        reason: format!("{missed} messages were overwritten before they were read"),
+                    });
+                }
+                for letter in letters {
+                    match letter {
+                        Ok(envelope) => {
+                            world.send_event(EventReceiveEnvelope {
+                                envelope,
+                                dht_key: snapshot.peer,
+                            });
+                        }
+                        Err(err) => {
+                            world.send_event(EventUnverifiedMessage {
+                                dht_key: snapshot.peer,
+                                reason: err.to_string(),
+                            });
+                        }
+                    }
+                }
+            })
+            .await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::codec::CodecKind;
+
+    fn peer(b: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([b; 32]))
+    }
+
+    #[test]
+    fn accepts_capacities_up_to_the_limit() {
+        assert!(check_capacity(0).is_err());
+        assert!(check_capacity(1).is_ok());
+        assert!(check_capacity(MAX_CAPACITY).is_ok());
+        assert!(check_capacity(MAX_CAPACITY + 1).is_err());
+        assert!(check_capacity(u16::MAX).is_err());
+    }
+
+    #[test]
+    fn wraps_around_the_mailbox() {
+        assert_eq!(slot(1, 3), 1);
+        assert_eq!(slot(3, 3), 3);
+        assert_eq!(slot(4, 3), 1);
+        assert_eq!(slot(1, 1), 1);
+        assert_eq!(slot(2, 1), 1);
+    }
+
+    #[test]
+    fn binds_letters_to_their_mailbox_and_sequence() {
+        let settings = VeilidSettings::default();
+        let envelope = Envelope::new(PayloadKind::User, &"e4", CodecKind::Json, &settings).unwrap();
+        let data = |mailbox, seq| letter_data(&envelope, peer(1), peer(2), mailbox, seq).unwrap();
+
+        assert_eq!(data(peer(3), 1), data(peer(3), 1));
+        assert_ne!(data(peer(3), 1), data(peer(3), 2));
+        assert_ne!(data(peer(3), 1), data(peer(4), 1));
+        assert_ne!(
+            data(peer(3), 1),
+            envelope.signed_data(peer(1), peer(2)).unwrap()
+        );
+    }
+}
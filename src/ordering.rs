@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid::VeilidDuplex;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::envelope::Envelope;
+use crate::fragment::FragmentationSettings;
+use crate::signing::sign;
+use crate::transport::{encode_frame, send_frame, Routes};
+use crate::{EventError, EventMessageSent, TasksRutime, VeilidApp};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sequence {
+    pub stream: Uuid,
+    pub number: u64,
+}
+
+// Failed attempts in a row before the queued messages to a peer are dropped
+const SEND_ATTEMPTS: u32 = 3;
+
+#[derive(Default)]
+struct OutboundQueue {
+    next: u64,
+    queue: VecDeque<(Envelope, Option<Uuid>)>,
+    sending: bool,
+    failures: u32,
+}
+
+#[derive(Resource)]
+pub(crate) struct Outbound {
+    stream: Uuid,
+    peers: HashMap<CryptoTyped<CryptoKey>, OutboundQueue>,
+}
+
+impl Default for Outbound {
+    fn default() -> Self {
+        Self {
+            stream: Uuid::new_v4(),
+            peers: HashMap::new(),
+        }
+    }
+}
+
+impl Outbound {
+    pub fn push(
+        &mut self,
+        peer: CryptoTyped<CryptoKey>,
+        envelope: Envelope,
+        uuid: Option<Uuid>,
+        max_fragment_size: usize,
+    ) {
+        let stream = self.stream;
+        let outbound = self.peers.entry(peer).or_default();
+
+        let fragments = envelope.into_fragments(max_fragment_size);
+        let last = fragments.len() - 1;
+        for (index, mut fragment) in fragments.into_iter().enumerate() {
+            fragment.sequence = Some(Sequence {
+                stream,
+                number: outbound.next,
+            });
+            outbound.next += 1;
+            outbound
+                .queue
+                .push_back((fragment, uuid.filter(|_| index == last)));
+        }
+    }
+}
+
+struct InboundStream {
+    stream: Uuid,
+    next: u64,
+    buffered: BTreeMap<u64, Envelope>,
+    waiting_since: Option<Instant>,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct Reorder {
+    peers: HashMap<CryptoTyped<CryptoKey>, InboundStream>,
+}
+
+impl Reorder {
+    pub fn insert(
+        &mut self,
+        sender: CryptoTyped<CryptoKey>,
+        envelope: Envelope,
+        settings: &FragmentationSettings,
+    ) -> Result<Vec<Envelope>, Error> {
+        let Some(sequence) = envelope.sequence else {
+            return Ok(vec![envelope]);
+        };
+
+        let inbound = self.peers.entry(sender).or_insert_with(|| InboundStream {
+            stream: sequence.stream,
+            next: 0,
+            buffered: BTreeMap::new(),
+            waiting_since: None,
+        });
+        if inbound.stream != sequence.stream {
+            // The sender restarted and counts from 0 again
+            *inbound = InboundStream {
+                stream: sequence.stream,
+                next: 0,
+                buffered: BTreeMap::new(),
+                waiting_since: None,
+            };
+        }
+
+        if sequence.number < inbound.next || inbound.buffered.contains_key(&sequence.number) {
+            return Err(anyhow!(
+                "message {} from {sender} arrived twice",
+                sequence.number
+            ));
+        }
+        if inbound.buffered.len() >= settings.max_fragments() as usize {
+            return Err(anyhow!(
+                "dropping message {} from {sender}: too many messages held back",
+                sequence.number
+            ));
+        }
+        inbound.buffered.insert(sequence.number, envelope);
+
+        let ready = inbound.release();
+        if inbound.buffered.is_empty() {
+            inbound.waiting_since = None;
+        } else if !ready.is_empty() || inbound.waiting_since.is_none() {
+            inbound.waiting_since = Some(Instant::now());
+        }
+        Ok(ready)
+    }
+
+    pub fn expire(
+        &mut self,
+        timeout: Duration,
+    ) -> Vec<(CryptoTyped<CryptoKey>, u64, Vec<Envelope>)> {
+        let mut expired = vec![];
+        for (sender, inbound) in self.peers.iter_mut() {
+            let Some(since) = inbound.waiting_since else {
+                continue;
+            };
+            if since.elapsed() <= timeout {
+                continue;
+            }
+            let Some(first) = inbound.buffered.keys().next().copied() else {
+                continue;
+            };
+
+            let skipped = first - inbound.next;
+            inbound.next = first;
+            let ready = inbound.release();
+            inbound.waiting_since = (!inbound.buffered.is_empty()).then(Instant::now);
+            expired.push((*sender, skipped, ready));
+        }
+        expired
+    }
+
+    pub(crate) fn touch(&mut self) {
+        let now = Instant::now();
+        for inbound in self.peers.values_mut() {
+            if inbound.waiting_since.is_some() {
+                inbound.waiting_since = Some(now);
+            }
+        }
+    }
+}
+
+impl InboundStream {
+    fn release(&mut self) -> Vec<Envelope> {
+        let mut ready = vec![];
+        while let Some(envelope) = self.buffered.remove(&self.next) {
+            ready.push(envelope);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+// -------
+// Systems
+// -------
+
+async fn send_fragments(
+    veilid_app: &VeilidDuplex,
+    routes: &Routes,
+    fragments: Vec<(Envelope, Option<Uuid>)>,
+    destination: CryptoTyped<CryptoKey>,
+) -> (
+    Vec<Uuid>,
+    Result<(), (Error, Vec<(Envelope, Option<Uuid>)>)>,
+) {
+    let mut sent = vec![];
+    let mut fragments = fragments.into_iter();
+    while let Some((fragment, uuid)) = fragments.next() {
+        let result = async {
+            let mut fragment = fragment.clone();
+            let signed_data = fragment.signed_data(veilid_app.our_dht_key, destination)?;
+            fragment.signature = Some(sign(veilid_app, &signed_data)?);
+            let frame = encode_frame(veilid_app.our_dht_key, fragment)?;
+            send_frame(veilid_app, routes, destination, frame).await
+        }
+        .await;
+
+        if let Err(err) = result {
+            let unsent = std::iter::once((fragment, uuid)).chain(fragments).collect();
+            return (sent, Err((err, unsent)));
+        }
+        sent.extend(uuid);
+    }
+    (sent, Ok(()))
+}
+
+impl Outbound {
+    // Puts what wasn't sent back in front of the queue, or drops it after
+    // SEND_ATTEMPTS failures and returns the dropped message uuids
+    fn requeue(
+        &mut self,
+        peer: CryptoTyped<CryptoKey>,
+        unsent: Vec<(Envelope, Option<Uuid>)>,
+    ) -> Option<Vec<Option<Uuid>>> {
+        let queue = self.peers.get_mut(&peer)?;
+        queue.failures += 1;
+        if queue.failures < SEND_ATTEMPTS {
+            for fragment in unsent.into_iter().rev() {
+                queue.queue.push_front(fragment);
+            }
+            return None;
+        }
+
+        queue.failures = 0;
+        let dropped = unsent.into_iter().chain(queue.queue.drain(..));
+        Some(
+            dropped
+                .filter(|(fragment, _)| is_last_fragment(fragment))
+                .map(|(_, uuid)| uuid)
+                .collect(),
+        )
+    }
+}
+
+fn is_last_fragment(envelope: &Envelope) -> bool {
+    match envelope.fragment {
+        Some(fragment) => fragment.index + 1 == fragment.count,
+        None => true,
+    }
+}
+
+pub(crate) fn send_outbound(
+    mut outbound: ResMut<Outbound>,
+    veilid_app: Res<VeilidApp>,
+    routes: Res<Routes>,
+    runtime: ResMut<TasksRutime>,
+) {
+    let Some(veilid_app) = veilid_app.app.clone() else {
+        return;
+    };
+
+    for (peer, queue) in outbound.peers.iter_mut() {
+        if queue.sending || queue.queue.is_empty() {
+            continue;
+        }
+        queue.sending = true;
+
+        let fragments: Vec<_> = queue.queue.drain(..).collect();
+        let veilid_app = veilid_app.clone();
+        let routes = routes.clone();
+        let peer = *peer;
+
+        runtime.spawn_background_task(move |mut ctx| async move {
+            let (sent, result) = send_fragments(&veilid_app, &routes, fragments, peer).await;
+
+            ctx.run_on_main_thread(move |ctx| {
+                let world = ctx.world;
+                let mut outbound = world.resource_mut::<Outbound>();
+                if let Some(queue) = outbound.peers.get_mut(&peer) {
+                    queue.sending = false;
+                    if result.is_ok() {
+                        queue.failures = 0;
+                    }
+                }
+                let failure = result
+                    .err()
+                    .map(|(err, unsent)| (err, outbound.requeue(peer, unsent)));
+
+                for uuid in sent {
+                    world.send_event(EventMessageSent {
+                        uuid,
+                        dht_key: peer,
+                    });
+                }
+                match failure {
+                    Some((err, Some(dropped))) => {
+                        for uuid in dropped {
+                            let message = uuid
+                                .map_or("a message".to_string(), |uuid| format!("message {uuid}"));
+                            world.send_event(EventError(anyhow!(
+                                "dropped {message} to {peer} after {SEND_ATTEMPTS} attempts: {err}"
+                            )));
+                        }
+                    }
+                    Some((err, None)) => {
+                        world.send_event(EventError(err));
+                    }
+                    None => {}
+                }
+            })
+            .await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::codec::CodecKind;
+    use crate::envelope::PayloadKind;
+    use crate::VeilidSettings;
+
+    fn peer() -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([1; 32]))
+    }
+
+    fn queued(count: u64) -> Vec<Envelope> {
+        let mut outbound = Outbound::default();
+        for turn in 0..count {
+            let envelope = Envelope::new(
+                PayloadKind::User,
+                &turn,
+                CodecKind::Json,
+                &VeilidSettings::default(),
+            )
+            .unwrap();
+            outbound.push(peer(), envelope, None, 1024);
+        }
+        let queue = outbound.peers.remove(&peer()).unwrap().queue;
+        queue.into_iter().map(|(envelope, _)| envelope).collect()
+    }
+
+    fn numbers(envelopes: &[Envelope]) -> Vec<u64> {
+        envelopes
+            .iter()
+            .map(|envelope| envelope.sequence.unwrap().number)
+            .collect()
+    }
+
+    #[test]
+    fn releases_in_order() {
+        let settings = FragmentationSettings::default();
+        let mut reorder = Reorder::default();
+        let mut envelopes = queued(3);
+        let third = envelopes.pop().unwrap();
+        let second = envelopes.pop().unwrap();
+        let first = envelopes.pop().unwrap();
+
+        assert!(reorder.insert(peer(), third, &settings).unwrap().is_empty());
+        assert!(reorder
+            .insert(peer(), second, &settings)
+            .unwrap()
+            .is_empty());
+        let ready = reorder.insert(peer(), first, &settings).unwrap();
+        assert_eq!(numbers(&ready), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let settings = FragmentationSettings::default();
+        let mut reorder = Reorder::default();
+        let mut envelopes = queued(3);
+
+        reorder
+            .insert(peer(), envelopes[0].clone(), &settings)
+            .unwrap();
+        assert!(reorder
+            .insert(peer(), envelopes.remove(0), &settings)
+            .is_err());
+        reorder
+            .insert(peer(), envelopes[1].clone(), &settings)
+            .unwrap();
+        assert!(reorder
+            .insert(peer(), envelopes.remove(1), &settings)
+            .is_err());
+    }
+
+    #[test]
+    fn skips_missing_messages_after_timeout() {
+        let settings = FragmentationSettings::default();
+        let mut reorder = Reorder::default();
+        let mut envelopes = queued(3);
+        envelopes.remove(0);
+        for envelope in envelopes {
+            reorder.insert(peer(), envelope, &settings).unwrap();
+        }
+
+        assert!(reorder.expire(Duration::from_secs(60)).is_empty());
+        let expired = reorder.expire(Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        let (sender, skipped, ready) = &expired[0];
+        assert_eq!((*sender, *skipped), (peer(), 1));
+        assert_eq!(numbers(ready), vec![1, 2]);
+    }
+
+    #[test]
+    fn restarts_on_new_stream() {
+        let settings = FragmentationSettings::default();
+        let mut reorder = Reorder::default();
+        let mut before = queued(2);
+        reorder.insert(peer(), before.remove(1), &settings).unwrap();
+
+        let ready = reorder
+            .insert(peer(), queued(1).remove(0), &settings)
+            .unwrap();
+        assert_eq!(numbers(&ready), vec![0]);
+    }
+
+    #[test]
+    fn requeues_unsent_messages_in_front() {
+        let mut outbound = Outbound::default();
+        for turn in 0..3u32 {
+            let envelope = Envelope::new(
+                PayloadKind::User,
+                &turn,
+                CodecKind::Json,
+                &VeilidSettings::default(),
+            )
+            .unwrap();
+            outbound.push(peer(), envelope, Some(Uuid::new_v4()), 1024);
+        }
+        let queue = outbound.peers.get_mut(&peer()).unwrap();
+        let unsent: Vec<_> = queue.queue.drain(..2).collect();
+
+        assert!(outbound.requeue(peer(), unsent).is_none());
+        let queue = &outbound.peers[&peer()].queue;
+        let queued: Vec<_> = queue.iter().map(|(envelope, _)| envelope.clone()).collect();
+        assert_eq!(numbers(&queued), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn drops_messages_after_repeated_failures() {
+        let mut outbound = Outbound::default();
+        let uuid = Uuid::new_v4();
+        let envelope = Envelope::new(
+            PayloadKind::User,
+            &vec![0u8; 3000],
+            CodecKind::Json,
+            &VeilidSettings::default(),
+        )
+        .unwrap();
+        outbound.push(peer(), envelope, Some(uuid), 1024);
+
+        for _ in 1..SEND_ATTEMPTS {
+            let queue = outbound.peers.get_mut(&peer()).unwrap();
+            let unsent = queue.queue.drain(..).collect();
+            assert!(outbound.requeue(peer(), unsent).is_none());
+        }
+        let queue = outbound.peers.get_mut(&peer()).unwrap();
+        let unsent = queue.queue.drain(..).collect();
+        assert_eq!(outbound.requeue(peer(), unsent), Some(vec![Some(uuid)]));
+        assert!(outbound.peers[&peer()].queue.is_empty());
+    }
+
+    #[test]
+    fn passes_unsequenced_envelopes_through() {
+        let settings = FragmentationSettings::default();
+        let mut reorder = Reorder::default();
+        let envelope = Envelope::new(
+            PayloadKind::User,
+            &0u32,
+            CodecKind::Json,
+            &VeilidSettings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            reorder.insert(peer(), envelope, &settings).unwrap().len(),
+            1
+        );
+    }
+}
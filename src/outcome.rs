@@ -0,0 +1,638 @@
+use anyhow::Error;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid::VeilidDuplex;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped, PublicKey, Signature};
+
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::signing::{sign, verify_peer};
+use crate::transcript::{EventTranscriptMismatch, Transcript, TranscriptHash};
+use crate::{EventError, TasksRutime, VeilidApp};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameOutcome {
+    Win(CryptoTyped<CryptoKey>),
+    Draw,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConclusionReason {
+    /// The losing player resigned. Settled by the resigning player's signature alone.
+    Resignation,
+    /// A draw offer was accepted. Settled by the accepting player's signature.
+    DrawAgreement,
+    /// The game decided the result, e.g. by checkmate or time. Settled once both players
+    /// confirmed the same outcome.
+    Confirmed,
+}
+
+/// A result both players can sign, tied to the transcript head it was reached at.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conclusion {
+    pub outcome: GameOutcome,
+    pub reason: ConclusionReason,
+    pub head: TranscriptHash,
+}
+
+impl Conclusion {
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"bevy_veilid result".to_vec();
+        data.extend_from_slice(&self.head);
+        data.push(self.reason as u8);
+        match self.outcome {
+            GameOutcome::Win(winner) => {
+                data.push(1);
+                data.extend_from_slice(winner.to_string().as_bytes());
+            }
+            GameOutcome::Draw => data.push(0),
+        }
+        data
+    }
+}
+
+/// A player's signature over a [`Conclusion`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResultSignature {
+    pub player: CryptoTyped<CryptoKey>,
+    /// Owner key of the player's DHT record.
+    pub public_key: PublicKey,
+    pub conclusion: Conclusion,
+    pub signature: Signature,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrawOffer {
+    Local(CryptoTyped<CryptoKey>),
+    Remote(CryptoTyped<CryptoKey>),
+}
+
+/// The end of the game as agreed with the other player: draw offers in flight, signed
+/// claims, and the settled result.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GameResult {
+    #[serde(skip)]
+    draw_offer: Option<DrawOffer>,
+    signatures: Vec<ResultSignature>,
+    concluded: Option<Conclusion>,
+}
+
+impl GameResult {
+    pub fn conclusion(&self) -> Option<&Conclusion> {
+        self.concluded.as_ref()
+    }
+
+    pub fn outcome(&self) -> Option<GameOutcome> {
+        self.concluded.map(|c| c.outcome)
+    }
+
+    pub fn is_concluded(&self) -> bool {
+        self.concluded.is_some()
+    }
+
+    /// Two different players signed the settled result.
+    pub fn is_signed_by_both(&self) -> bool {
+        let Some(concluded) = self.concluded else {
+            return false;
+        };
+        let mut signers = self
+            .signatures
+            .iter()
+            .filter(|s| s.conclusion == concluded)
+            .map(|s| s.player);
+        signers
+            .next()
+            .is_some_and(|first| signers.any(|other| other != first))
+    }
+
+    pub fn signatures(&self) -> &[ResultSignature] {
+        &self.signatures
+    }
+
+    /// The peer whose draw offer waits for the local player's answer.
+    pub fn draw_offered_by(&self) -> Option<CryptoTyped<CryptoKey>> {
+        match self.draw_offer? {
+            DrawOffer::Remote(peer) => Some(peer),
+            DrawOffer::Local(_) => None,
+        }
+    }
+
+    /// The peer the local player offered a draw to.
+    pub fn draw_offered_to(&self) -> Option<CryptoTyped<CryptoKey>> {
+        match self.draw_offer? {
+            DrawOffer::Local(peer) => Some(peer),
+            DrawOffer::Remote(_) => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    // A draw offer or a claimed result waits for the other player
+    pub(crate) fn is_pending(&self) -> bool {
+        self.concluded.is_none() && (self.draw_offer.is_some() || !self.signatures.is_empty())
+    }
+
+    fn is_signed_by(&self, player: CryptoTyped<CryptoKey>, conclusion: &Conclusion) -> bool {
+        self.signatures
+            .iter()
+            .any(|s| s.player == player && s.conclusion == *conclusion)
+    }
+
+    fn signed_by(&self, player: CryptoTyped<CryptoKey>) -> Option<Conclusion> {
+        self.signatures
+            .iter()
+            .rev()
+            .find(|s| s.player == player)
+            .map(|s| s.conclusion)
+    }
+
+    fn add_signature(&mut self, signature: ResultSignature) {
+        self.signatures
+            .retain(|s| !(s.player == signature.player && s.conclusion == signature.conclusion));
+        self.signatures.push(signature);
+    }
+
+    fn settle(&mut self, conclusion: Conclusion) -> bool {
+        if self.concluded.is_some() {
+            return self.concluded == Some(conclusion);
+        }
+        let signers = self
+            .signatures
+            .iter()
+            .filter(|s| s.conclusion == conclusion)
+            .map(|s| s.player)
+            .collect::<Vec<_>>();
+        let settled = match (conclusion.reason, conclusion.outcome) {
+            (ConclusionReason::Resignation, GameOutcome::Win(winner)) => {
+                signers.iter().any(|player| *player != winner)
+            }
+            (ConclusionReason::Resignation, GameOutcome::Draw) => false,
+            (ConclusionReason::DrawAgreement, _) => !signers.is_empty(),
+            (ConclusionReason::Confirmed, _) => signers.iter().any(|p| *p != signers[0]),
+        };
+        if settled {
+            self.concluded = Some(conclusion);
+            self.draw_offer = None;
+        }
+        settled
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ResultMessage {
+    OfferDraw,
+    DeclineDraw,
+    Sign {
+        conclusion: Conclusion,
+        signature: Signature,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Resigns the game against `dht_key`.
+#[derive(Event)]
+pub struct EventResign {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Offers `dht_key` a draw. The offer stands until it is accepted or declined.
+#[derive(Event)]
+pub struct EventOfferDraw {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Accepts the draw offered by `dht_key`. Ignored without an open offer.
+#[derive(Event)]
+pub struct EventAcceptDraw {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Declines the draw offered by `dht_key`.
+#[derive(Event)]
+pub struct EventDeclineDraw {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Claims a result the game decided, e.g. by checkmate or [`EventTimeExpired`](crate::EventTimeExpired).
+/// The result is settled once `dht_key` confirms the same outcome.
+#[derive(Event)]
+pub struct EventConfirmResult {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub outcome: GameOutcome,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventDrawOffered {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventDrawDeclined {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// `dht_key` claims `outcome` and waits for [`EventConfirmResult`].
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventResultProposed {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub outcome: GameOutcome,
+}
+
+/// `dht_key` signed a result the local player doesn't agree with.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventResultDisputed {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub local: Option<GameOutcome>,
+    pub remote: GameOutcome,
+}
+
+/// The game against `dht_key` is over. Sent when the result is settled, and again with
+/// `signed_by_both` once the other player's signature arrives.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventGameConcluded {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub outcome: GameOutcome,
+    pub reason: ConclusionReason,
+    pub signed_by_both: bool,
+}
+
+#[derive(Event)]
+pub(crate) struct EventPeerResultVerified {
+    signature: ResultSignature,
+}
+
+// -------
+// Systems
+// -------
+
+fn sign_conclusion(app: &VeilidDuplex, conclusion: Conclusion) -> Result<ResultSignature, Error> {
+    Ok(ResultSignature {
+        player: app.our_dht_key,
+        public_key: app.dht_keypair.key,
+        conclusion,
+        signature: sign(app, &conclusion.signed_data())?,
+    })
+}
+
+fn sign_and_send(
+    app: &VeilidDuplex,
+    conclusion: Conclusion,
+    dht_key: CryptoTyped<CryptoKey>,
+    result: &mut GameResult,
+    ew_send_protocol: &mut EventWriter<EventSendProtocol>,
+    ew_game_concluded: &mut EventWriter<EventGameConcluded>,
+    ew_error: &mut EventWriter<EventError>,
+) {
+    let signature = match sign_conclusion(app, conclusion) {
+        Ok(signature) => signature,
+        Err(err) => {
+            ew_error.send(EventError(err));
+            return;
+        }
+    };
+
+    ew_send_protocol.send(EventSendProtocol {
+        message: ProtocolMessage::Result(ResultMessage::Sign {
+            conclusion,
+            signature: signature.signature,
+        }),
+        dht_key,
+    });
+    result.add_signature(signature);
+    if result.settle(conclusion) {
+        ew_game_concluded.send(EventGameConcluded {
+            dht_key,
+            outcome: conclusion.outcome,
+            reason: conclusion.reason,
+            signed_by_both: result.is_signed_by_both(),
+        });
+    }
+}
+
+pub(crate) fn on_result_commands(
+    mut er_resign: EventReader<EventResign>,
+    mut er_offer_draw: EventReader<EventOfferDraw>,
+    mut er_accept_draw: EventReader<EventAcceptDraw>,
+    mut er_decline_draw: EventReader<EventDeclineDraw>,
+    mut er_confirm_result: EventReader<EventConfirmResult>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_game_concluded: EventWriter<EventGameConcluded>,
+    mut ew_result_disputed: EventWriter<EventResultDisputed>,
+    mut ew_error: EventWriter<EventError>,
+    mut result: ResMut<GameResult>,
+    transcript: Res<Transcript>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+    let head = transcript.head();
+
+    for e in er_offer_draw.read() {
+        if result.is_concluded() || result.draw_offer.is_some() {
+            continue;
+        }
+        result.draw_offer = Some(DrawOffer::Local(e.dht_key));
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Result(ResultMessage::OfferDraw),
+            dht_key: e.dht_key,
+        });
+    }
+
+    for e in er_decline_draw.read() {
+        if result.draw_offer != Some(DrawOffer::Remote(e.dht_key)) {
+            continue;
+        }
+        result.draw_offer = None;
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Result(ResultMessage::DeclineDraw),
+            dht_key: e.dht_key,
+        });
+    }
+
+    let mut conclusions = Vec::new();
+    for e in er_resign.read() {
+        let outcome = GameOutcome::Win(e.dht_key);
+        conclusions.push((e.dht_key, outcome, ConclusionReason::Resignation));
+    }
+    for e in er_accept_draw.read() {
+        if result.draw_offer == Some(DrawOffer::Remote(e.dht_key)) {
+            let reason = ConclusionReason::DrawAgreement;
+            conclusions.push((e.dht_key, GameOutcome::Draw, reason));
+        }
+    }
+    for e in er_confirm_result.read() {
+        let claimed = result
+            .signed_by(e.dht_key)
+            .filter(|c| c.reason == ConclusionReason::Confirmed && c.head == head);
+        if let Some(claimed) = claimed.filter(|c| c.outcome != e.outcome) {
+            ew_result_disputed.send(EventResultDisputed {
+                dht_key: e.dht_key,
+                local: Some(e.outcome),
+                remote: claimed.outcome,
+            });
+            continue;
+        }
+        conclusions.push((e.dht_key, e.outcome, ConclusionReason::Confirmed));
+    }
+
+    for (dht_key, outcome, reason) in conclusions {
+        if result.is_concluded() {
+            continue;
+        }
+        let conclusion = Conclusion {
+            outcome,
+            reason,
+            head,
+        };
+        sign_and_send(
+            app,
+            conclusion,
+            dht_key,
+            &mut result,
+            &mut ew_send_protocol,
+            &mut ew_game_concluded,
+            &mut ew_error,
+        );
+    }
+}
+
+pub(crate) fn on_ev_receive_result(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_draw_offered: EventWriter<EventDrawOffered>,
+    mut ew_draw_declined: EventWriter<EventDrawDeclined>,
+    mut result: ResMut<GameResult>,
+    veilid_app: Res<VeilidApp>,
+    runtime: ResMut<TasksRutime>,
+) {
+    let Some(app) = veilid_app.app.clone() else {
+        return;
+    };
+
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Result(message) = &e.message else {
+            continue;
+        };
+
+        match message {
+            ResultMessage::OfferDraw => {
+                if result.is_concluded() {
+                    continue;
+                }
+                // A crossed offer replaces ours, so either side can accept
+                result.draw_offer = Some(DrawOffer::Remote(e.dht_key));
+                ew_draw_offered.send(EventDrawOffered { dht_key: e.dht_key });
+            }
+            ResultMessage::DeclineDraw => {
+                if result.draw_offer == Some(DrawOffer::Local(e.dht_key)) {
+                    result.draw_offer = None;
+                    ew_draw_declined.send(EventDrawDeclined { dht_key: e.dht_key });
+                }
+            }
+            ResultMessage::Sign {
+                conclusion,
+                signature,
+            } => {
+                let app = app.clone();
+                let dht_key = e.dht_key;
+                let conclusion = *conclusion;
+                let signature = *signature;
+                runtime.spawn_background_task(move |mut ctx| async move {
+                    let result =
+                        verify_peer(&app, dht_key, &conclusion.signed_data(), &signature).await;
+
+                    ctx.run_on_main_thread(move |ctx| match result {
+                        Ok(public_key) => {
+                            ctx.world.send_event(EventPeerResultVerified {
+                                signature: ResultSignature {
+                                    player: dht_key,
+                                    public_key,
+                                    conclusion,
+                                    signature,
+                                },
+                            });
+                        }
+                        Err(err) => {
+                            ctx.world.send_event(EventError(err));
+                        }
+                    })
+                    .await;
+                });
+            }
+        }
+    }
+}
+
+pub(crate) fn on_ev_peer_result_verified(
+    mut er_peer_result_verified: EventReader<EventPeerResultVerified>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_game_concluded: EventWriter<EventGameConcluded>,
+    mut ew_result_proposed: EventWriter<EventResultProposed>,
+    mut ew_result_disputed: EventWriter<EventResultDisputed>,
+    mut ew_transcript_mismatch: EventWriter<EventTranscriptMismatch>,
+    mut ew_error: EventWriter<EventError>,
+    mut result: ResMut<GameResult>,
+    transcript: Res<Transcript>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+    let our_dht_key = app.our_dht_key;
+
+    for e in er_peer_result_verified.read() {
+        let peer = e.signature.player;
+        let conclusion = e.signature.conclusion;
+        if conclusion.head != transcript.head() {
+            ew_transcript_mismatch.send(EventTranscriptMismatch {
+                dht_key: peer,
+                local: transcript.head(),
+                remote: conclusion.head,
+            });
+            continue;
+        }
+
+        let ours = result
+            .signed_by(our_dht_key)
+            .filter(|c| c.head == conclusion.head);
+        let acceptable = result.concluded.unwrap_or(conclusion) == conclusion
+            && match (conclusion.reason, conclusion.outcome) {
+                (ConclusionReason::Resignation, outcome) => {
+                    outcome == GameOutcome::Win(our_dht_key)
+                }
+                (ConclusionReason::DrawAgreement, GameOutcome::Draw) => {
+                    result.draw_offer == Some(DrawOffer::Local(peer))
+                        || result.is_signed_by(our_dht_key, &conclusion)
+                }
+                (ConclusionReason::DrawAgreement, _) => false,
+                (ConclusionReason::Confirmed, _) => ours.unwrap_or(conclusion) == conclusion,
+            };
+        if !acceptable {
+            ew_result_disputed.send(EventResultDisputed {
+                dht_key: peer,
+                local: result.outcome().or(ours.map(|c| c.outcome)),
+                remote: conclusion.outcome,
+            });
+            continue;
+        }
+
+        result.add_signature(e.signature.clone());
+        if !result.settle(conclusion) {
+            ew_result_proposed.send(EventResultProposed {
+                dht_key: peer,
+                outcome: conclusion.outcome,
+            });
+            continue;
+        }
+
+        if result.is_signed_by(our_dht_key, &conclusion) {
+            ew_game_concluded.send(EventGameConcluded {
+                dht_key: peer,
+                outcome: conclusion.outcome,
+                reason: conclusion.reason,
+                signed_by_both: result.is_signed_by_both(),
+            });
+        } else {
+            sign_and_send(
+                app,
+                conclusion,
+                peer,
+                &mut result,
+                &mut ew_send_protocol,
+                &mut ew_game_concluded,
+                &mut ew_error,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn player(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn conclusion(outcome: GameOutcome, reason: ConclusionReason) -> Conclusion {
+        Conclusion {
+            outcome,
+            reason,
+            head: [5; 32],
+        }
+    }
+
+    fn sign(result: &mut GameResult, by: u8, conclusion: Conclusion) -> bool {
+        result.add_signature(ResultSignature {
+            player: player(by),
+            public_key: CryptoKey::new([by; 32]),
+            conclusion,
+            signature: Signature::new([0; 64]),
+        });
+        result.settle(conclusion)
+    }
+
+    #[test]
+    fn settles_a_resignation_by_the_loser_only() {
+        let resigned = conclusion(GameOutcome::Win(player(1)), ConclusionReason::Resignation);
+
+        let mut result = GameResult::default();
+        assert!(!sign(&mut result, 1, resigned));
+        assert!(result.is_pending());
+        assert!(sign(&mut result, 2, resigned));
+        assert_eq!(result.outcome(), Some(GameOutcome::Win(player(1))));
+        assert!(result.is_signed_by_both());
+        assert!(!result.is_pending());
+    }
+
+    #[test]
+    fn settles_a_confirmed_result_once_both_agree() {
+        let won = conclusion(GameOutcome::Win(player(1)), ConclusionReason::Confirmed);
+        let lost = conclusion(GameOutcome::Win(player(2)), ConclusionReason::Confirmed);
+
+        let mut result = GameResult::default();
+        assert!(!sign(&mut result, 1, won));
+        assert!(!sign(&mut result, 1, won));
+        assert!(!sign(&mut result, 2, lost));
+        assert!(!result.is_concluded());
+
+        assert!(sign(&mut result, 2, won));
+        assert_eq!(result.conclusion(), Some(&won));
+        assert!(result.is_signed_by_both());
+    }
+
+    #[test]
+    fn keeps_the_settled_result() {
+        let draw = conclusion(GameOutcome::Draw, ConclusionReason::DrawAgreement);
+        let resigned = conclusion(GameOutcome::Win(player(2)), ConclusionReason::Resignation);
+
+        let mut result = GameResult::default();
+        assert!(sign(&mut result, 1, draw));
+        assert!(!result.is_signed_by_both());
+        assert!(!sign(&mut result, 1, resigned));
+        assert_eq!(result.outcome(), Some(GameOutcome::Draw));
+
+        result.clear();
+        assert!(!result.is_concluded());
+        assert!(result.signatures().is_empty());
+    }
+
+    #[test]
+    fn signs_the_outcome_reason_and_position() {
+        let draw = conclusion(GameOutcome::Draw, ConclusionReason::DrawAgreement);
+        let mut elsewhere = draw;
+        elsewhere.head = [6; 32];
+        let confirmed = conclusion(GameOutcome::Draw, ConclusionReason::Confirmed);
+        let won = conclusion(GameOutcome::Win(player(1)), ConclusionReason::DrawAgreement);
+
+        for other in [elsewhere, confirmed, won] {
+            assert_ne!(draw.signed_data(), other.signed_data());
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::clock::TurnClock;
+use crate::fragment::Reassembly;
+use crate::ordering::Reorder;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::timestamp;
+use crate::transfer::Transfers;
+use crate::turn::TurnState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Request {
+    id: Uuid,
+    dht_key: CryptoTyped<CryptoKey>,
+    resume: bool,
+}
+
+/// Whether the game is paused. Pausing and resuming both need the other player's consent.
+#[derive(Resource, Default)]
+pub struct Pause {
+    paused_at: Option<u64>,
+    sent: Option<Request>,
+    received: Option<Request>,
+}
+
+impl Pause {
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// When the pause started, in milliseconds since the Unix epoch.
+    pub fn paused_at(&self) -> Option<u64> {
+        self.paused_at
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum PauseMessage {
+    Request { id: Uuid, resume: bool },
+    Accept { id: Uuid, at: u64 },
+    Decline { id: Uuid },
+}
+
+// ------
+// Events
+// ------
+
+/// Asks `dht_key` to pause the game.
+#[derive(Event)]
+pub struct EventRequestPause {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Asks `dht_key` to resume the paused game.
+#[derive(Event)]
+pub struct EventRequestResume {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Accepts the pause or resume requested by `dht_key`. Ignored without an open request.
+#[derive(Event)]
+pub struct EventAcceptPauseRequest {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event)]
+pub struct EventDeclinePauseRequest {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// `dht_key` asks to pause the game, or to resume it if `resume` is set.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventPauseRequested {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub resume: bool,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventPauseDeclined {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub resume: bool,
+}
+
+/// Both players agreed to pause at `at` milliseconds since the Unix epoch.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventPaused {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub at: u64,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventResumed {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub paused_for: Duration,
+}
+
+#[derive(Event)]
+pub(crate) struct EventPauseAgreed {
+    dht_key: CryptoTyped<CryptoKey>,
+    resume: bool,
+    at: u64,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn is_not_paused(pause: Res<Pause>) -> bool {
+    !pause.is_paused()
+}
+
+pub(crate) fn on_pause_commands(
+    mut er_request_pause: EventReader<EventRequestPause>,
+    mut er_request_resume: EventReader<EventRequestResume>,
+    mut er_accept: EventReader<EventAcceptPauseRequest>,
+    mut er_decline: EventReader<EventDeclinePauseRequest>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_pause_agreed: EventWriter<EventPauseAgreed>,
+    mut pause: ResMut<Pause>,
+) {
+    let requests = er_request_pause
+        .read()
+        .map(|e| (e.dht_key, false))
+        .chain(er_request_resume.read().map(|e| (e.dht_key, true)))
+        .collect::<Vec<_>>();
+    for (dht_key, resume) in requests {
+        if pause.is_paused() != resume {
+            continue;
+        }
+        let id = Uuid::new_v4();
+        pause.sent = Some(Request {
+            id,
+            dht_key,
+            resume,
+        });
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Pause(PauseMessage::Request { id, resume }),
+            dht_key,
+        });
+    }
+
+    for e in er_accept.read() {
+        let Some(request) = pause.received.filter(|r| r.dht_key == e.dht_key) else {
+            continue;
+        };
+        pause.received = None;
+        let at = timestamp();
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Pause(PauseMessage::Accept { id: request.id, at }),
+            dht_key: e.dht_key,
+        });
+        ew_pause_agreed.send(EventPauseAgreed {
+            dht_key: e.dht_key,
+            resume: request.resume,
+            at,
+        });
+    }
+
+    for e in er_decline.read() {
+        let Some(request) = pause.received.filter(|r| r.dht_key == e.dht_key) else {
+            continue;
+        };
+        pause.received = None;
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Pause(PauseMessage::Decline { id: request.id }),
+            dht_key: e.dht_key,
+        });
+    }
+}
+
+pub(crate) fn on_ev_receive_pause(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_pause_requested: EventWriter<EventPauseRequested>,
+    mut ew_pause_declined: EventWriter<EventPauseDeclined>,
+    mut ew_pause_agreed: EventWriter<EventPauseAgreed>,
+    mut pause: ResMut<Pause>,
+    clock: Res<TurnClock>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Pause(message) = &e.message else {
+            continue;
+        };
+
+        match *message {
+            PauseMessage::Request { id, resume } => {
+                pause.received = Some(Request {
+                    id,
+                    dht_key: e.dht_key,
+                    resume,
+                });
+                ew_pause_requested.send(EventPauseRequested {
+                    dht_key: e.dht_key,
+                    resume,
+                });
+            }
+            PauseMessage::Accept { id, at } => {
+                let Some(request) = pause.sent.filter(|r| r.id == id) else {
+                    continue;
+                };
+                pause.sent = None;
+                ew_pause_agreed.send(EventPauseAgreed {
+                    dht_key: e.dht_key,
+                    resume: request.resume,
+                    at: clock.remote_time(at, None),
+                });
+            }
+            PauseMessage::Decline { id } => {
+                let Some(request) = pause.sent.filter(|r| r.id == id) else {
+                    continue;
+                };
+                pause.sent = None;
+                ew_pause_declined.send(EventPauseDeclined {
+                    dht_key: e.dht_key,
+                    resume: request.resume,
+                });
+            }
+        }
+    }
+}
+
+pub(crate) fn apply_pauses(
+    mut er_pause_agreed: EventReader<EventPauseAgreed>,
+    mut ew_paused: EventWriter<EventPaused>,
+    mut ew_resumed: EventWriter<EventResumed>,
+    mut pause: ResMut<Pause>,
+    mut clock: ResMut<TurnClock>,
+    mut transfers: ResMut<Transfers>,
+    mut reassembly: ResMut<Reassembly>,
+    mut reorder: ResMut<Reorder>,
+    turn_state: Res<TurnState>,
+) {
+    for e in er_pause_agreed.read() {
+        match (e.resume, pause.paused_at) {
+            (false, None) => {
+                pause.paused_at = Some(e.at);
+                clock.pause(e.at);
+                ew_paused.send(EventPaused {
+                    dht_key: e.dht_key,
+                    at: e.at,
+                });
+            }
+            (true, Some(paused_at)) => {
+                pause.paused_at = None;
+                clock.resume(e.at, turn_state.started_at());
+                transfers.touch();
+                reassembly.touch();
+                reorder.touch();
+                ew_resumed.send(EventResumed {
+                    dht_key: e.dht_key,
+                    paused_for: Duration::from_millis(e.at.saturating_sub(paused_at)),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn peer(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn setup() -> (World, Schedule) {
+        let mut world = World::new();
+        world.init_resource::<Events<EventRequestPause>>();
+        world.init_resource::<Events<EventRequestResume>>();
+        world.init_resource::<Events<EventAcceptPauseRequest>>();
+        world.init_resource::<Events<EventDeclinePauseRequest>>();
+        world.init_resource::<Events<EventReceiveProtocol>>();
+        world.init_resource::<Events<EventSendProtocol>>();
+        world.init_resource::<Events<EventPauseRequested>>();
+        world.init_resource::<Events<EventPauseDeclined>>();
+        world.init_resource::<Events<EventPauseAgreed>>();
+        world.init_resource::<Events<EventPaused>>();
+        world.init_resource::<Events<EventResumed>>();
+        world.init_resource::<Pause>();
+        world.init_resource::<TurnClock>();
+        world.init_resource::<Transfers>();
+        world.init_resource::<Reassembly>();
+        world.init_resource::<Reorder>();
+        world.init_resource::<TurnState>();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(((on_pause_commands, on_ev_receive_pause), apply_pauses).chain());
+        (world, schedule)
+    }
+
+    fn sent_ids(world: &World) -> Vec<Uuid> {
+        let events = world.resource::<Events<EventSendProtocol>>();
+        events
+            .iter_current_update_events()
+            .filter_map(|e| match e.message {
+                ProtocolMessage::Pause(PauseMessage::Request { id, .. }) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn receive(world: &mut World, message: PauseMessage) {
+        world.send_event(EventReceiveProtocol {
+            message: ProtocolMessage::Pause(message),
+            dht_key: peer(2),
+        });
+    }
+
+    #[test]
+    fn pauses_once_the_peer_accepts() {
+        let (mut world, mut schedule) = setup();
+        world.send_event(EventRequestPause { dht_key: peer(2) });
+        schedule.run(&mut world);
+        assert!(!world.resource::<Pause>().is_paused());
+        let id = sent_ids(&world)[0];
+
+        receive(
+            &mut world,
+            PauseMessage::Accept {
+                id,
+                at: timestamp(),
+            },
+        );
+        schedule.run(&mut world);
+        assert!(world.resource::<Pause>().is_paused());
+        assert!(world.resource::<TurnClock>().is_paused());
+        assert_eq!(world.resource::<Events<EventPaused>>().len(), 1);
+
+        // Already paused, so only a resume is asked for
+        world.send_event(EventRequestPause { dht_key: peer(2) });
+        schedule.run(&mut world);
+        assert_eq!(sent_ids(&world).len(), 1);
+    }
+
+    #[test]
+    fn ignores_answers_to_other_requests() {
+        let (mut world, mut schedule) = setup();
+        world.send_event(EventRequestPause { dht_key: peer(2) });
+        schedule.run(&mut world);
+
+        receive(
+            &mut world,
+            PauseMessage::Accept {
+                id: Uuid::new_v4(),
+                at: timestamp(),
+            },
+        );
+        schedule.run(&mut world);
+        assert!(!world.resource::<Pause>().is_paused());
+
+        let id = sent_ids(&world)[0];
+        receive(&mut world, PauseMessage::Decline { id });
+        schedule.run(&mut world);
+        assert!(!world.resource::<Pause>().is_paused());
+        assert_eq!(world.resource::<Events<EventPauseDeclined>>().len(), 1);
+    }
+
+    #[test]
+    fn answers_the_peers_request() {
+        let (mut world, mut schedule) = setup();
+        let id = Uuid::new_v4();
+        receive(&mut world, PauseMessage::Request { id, resume: false });
+        schedule.run(&mut world);
+        assert_eq!(world.resource::<Events<EventPauseRequested>>().len(), 1);
+
+        world.send_event(EventAcceptPauseRequest { dht_key: peer(3) });
+        schedule.run(&mut world);
+        assert!(!world.resource::<Pause>().is_paused());
+
+        world.send_event(EventAcceptPauseRequest { dht_key: peer(2) });
+        schedule.run(&mut world);
+        assert!(world.resource::<Pause>().is_paused());
+    }
+
+    #[test]
+    fn reports_how_long_the_pause_lasted() {
+        let (mut world, mut schedule) = setup();
+        for (resume, at) in [(false, 1_000), (true, 4_500)] {
+            world.send_event(EventPauseAgreed {
+                dht_key: peer(2),
+                resume,
+                at,
+            });
+        }
+        schedule.run(&mut world);
+
+        assert!(!world.resource::<Pause>().is_paused());
+        let resumed = world.resource::<Events<EventResumed>>();
+        let paused_for = resumed
+            .iter_current_update_events()
+            .next()
+            .unwrap()
+            .paused_for;
+        assert_eq!(paused_for, Duration::from_millis(3_500));
+    }
+}
@@ -0,0 +1,103 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::codec::{CodecMessage, PeerCodecs};
+use crate::content::ContentMessage;
+use crate::deck::DeckMessage;
+use crate::envelope::{Envelope, PayloadKind};
+use crate::lockstep::LockstepMessage;
+use crate::mailbox::{Correspondence, MailboxMessage};
+use crate::ordering::Outbound;
+use crate::outcome::ResultMessage;
+use crate::pause::PauseMessage;
+use crate::random::RandomMessage;
+use crate::rematch::RematchMessage;
+use crate::resync::ResyncMessage;
+use crate::save::SessionMessage;
+use crate::simultaneous::CommitRevealMessage;
+use crate::takeback::TakebackMessage;
+use crate::transcript::TranscriptMessage;
+use crate::transfer::TransferMessage;
+use crate::turn::TurnMessage;
+use crate::validation::ValidationMessage;
+use crate::{EventError, VeilidApp, VeilidSettings};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ProtocolMessage {
+    Transfer(TransferMessage),
+    Content(ContentMessage),
+    Turn(TurnMessage),
+    Lockstep(LockstepMessage),
+    Resync(ResyncMessage),
+    CommitReveal(CommitRevealMessage),
+    Random(RandomMessage),
+    Deck(DeckMessage),
+    Validation(ValidationMessage),
+    Transcript(TranscriptMessage),
+    Result(ResultMessage),
+    Rematch(RematchMessage),
+    Takeback(TakebackMessage),
+    Pause(PauseMessage),
+    Session(SessionMessage),
+    Mailbox(MailboxMessage),
+    Codec(CodecMessage),
+}
+
+// ------
+// Events
+// ------
+
+#[derive(Event)]
+pub(crate) struct EventSendProtocol {
+    pub message: ProtocolMessage,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event)]
+pub(crate) struct EventReceiveProtocol {
+    pub message: ProtocolMessage,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn on_ev_send_protocol(
+    mut er_send_protocol: EventReader<EventSendProtocol>,
+    mut ew_error: EventWriter<EventError>,
+    mut correspondence: ResMut<Correspondence>,
+    mut outbound: ResMut<Outbound>,
+    peer_codecs: Res<PeerCodecs>,
+    veilid_app: Res<VeilidApp>,
+    settings: Res<VeilidSettings>,
+) {
+    if veilid_app.app.is_none() {
+        return;
+    }
+    let max_fragment_size = settings.fragmentation.max_fragment_size;
+
+    for e in er_send_protocol.read() {
+        let codec = peer_codecs.choose(e.dht_key, settings.codec);
+        let envelope = match Envelope::new(PayloadKind::Protocol, &e.message, codec, &settings) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                ew_error.send(EventError(err));
+                continue;
+            }
+        };
+
+        // The mailbox announcement has to reach the peer before it knows where to look.
+        let announcement = matches!(
+            e.message,
+            ProtocolMessage::Mailbox(MailboxMessage::Open { .. })
+        );
+        if !announcement && correspondence.mailbox(e.dht_key).is_some() {
+            correspondence.post(e.dht_key, envelope, None, max_fragment_size);
+            continue;
+        }
+
+        outbound.push(e.dht_key, envelope, None, max_fragment_size);
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::simultaneous::EventCheatDetected;
+use crate::VeilidApp;
+
+type Commitment = [u8; 32];
+
+struct Draw {
+    dht_key: CryptoTyped<CryptoKey>,
+    seed_session: bool,
+    local: [u8; 32],
+    revealed: bool,
+    commitment: Option<Commitment>,
+    reveal: Option<[u8; 32]>,
+}
+
+/// Random values agreed on with a peer that neither side can bias.
+#[derive(Resource, Default)]
+pub struct SharedRandom {
+    queued: Vec<(Uuid, CryptoTyped<CryptoKey>, bool)>,
+    draws: HashMap<Uuid, Draw>,
+}
+
+impl SharedRandom {
+    /// Starts a draw with `dht_key`. The result arrives as [`EventSharedRandom`] with the
+    /// returned id on both peers.
+    pub fn request_shared_random(&mut self, dht_key: CryptoTyped<CryptoKey>) -> Uuid {
+        let id = Uuid::new_v4();
+        self.queued.push((id, dht_key, false));
+        id
+    }
+
+    /// Like [`SharedRandom::request_shared_random`], and also seeds [`SessionRng`] with the
+    /// result on both peers.
+    pub fn seed_session(&mut self, dht_key: CryptoTyped<CryptoKey>) -> Uuid {
+        let id = Uuid::new_v4();
+        self.queued.push((id, dht_key, true));
+        id
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.queued.clear();
+        self.draws.clear();
+    }
+}
+
+/// Deterministic random number generator seeded from a shared draw.
+#[derive(Resource, Clone, Debug)]
+pub struct SessionRng(ChaCha20Rng);
+
+impl SessionRng {
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self(ChaCha20Rng::from_seed(seed))
+    }
+}
+
+impl RngCore for SessionRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+fn commitment(player: CryptoTyped<CryptoKey>, id: Uuid, value: &[u8; 32]) -> Commitment {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(player.to_string().as_bytes());
+    hasher.update(id.as_bytes());
+    hasher.update(value);
+    *hasher.finalize().as_bytes()
+}
+
+fn combine(id: Uuid, mut contributions: [(CryptoTyped<CryptoKey>, [u8; 32]); 2]) -> [u8; 32] {
+    contributions.sort_by_key(|(player, _)| player.to_string());
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(id.as_bytes());
+    for (_, value) in contributions {
+        hasher.update(&value);
+    }
+    *hasher.finalize().as_bytes()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum RandomMessage {
+    Commit {
+        id: Uuid,
+        commitment: Commitment,
+        seed_session: bool,
+    },
+    Reveal {
+        id: Uuid,
+        value: [u8; 32],
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Result of a shared draw. `value` is taken from the first bytes of `seed`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventSharedRandom {
+    pub id: Uuid,
+    pub value: u64,
+    pub seed: [u8; 32],
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn on_shared_random(
+    mut commands: Commands,
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_shared_random: EventWriter<EventSharedRandom>,
+    mut ew_cheat_detected: EventWriter<EventCheatDetected>,
+    mut shared_random: ResMut<SharedRandom>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+    let our_dht_key = app.our_dht_key;
+    let mut touched = Vec::new();
+
+    let SharedRandom { queued, draws } = &mut *shared_random;
+
+    let start = |draws: &mut HashMap<Uuid, Draw>,
+                 ew_send_protocol: &mut EventWriter<EventSendProtocol>,
+                 id: Uuid,
+                 dht_key: CryptoTyped<CryptoKey>,
+                 seed_session: bool| {
+        let local: [u8; 32] = rand::random();
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Random(RandomMessage::Commit {
+                id,
+                commitment: commitment(our_dht_key, id, &local),
+                seed_session,
+            }),
+            dht_key,
+        });
+        draws.insert(
+            id,
+            Draw {
+                dht_key,
+                seed_session,
+                local,
+                revealed: false,
+                commitment: None,
+                reveal: None,
+            },
+        );
+    };
+
+    for (id, dht_key, seed_session) in queued.drain(..) {
+        start(draws, &mut ew_send_protocol, id, dht_key, seed_session);
+    }
+
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Random(message) = &e.message else {
+            continue;
+        };
+
+        match message {
+            RandomMessage::Commit {
+                id,
+                commitment,
+                seed_session,
+            } => {
+                if !draws.contains_key(id) {
+                    start(draws, &mut ew_send_protocol, *id, e.dht_key, *seed_session);
+                }
+                let draw = draws.get_mut(id).unwrap();
+                if draw.dht_key != e.dht_key {
+                    continue;
+                }
+                match draw.commitment {
+                    Some(existing) if existing != *commitment => {
+                        ew_cheat_detected.send(EventCheatDetected {
+                            dht_key: e.dht_key,
+                            round: 0,
+                            reason: "changed its random commitment".to_string(),
+                        });
+                        draws.remove(id);
+                        continue;
+                    }
+                    _ => draw.commitment = Some(*commitment),
+                }
+                touched.push(*id);
+            }
+            RandomMessage::Reveal { id, value } => {
+                // Kept until the commitment arrives, since messages may be reordered
+                if let Some(draw) = draws.get_mut(id).filter(|d| d.dht_key == e.dht_key) {
+                    draw.reveal = Some(*value);
+                    touched.push(*id);
+                }
+            }
+        }
+    }
+
+    for id in touched {
+        let Some(draw) = draws.get_mut(&id) else {
+            continue;
+        };
+
+        if draw.commitment.is_some() && !draw.revealed {
+            draw.revealed = true;
+            ew_send_protocol.send(EventSendProtocol {
+                message: ProtocolMessage::Random(RandomMessage::Reveal {
+                    id,
+                    value: draw.local,
+                }),
+                dht_key: draw.dht_key,
+            });
+        }
+
+        let (Some(expected), Some(reveal)) = (draw.commitment, draw.reveal) else {
+            continue;
+        };
+        let draw = draws.remove(&id).unwrap();
+
+        if commitment(draw.dht_key, id, &reveal) != expected {
+            ew_cheat_detected.send(EventCheatDetected {
+                dht_key: draw.dht_key,
+                round: 0,
+                reason: "revealed a random value that does not match its commitment".to_string(),
+            });
+            continue;
+        }
+
+        let seed = combine(id, [(our_dht_key, draw.local), (draw.dht_key, reveal)]);
+        if draw.seed_session {
+            commands.insert_resource(SessionRng::from_seed(seed));
+        }
+        ew_shared_random.send(EventSharedRandom {
+            id,
+            value: u64::from_le_bytes(seed[..8].try_into().unwrap()),
+            seed,
+            dht_key: draw.dht_key,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn player(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    #[test]
+    fn agrees_on_the_seed_from_either_side() {
+        let id = Uuid::new_v4();
+        let ours = (player(1), [1; 32]);
+        let theirs = (player(2), [2; 32]);
+        assert_eq!(combine(id, [ours, theirs]), combine(id, [theirs, ours]));
+        assert_ne!(
+            combine(id, [ours, theirs]),
+            combine(Uuid::new_v4(), [ours, theirs])
+        );
+    }
+
+    #[test]
+    fn binds_commitments_to_player_and_draw() {
+        let id = Uuid::new_v4();
+        let value = [7; 32];
+        let expected = commitment(player(1), id, &value);
+        assert_eq!(commitment(player(1), id, &value), expected);
+        assert_ne!(commitment(player(2), id, &value), expected);
+        assert_ne!(commitment(player(1), Uuid::new_v4(), &value), expected);
+        assert_ne!(commitment(player(1), id, &[8; 32]), expected);
+    }
+
+    #[test]
+    fn seeds_the_same_sequence() {
+        let mut ours = SessionRng::from_seed([3; 32]);
+        let mut theirs = ours.clone();
+        let ours = (0..4).map(|_| ours.next_u64()).collect::<Vec<_>>();
+        let theirs = (0..4).map(|_| theirs.next_u64()).collect::<Vec<_>>();
+        assert_eq!(ours, theirs);
+    }
+
+    #[test]
+    fn queues_draws_until_cleared() {
+        let mut shared_random = SharedRandom::default();
+        let draw = shared_random.request_shared_random(player(2));
+        let seed = shared_random.seed_session(player(2));
+        assert_ne!(draw, seed);
+        assert_eq!(
+            shared_random.queued,
+            vec![(draw, player(2), false), (seed, player(2), true)]
+        );
+
+        shared_random.clear();
+        assert!(shared_random.queued.is_empty());
+    }
+}
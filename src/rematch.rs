@@ -0,0 +1,322 @@
+use anyhow::anyhow;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::clock::TurnClock;
+use crate::deck::Decks;
+use crate::lockstep::LockstepLog;
+use crate::outcome::GameResult;
+use crate::pause::Pause;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::random::SharedRandom;
+use crate::takeback::Takebacks;
+use crate::transcript::Transcript;
+use crate::turn::TurnState;
+use crate::EventError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Proposal {
+    id: Uuid,
+    dht_key: CryptoTyped<CryptoKey>,
+    swap_seats: bool,
+    local: bool,
+}
+
+/// Rematches played over the current connection.
+#[derive(Resource, Default)]
+pub struct Rematch {
+    proposal: Option<Proposal>,
+    round: u32,
+}
+
+impl Rematch {
+    /// Number of rematches started so far, 0 during the first game.
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    /// The peer whose rematch proposal waits for the local player's answer.
+    pub fn proposed_by(&self) -> Option<CryptoTyped<CryptoKey>> {
+        self.proposal.filter(|p| !p.local).map(|p| p.dht_key)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum RematchMessage {
+    Propose { id: Uuid, swap_seats: bool },
+    Accept { id: Uuid },
+    Decline { id: Uuid },
+}
+
+// ------
+// Events
+// ------
+
+/// Proposes another game to `dht_key` over the same connection. With `swap_seats`, the
+/// seat order is rotated so the next seat moves first. Refused until [`GameResult`] is
+/// concluded.
+#[derive(Event)]
+pub struct EventProposeRematch {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub swap_seats: bool,
+}
+
+/// Accepts the rematch proposed by `dht_key`. Ignored without an open proposal, and refused
+/// until [`GameResult`] is concluded.
+#[derive(Event)]
+pub struct EventAcceptRematch {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event)]
+pub struct EventDeclineRematch {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventRematchProposed {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub swap_seats: bool,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventRematchDeclined {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Both players agreed on a rematch. Turns, clocks, the transcript, lockstep hashes, decks
+/// and the game result were reset, and `seats` is the new seat order.
+#[derive(Event, Debug, Clone)]
+pub struct EventRematchStarted {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub round: u32,
+    pub seats: Vec<CryptoTyped<CryptoKey>>,
+}
+
+#[derive(Event)]
+pub(crate) struct EventRematchAccepted {
+    proposal: Proposal,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn on_rematch_commands(
+    mut er_propose_rematch: EventReader<EventProposeRematch>,
+    mut er_accept_rematch: EventReader<EventAcceptRematch>,
+    mut er_decline_rematch: EventReader<EventDeclineRematch>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_rematch_accepted: EventWriter<EventRematchAccepted>,
+    mut ew_error: EventWriter<EventError>,
+    mut rematch: ResMut<Rematch>,
+    result: Res<GameResult>,
+) {
+    for e in er_propose_rematch.read() {
+        if !result.is_concluded() {
+            ew_error.send(EventError(anyhow!(
+                "can't propose a rematch before the game is over"
+            )));
+            continue;
+        }
+        let id = Uuid::new_v4();
+        rematch.proposal = Some(Proposal {
+            id,
+            dht_key: e.dht_key,
+            swap_seats: e.swap_seats,
+            local: true,
+        });
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Rematch(RematchMessage::Propose {
+                id,
+                swap_seats: e.swap_seats,
+            }),
+            dht_key: e.dht_key,
+        });
+    }
+
+    for e in er_accept_rematch.read() {
+        let Some(proposal) = rematch
+            .proposal
+            .filter(|p| !p.local && p.dht_key == e.dht_key)
+        else {
+            continue;
+        };
+        // The proposal stays open, the result may still be on its way
+        if !result.is_concluded() {
+            ew_error.send(EventError(anyhow!(
+                "can't accept a rematch before the game is over"
+            )));
+            continue;
+        }
+        rematch.proposal = None;
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Rematch(RematchMessage::Accept { id: proposal.id }),
+            dht_key: e.dht_key,
+        });
+        ew_rematch_accepted.send(EventRematchAccepted { proposal });
+    }
+
+    for e in er_decline_rematch.read() {
+        let Some(proposal) = rematch
+            .proposal
+            .filter(|p| !p.local && p.dht_key == e.dht_key)
+        else {
+            continue;
+        };
+        rematch.proposal = None;
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Rematch(RematchMessage::Decline { id: proposal.id }),
+            dht_key: e.dht_key,
+        });
+    }
+}
+
+pub(crate) fn on_ev_receive_rematch(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_rematch_proposed: EventWriter<EventRematchProposed>,
+    mut ew_rematch_declined: EventWriter<EventRematchDeclined>,
+    mut ew_rematch_accepted: EventWriter<EventRematchAccepted>,
+    mut rematch: ResMut<Rematch>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Rematch(message) = &e.message else {
+            continue;
+        };
+
+        match *message {
+            RematchMessage::Propose { id, swap_seats } => {
+                rematch.proposal = Some(Proposal {
+                    id,
+                    dht_key: e.dht_key,
+                    swap_seats,
+                    local: false,
+                });
+                ew_rematch_proposed.send(EventRematchProposed {
+                    dht_key: e.dht_key,
+                    swap_seats,
+                });
+            }
+            RematchMessage::Accept { id } => {
+                let Some(proposal) = rematch.proposal.filter(|p| p.local && p.id == id) else {
+                    continue;
+                };
+                rematch.proposal = None;
+                ew_rematch_accepted.send(EventRematchAccepted { proposal });
+            }
+            RematchMessage::Decline { id } => {
+                if rematch.proposal.is_some_and(|p| p.local && p.id == id) {
+                    rematch.proposal = None;
+                    ew_rematch_declined.send(EventRematchDeclined { dht_key: e.dht_key });
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn start_rematches(
+    mut er_rematch_accepted: EventReader<EventRematchAccepted>,
+    mut ew_rematch_started: EventWriter<EventRematchStarted>,
+    mut rematch: ResMut<Rematch>,
+    mut turn_state: ResMut<TurnState>,
+    mut clock: ResMut<TurnClock>,
+    mut transcript: ResMut<Transcript>,
+    mut lockstep_log: ResMut<LockstepLog>,
+    mut decks: ResMut<Decks>,
+    mut result: ResMut<GameResult>,
+    mut takebacks: ResMut<Takebacks>,
+    mut pause: ResMut<Pause>,
+    mut shared_random: ResMut<SharedRandom>,
+) {
+    for e in er_rematch_accepted.read() {
+        let mut seats = turn_state.seats().to_vec();
+        if e.proposal.swap_seats && !seats.is_empty() {
+            seats.rotate_left(1);
+        }
+        if !seats.is_empty() {
+            turn_state.start(seats.clone());
+        }
+        clock.reset();
+        transcript.clear();
+        lockstep_log.clear();
+        decks.clear();
+        result.clear();
+        takebacks.clear();
+        pause.clear();
+        shared_random.clear();
+        rematch.round += 1;
+
+        ew_rematch_started.send(EventRematchStarted {
+            dht_key: e.proposal.dht_key,
+            round: rematch.round,
+            seats,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::outcome::{Conclusion, ConclusionReason, GameOutcome};
+
+    fn peer(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn setup() -> World {
+        let mut world = World::new();
+        world.init_resource::<Events<EventProposeRematch>>();
+        world.init_resource::<Events<EventAcceptRematch>>();
+        world.init_resource::<Events<EventDeclineRematch>>();
+        world.init_resource::<Events<EventSendProtocol>>();
+        world.init_resource::<Events<EventRematchAccepted>>();
+        world.init_resource::<Events<EventError>>();
+        world.init_resource::<Rematch>();
+        world.init_resource::<GameResult>();
+        world
+    }
+
+    fn sent(world: &World) -> usize {
+        world.resource::<Events<EventSendProtocol>>().len()
+    }
+
+    #[test]
+    fn waits_for_the_result_before_a_rematch() {
+        let mut world = setup();
+        let commands = world.register_system(on_rematch_commands);
+        world.send_event(EventProposeRematch {
+            dht_key: peer(2),
+            swap_seats: false,
+        });
+        world.run_system(commands).unwrap();
+        assert_eq!(sent(&world), 0);
+        assert_eq!(world.resource::<Events<EventError>>().len(), 1);
+
+        world.resource_mut::<Rematch>().proposal = Some(Proposal {
+            id: Uuid::new_v4(),
+            dht_key: peer(2),
+            swap_seats: false,
+            local: false,
+        });
+        world.send_event(EventAcceptRematch { dht_key: peer(2) });
+        world.run_system(commands).unwrap();
+        assert_eq!(sent(&world), 0);
+        assert_eq!(world.resource::<Rematch>().proposed_by(), Some(peer(2)));
+
+        let conclusion = Conclusion {
+            outcome: GameOutcome::Draw,
+            reason: ConclusionReason::DrawAgreement,
+            head: Default::default(),
+        };
+        let result = serde_json::json!({ "signatures": [], "concluded": conclusion });
+        world.insert_resource(serde_json::from_value::<GameResult>(result).unwrap());
+        world.send_event(EventAcceptRematch { dht_key: peer(2) });
+        world.run_system(commands).unwrap();
+        assert_eq!(sent(&world), 1);
+        assert_eq!(world.resource::<Events<EventRematchAccepted>>().len(), 1);
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use anyhow::Error;
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::codec::CodecKind;
+use crate::envelope::base64_payload;
+use crate::{
+    timestamp, EventError, EventMessageSent, EventReceiveMessage, EventSendMessage, VeilidApp,
+    VeilidSettings,
+};
+
+/// Plays a [`VeilidReplay`] inserted as [`ReplayPlayer`] back as [`EventReceiveMessage`],
+/// without a network. Works with or without [`VeilidPlugin`](crate::VeilidPlugin).
+#[derive(Default, Clone)]
+pub struct ReplayPlugin<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(pub PhantomData<T>);
+
+impl<T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static>
+    Plugin for ReplayPlugin<T>
+{
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, play_replay::<T>);
+        app.add_event::<EventReceiveMessage<T>>();
+        app.add_event::<EventReplayRestarted>();
+        app.add_event::<EventReplayFinished>();
+        app.add_event::<EventError>();
+    }
+}
+
+/// A message sent or received while recording.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplayMessage {
+    /// Frames since the recording started.
+    pub tick: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub sender: CryptoTyped<CryptoKey>,
+    /// Sent by the player who recorded the replay.
+    pub sent: bool,
+    pub codec: CodecKind,
+    #[serde(with = "base64_payload")]
+    pub payload: Vec<u8>,
+}
+
+impl ReplayMessage {
+    pub fn open<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.codec.decode(&self.payload)
+    }
+}
+
+/// Every message of a game, in the order it was sent or received.
+#[derive(Asset, TypePath, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct VeilidReplay {
+    /// The player who recorded the replay.
+    pub player: Option<CryptoTyped<CryptoKey>>,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: u64,
+    pub messages: Vec<ReplayMessage>,
+}
+
+impl VeilidReplay {
+    /// Tick of the last message.
+    pub fn length(&self) -> u64 {
+        self.messages.last().map(|m| m.tick).unwrap_or_default()
+    }
+
+    pub fn export(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn import(json: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        Ok(std::fs::write(path, self.export()?)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::import(&std::fs::read_to_string(path)?)
+    }
+}
+
+/// Records every message sent and received while inserted. Sent messages are recorded
+/// once [`EventMessageSent`] confirms them.
+#[derive(Resource, Default)]
+pub struct ReplayRecorder {
+    replay: VeilidReplay,
+    tick: u64,
+    unsent: HashMap<Uuid, ReplayMessage>,
+}
+
+impl ReplayRecorder {
+    pub fn replay(&self) -> &VeilidReplay {
+        &self.replay
+    }
+
+    /// Returns the recording so far and starts a new one.
+    pub fn take(&mut self) -> VeilidReplay {
+        self.tick = 0;
+        self.unsent.clear();
+        std::mem::take(&mut self.replay)
+    }
+
+    fn sent(&mut self, uuid: Uuid) {
+        if let Some(mut message) = self.unsent.remove(&uuid) {
+            message.tick = self.tick;
+            message.timestamp = timestamp();
+            self.replay.messages.push(message);
+        }
+    }
+}
+
+/// Playback state of a [`VeilidReplay`]. Plays one tick per frame.
+#[derive(Resource)]
+pub struct ReplayPlayer {
+    replay: VeilidReplay,
+    next: usize,
+    tick: u64,
+    paused: bool,
+    step: bool,
+    seek: Option<u64>,
+    finished: bool,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: VeilidReplay) -> Self {
+        Self {
+            replay,
+            next: 0,
+            tick: 0,
+            paused: false,
+            step: false,
+            seek: None,
+            finished: false,
+        }
+    }
+
+    pub fn replay(&self) -> &VeilidReplay {
+        &self.replay
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.replay.messages.len()
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn play(&mut self) {
+        self.paused = false;
+    }
+
+    /// Jumps to the next message and delivers it, also while paused.
+    pub fn step(&mut self) {
+        self.step = true;
+    }
+
+    /// Jumps to `tick`, delivering every message up to it. Seeking backwards starts over
+    /// with [`EventReplayRestarted`], and the messages follow a frame later.
+    pub fn seek(&mut self, tick: u64) {
+        self.seek = Some(tick);
+    }
+}
+
+// ------
+// Events
+// ------
+
+/// Playback went back to the start. Reset the game state; messages up to the seek target
+/// are delivered in the next frame.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventReplayRestarted;
+
+/// The last message of the replay was delivered.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventReplayFinished;
+
+// -------
+// Systems
+// -------
+
+fn encode_message<T: Serialize>(
+    codec: CodecKind,
+    tick: u64,
+    sender: CryptoTyped<CryptoKey>,
+    sent: bool,
+    message: &T,
+) -> Result<ReplayMessage, Error> {
+    Ok(ReplayMessage {
+        tick,
+        timestamp: timestamp(),
+        sender,
+        sent,
+        codec,
+        payload: codec.encode(message)?,
+    })
+}
+
+pub(crate) fn record_replay<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    mut er_send_message: EventReader<EventSendMessage<T>>,
+    mut er_message_sent: EventReader<EventMessageSent>,
+    mut er_receive_message: EventReader<EventReceiveMessage<T>>,
+    mut ew_error: EventWriter<EventError>,
+    recorder: Option<ResMut<ReplayRecorder>>,
+    veilid_app: Res<VeilidApp>,
+    settings: Res<VeilidSettings>,
+) {
+    let (Some(mut recorder), Some(app)) = (recorder, veilid_app.app.as_ref()) else {
+        er_send_message.clear();
+        er_message_sent.clear();
+        er_receive_message.clear();
+        return;
+    };
+
+    if recorder.replay.player.is_none() {
+        recorder.replay.player = Some(app.our_dht_key);
+        recorder.replay.started_at = timestamp();
+    }
+    let tick = recorder.tick;
+
+    // Held until sent, so messages that never left aren't recorded
+    for e in er_send_message.read() {
+        match encode_message(settings.codec, tick, app.our_dht_key, true, &e.message) {
+            Ok(message) => {
+                recorder.unsent.insert(e.uuid, message);
+            }
+            Err(err) => {
+                ew_error.send(EventError(err));
+            }
+        }
+    }
+    for e in er_message_sent.read() {
+        recorder.sent(e.uuid);
+    }
+    for e in er_receive_message.read() {
+        match encode_message(settings.codec, tick, e.dht_key, false, &e.message) {
+            Ok(message) => recorder.replay.messages.push(message),
+            Err(err) => {
+                ew_error.send(EventError(err));
+            }
+        }
+    }
+    recorder.tick += 1;
+}
+
+pub(crate) fn play_replay<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    mut ew_receive_message: EventWriter<EventReceiveMessage<T>>,
+    mut ew_replay_restarted: EventWriter<EventReplayRestarted>,
+    mut ew_replay_finished: EventWriter<EventReplayFinished>,
+    mut ew_error: EventWriter<EventError>,
+    player: Option<ResMut<ReplayPlayer>>,
+) {
+    let Some(mut player) = player else {
+        return;
+    };
+
+    if let Some(target) = player.seek {
+        if target < player.tick {
+            player.next = 0;
+            player.tick = 0;
+            player.finished = false;
+            ew_replay_restarted.send(EventReplayRestarted);
+            return;
+        }
+        player.seek = None;
+        player.tick = target;
+    } else if player.step {
+        player.step = false;
+        let Some(tick) = player.replay.messages.get(player.next).map(|m| m.tick) else {
+            return;
+        };
+        player.tick = player.tick.max(tick);
+    } else if !player.paused {
+        player.tick += 1;
+    } else {
+        return;
+    }
+
+    while let Some(message) = player.replay.messages.get(player.next) {
+        if message.tick > player.tick {
+            break;
+        }
+        match message.open::<T>() {
+            Ok(decoded) => {
+                ew_receive_message.send(EventReceiveMessage {
+                    message: decoded,
+                    dht_key: message.sender,
+                });
+            }
+            Err(err) => {
+                ew_error.send(EventError(err));
+            }
+        }
+        player.next += 1;
+    }
+
+    if player.is_finished() && !player.finished {
+        player.finished = true;
+        ew_replay_finished.send(EventReplayFinished);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn player(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn message(tick: u64, value: u32) -> ReplayMessage {
+        encode_message(CodecKind::Json, tick, player(1), false, &value).unwrap()
+    }
+
+    fn app(ticks: &[u64]) -> App {
+        let replay = VeilidReplay {
+            player: Some(player(2)),
+            started_at: 0,
+            messages: ticks
+                .iter()
+                .enumerate()
+                .map(|(i, tick)| message(*tick, i as u32))
+                .collect(),
+        };
+        let mut app = App::new();
+        app.add_plugins(ReplayPlugin::<u32>::default())
+            .insert_resource(ReplayPlayer::new(replay));
+        app
+    }
+
+    fn update(app: &mut App) -> Vec<u32> {
+        app.update();
+        let events = app.world().resource::<Events<EventReceiveMessage<u32>>>();
+        events
+            .iter_current_update_events()
+            .map(|e| e.message)
+            .collect()
+    }
+
+    fn player_mut(app: &mut App) -> Mut<'_, ReplayPlayer> {
+        app.world_mut().resource_mut::<ReplayPlayer>()
+    }
+
+    #[test]
+    fn records_sent_messages_once_they_are_sent() {
+        let mut recorder = ReplayRecorder::default();
+        let sent = Uuid::new_v4();
+        recorder.unsent.insert(sent, message(0, 1));
+        recorder.unsent.insert(Uuid::new_v4(), message(0, 2));
+        recorder.tick = 3;
+
+        recorder.sent(sent);
+        recorder.sent(sent);
+        assert_eq!(recorder.replay().messages.len(), 1);
+        assert_eq!(recorder.replay().messages[0].tick, 3);
+        assert_eq!(recorder.replay().messages[0].open::<u32>().unwrap(), 1);
+
+        recorder.take();
+        assert!(recorder.unsent.is_empty());
+        assert!(recorder.replay().messages.is_empty());
+    }
+
+    #[test]
+    fn plays_one_tick_per_frame() {
+        let mut app = app(&[1, 1, 3]);
+        assert_eq!(update(&mut app), vec![0, 1]);
+        assert!(update(&mut app).is_empty());
+        assert_eq!(update(&mut app), vec![2]);
+        assert!(app.world().resource::<ReplayPlayer>().is_finished());
+    }
+
+    #[test]
+    fn steps_to_the_next_message_while_paused() {
+        let mut app = app(&[5, 9]);
+        player_mut(&mut app).pause();
+        assert!(update(&mut app).is_empty());
+
+        player_mut(&mut app).step();
+        assert_eq!(update(&mut app), vec![0]);
+        assert_eq!(app.world().resource::<ReplayPlayer>().tick(), 5);
+        assert!(update(&mut app).is_empty());
+
+        player_mut(&mut app).step();
+        assert_eq!(update(&mut app), vec![1]);
+    }
+
+    #[test]
+    fn seeks_forward_and_back() {
+        let mut app = app(&[1, 4, 8]);
+        player_mut(&mut app).seek(4);
+        assert_eq!(update(&mut app), vec![0, 1]);
+
+        player_mut(&mut app).seek(2);
+        assert!(update(&mut app).is_empty());
+        let restarted = app.world().resource::<Events<EventReplayRestarted>>();
+        assert_eq!(restarted.iter_current_update_events().count(), 1);
+        assert_eq!(update(&mut app), vec![0]);
+        assert_eq!(app.world().resource::<ReplayPlayer>().tick(), 2);
+    }
+}
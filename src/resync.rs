@@ -0,0 +1,418 @@
+use std::any::type_name;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::codec::{CodecKind, PeerCodecs};
+use crate::envelope::base64_payload;
+use crate::lockstep::LockstepLog;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::transcript::Transcript;
+use crate::turn::TurnState;
+use crate::{EventError, VeilidSettings};
+
+type Apply = Box<dyn FnOnce(&mut World) + Send>;
+type ApplyToEntity = Box<dyn FnOnce(&mut EntityWorldMut) + Send>;
+
+struct ResourceEntry {
+    name: &'static str,
+    save: fn(&World, CodecKind) -> Result<Option<Vec<u8>>, Error>,
+    load: fn(CodecKind, &[u8]) -> Result<Apply, Error>,
+}
+
+struct ComponentEntry {
+    name: &'static str,
+    save: fn(&mut World, CodecKind) -> Result<Vec<(SnapshotId, Vec<u8>)>, Error>,
+    load: fn(CodecKind, &[u8]) -> Result<ApplyToEntity, Error>,
+    remove: fn(&mut EntityWorldMut),
+}
+
+/// Identifies an entity in snapshots. Both peers must give the same entity the same id,
+/// e.g. the index of a piece. Registered components are only captured and restored on
+/// entities with an id.
+#[derive(
+    Component, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct SnapshotId(pub u64);
+
+/// Resources and components included in resync snapshots. Both peers must register the
+/// same types.
+#[derive(Resource, Default)]
+pub struct SnapshotRegistry {
+    resources: Vec<ResourceEntry>,
+    components: Vec<ComponentEntry>,
+}
+
+impl SnapshotRegistry {
+    pub fn resource<R: Resource + Serialize + DeserializeOwned>(&mut self) -> &mut Self {
+        self.resources.push(ResourceEntry {
+            name: type_name::<R>(),
+            save: |world, codec| {
+                world
+                    .get_resource::<R>()
+                    .map(|resource| codec.encode(resource))
+                    .transpose()
+            },
+            load: |codec, bytes| {
+                let resource: R = codec.decode(bytes)?;
+                Ok(Box::new(move |world: &mut World| {
+                    world.insert_resource(resource)
+                }))
+            },
+        });
+        self
+    }
+
+    pub fn component<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self {
+        self.components.push(ComponentEntry {
+            name: type_name::<C>(),
+            save: |world, codec| {
+                let mut query = world.query::<(&SnapshotId, &C)>();
+                query
+                    .iter(world)
+                    .map(|(id, component)| Ok((*id, codec.encode(component)?)))
+                    .collect()
+            },
+            load: |codec, bytes| {
+                let component: C = codec.decode(bytes)?;
+                Ok(Box::new(move |entity: &mut EntityWorldMut| {
+                    entity.insert(component);
+                }))
+            },
+            remove: |entity| {
+                entity.remove::<C>();
+            },
+        });
+        self
+    }
+
+    pub(crate) fn capture(&self, world: &mut World, codec: CodecKind) -> Result<Snapshot, Error> {
+        let mut resources = Vec::new();
+        for entry in &self.resources {
+            if let Some(bytes) = (entry.save)(world, codec)? {
+                resources.push(Blob {
+                    name: entry.name.to_string(),
+                    bytes,
+                });
+            }
+        }
+
+        let mut entities: BTreeMap<SnapshotId, Vec<Blob>> = BTreeMap::new();
+        for entry in &self.components {
+            for (id, bytes) in (entry.save)(world, codec)? {
+                entities.entry(id).or_default().push(Blob {
+                    name: entry.name.to_string(),
+                    bytes,
+                });
+            }
+        }
+
+        Ok(Snapshot {
+            codec,
+            turn_state: world.resource::<TurnState>().clone(),
+            transcript: world.resource::<Transcript>().clone(),
+            resources,
+            entities: entities
+                .into_iter()
+                .map(|(id, components)| SnapshotEntity { id, components })
+                .collect(),
+        })
+    }
+
+    pub(crate) fn restore(&self, world: &mut World, snapshot: Snapshot) -> Result<(), Error> {
+        let resources = snapshot
+            .resources
+            .iter()
+            .map(|blob| {
+                let entry = self
+                    .resources
+                    .iter()
+                    .find(|entry| entry.name == blob.name)
+                    .ok_or_else(|| anyhow!("snapshot has unregistered resource {}", blob.name))?;
+                (entry.load)(snapshot.codec, &blob.bytes)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let entities = snapshot
+            .entities
+            .iter()
+            .map(|entity| {
+                let components = entity
+                    .components
+                    .iter()
+                    .map(|blob| {
+                        let entry = self
+                            .components
+                            .iter()
+                            .find(|entry| entry.name == blob.name)
+                            .ok_or_else(|| {
+                                anyhow!("snapshot has unregistered component {}", blob.name)
+                            })?;
+                        (entry.load)(snapshot.codec, &blob.bytes)
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok((entity.id, components))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // Only registered components change. Entities keep everything else, and ones
+        // missing from the snapshot just lose their registered components.
+        let existing: HashMap<SnapshotId, Entity> = world
+            .query::<(Entity, &SnapshotId)>()
+            .iter(world)
+            .map(|(entity, id)| (*id, entity))
+            .collect();
+        for entity in existing.values() {
+            let mut entity = world.entity_mut(*entity);
+            for entry in &self.components {
+                (entry.remove)(&mut entity);
+            }
+        }
+
+        for apply in resources {
+            apply(world);
+        }
+        for (id, components) in entities {
+            let mut entity = match existing.get(&id) {
+                Some(entity) => world.entity_mut(*entity),
+                None => world.spawn(id),
+            };
+            for apply in components {
+                apply(&mut entity);
+            }
+        }
+
+        world
+            .resource_mut::<TurnState>()
+            .restore(snapshot.turn_state);
+        *world.resource_mut::<Transcript>() = snapshot.transcript;
+        world.resource_mut::<LockstepLog>().clear();
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Blob {
+    name: String,
+    #[serde(with = "base64_payload")]
+    bytes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SnapshotEntity {
+    id: SnapshotId,
+    components: Vec<Blob>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Snapshot {
+    codec: CodecKind,
+    turn_state: TurnState,
+    #[serde(default)]
+    transcript: Transcript,
+    resources: Vec<Blob>,
+    entities: Vec<SnapshotEntity>,
+}
+
+impl Snapshot {
+    pub(crate) fn turn_state(&self) -> &TurnState {
+        &self.turn_state
+    }
+
+    pub(crate) fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ResyncMessage {
+    Request { id: Uuid },
+    Snapshot { id: Uuid, snapshot: Snapshot },
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct Resyncs {
+    requested: HashSet<Uuid>,
+    to_send: Vec<(Uuid, CryptoTyped<CryptoKey>)>,
+    to_apply: Vec<(Snapshot, CryptoTyped<CryptoKey>)>,
+}
+
+// ------
+// Events
+// ------
+
+/// Asks `dht_key` for a snapshot of the registered state and replaces the local state
+/// with it, e.g. after [`EventDesync`](crate::EventDesync) or when rejoining a game.
+#[derive(Event)]
+pub struct EventRequestResync {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// A snapshot from `dht_key` was applied.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventResynced {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub turn: u32,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn on_ev_request_resync(
+    mut er_request_resync: EventReader<EventRequestResync>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut resyncs: ResMut<Resyncs>,
+) {
+    for e in er_request_resync.read() {
+        let id = Uuid::new_v4();
+        resyncs.requested.insert(id);
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Resync(ResyncMessage::Request { id }),
+            dht_key: e.dht_key,
+        });
+    }
+}
+
+pub(crate) fn on_ev_receive_resync(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut resyncs: ResMut<Resyncs>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Resync(message) = &e.message else {
+            continue;
+        };
+
+        match message {
+            ResyncMessage::Request { id } => resyncs.to_send.push((*id, e.dht_key)),
+            ResyncMessage::Snapshot { id, snapshot } => {
+                // Only snapshots we asked for may overwrite local state
+                if resyncs.requested.remove(id) {
+                    resyncs.to_apply.push((snapshot.clone(), e.dht_key));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn process_resyncs(world: &mut World) {
+    let (to_send, to_apply) = {
+        let mut resyncs = world.resource_mut::<Resyncs>();
+        (
+            std::mem::take(&mut resyncs.to_send),
+            std::mem::take(&mut resyncs.to_apply),
+        )
+    };
+    if to_send.is_empty() && to_apply.is_empty() {
+        return;
+    }
+
+    let preferred = world.resource::<VeilidSettings>().codec;
+    world.resource_scope(|world, registry: Mut<SnapshotRegistry>| {
+        for (id, dht_key) in to_send {
+            let codec = world.resource::<PeerCodecs>().choose(dht_key, preferred);
+            match registry.capture(world, codec) {
+                Ok(snapshot) => {
+                    world.send_event(EventSendProtocol {
+                        message: ProtocolMessage::Resync(ResyncMessage::Snapshot { id, snapshot }),
+                        dht_key,
+                    });
+                }
+                Err(err) => {
+                    world.send_event(EventError(err));
+                }
+            }
+        }
+
+        for (snapshot, dht_key) in to_apply {
+            let turn = snapshot.turn_state.turn();
+            match registry.restore(world, snapshot) {
+                Ok(()) => {
+                    world.send_event(EventResynced { dht_key, turn });
+                }
+                Err(err) => {
+                    world.send_event(EventError(err));
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Resource, Serialize, Deserialize, Debug, PartialEq)]
+    struct Counter(u32);
+
+    #[derive(Component, Serialize, Deserialize, Debug, PartialEq)]
+    struct Piece(u8);
+
+    #[derive(Component, Debug, PartialEq)]
+    struct Sprite;
+
+    fn setup() -> (World, SnapshotRegistry) {
+        let mut world = World::new();
+        world.init_resource::<TurnState>();
+        world.init_resource::<Transcript>();
+        world.init_resource::<LockstepLog>();
+        let mut registry = SnapshotRegistry::default();
+        registry.resource::<Counter>().component::<Piece>();
+        (world, registry)
+    }
+
+    #[test]
+    fn restores_registered_state_on_the_same_entities() {
+        let (mut world, registry) = setup();
+        world.insert_resource(Counter(1));
+        let first = world.spawn((SnapshotId(1), Piece(1), Sprite)).id();
+        let second = world.spawn((SnapshotId(2), Piece(2))).id();
+        let untracked = world.spawn(Piece(9)).id();
+        let snapshot = registry.capture(&mut world, CodecKind::Json).unwrap();
+
+        world.insert_resource(Counter(5));
+        world.entity_mut(first).insert(Piece(7));
+        world.entity_mut(second).remove::<Piece>();
+        let extra = world.spawn((SnapshotId(3), Piece(3), Sprite)).id();
+
+        registry.restore(&mut world, snapshot).unwrap();
+        assert_eq!(world.resource::<Counter>(), &Counter(1));
+        assert_eq!(world.get::<Piece>(first), Some(&Piece(1)));
+        assert_eq!(world.get::<Sprite>(first), Some(&Sprite));
+        assert_eq!(world.get::<Piece>(second), Some(&Piece(2)));
+        assert_eq!(world.get::<Piece>(untracked), Some(&Piece(9)));
+        assert_eq!(world.get::<Piece>(extra), None);
+        assert_eq!(world.get::<Sprite>(extra), Some(&Sprite));
+    }
+
+    #[test]
+    fn spawns_entities_missing_locally() {
+        let (mut world, registry) = setup();
+        world.spawn((SnapshotId(4), Piece(4)));
+        let snapshot = registry.capture(&mut world, CodecKind::Json).unwrap();
+
+        let (mut other, _) = setup();
+        registry.restore(&mut other, snapshot).unwrap();
+        let pieces: Vec<_> = other
+            .query::<(&SnapshotId, &Piece)>()
+            .iter(&other)
+            .map(|(id, piece)| (id.0, piece.0))
+            .collect();
+        assert_eq!(pieces, vec![(4, 4)]);
+    }
+
+    #[test]
+    fn encodes_blobs_as_base64() {
+        let (mut world, registry) = setup();
+        world.insert_resource(Counter(1));
+        let snapshot = registry.capture(&mut world, CodecKind::Json).unwrap();
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert!(json["resources"][0]["bytes"].is_string());
+    }
+}
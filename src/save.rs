@@ -0,0 +1,503 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped, KeyPair, PublicKey, Signature};
+
+use crate::clock::TurnClock;
+use crate::identity::write_private;
+use crate::mailbox::Correspondence;
+use crate::outcome::GameResult;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::resync::{Snapshot, SnapshotRegistry};
+use crate::signing::{sign_with, verify, RecordOwners};
+use crate::transcript::{Transcript, TranscriptHash};
+use crate::turn::TurnState;
+use crate::{timestamp, EventError, VeilidApp, VeilidSettings};
+
+fn session_id(
+    a: CryptoTyped<CryptoKey>,
+    b: CryptoTyped<CryptoKey>,
+    first_move: TranscriptHash,
+) -> Uuid {
+    let mut players = [a.to_string(), b.to_string()];
+    players.sort();
+    let mut hasher = blake3::Hasher::new();
+    for player in players {
+        hasher.update(player.as_bytes());
+    }
+    hasher.update(&first_move);
+    let hash = hasher.finalize();
+    Uuid::from_bytes(hash.as_bytes()[..16].try_into().unwrap())
+}
+
+fn resume_data(
+    session: Uuid,
+    player: CryptoTyped<CryptoKey>,
+    sender: CryptoTyped<CryptoKey>,
+    destination: CryptoTyped<CryptoKey>,
+    head: TranscriptHash,
+) -> Vec<u8> {
+    let mut data = b"bevy_veilid resume".to_vec();
+    data.extend(session.as_bytes());
+    data.extend(format!("{player}/{sender}>{destination}").into_bytes());
+    data.extend(head);
+    data
+}
+
+/// A game in progress as written to disk.
+///
+/// The file holds the secret key of the local player's DHT record in plain JSON, the same
+/// key as the identity file when a persistent identity is used. Anyone who reads it can
+/// sign as that player, so keep it as private as the identity file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedGame {
+    pub session: Uuid,
+    /// DHT key of the local player when the game was saved.
+    pub player: CryptoTyped<CryptoKey>,
+    pub peer: CryptoTyped<CryptoKey>,
+    /// Milliseconds since the Unix epoch.
+    pub saved_at: u64,
+    // Proves on resume that we played the saved game, see `resume_data`.
+    player_keypair: KeyPair,
+    peer_owner: PublicKey,
+    snapshot: Snapshot,
+    #[serde(default)]
+    clock: TurnClock,
+    #[serde(default)]
+    result: GameResult,
+    #[serde(default)]
+    correspondence: Correspondence,
+}
+
+impl SavedGame {
+    pub fn turn_state(&self) -> &TurnState {
+        self.snapshot.turn_state()
+    }
+
+    /// The clocks as they stood when the game was saved.
+    pub fn clock(&self) -> &TurnClock {
+        &self.clock
+    }
+
+    pub fn result(&self) -> &GameResult {
+        &self.result
+    }
+
+    /// The mailboxes of a correspondence game, with how far each was read.
+    pub fn correspondence(&self) -> &Correspondence {
+        &self.correspondence
+    }
+
+    pub fn transcript(&self) -> &Transcript {
+        self.snapshot.transcript()
+    }
+
+    /// Writes the game, readable by its owner only on Unix (mode 0600).
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        write_private(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+struct Loaded {
+    session: Uuid,
+    player: CryptoTyped<CryptoKey>,
+    peer: CryptoTyped<CryptoKey>,
+    player_keypair: KeyPair,
+    peer_owner: PublicKey,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct SavedGames {
+    to_save: Vec<PathBuf>,
+    to_load: Vec<PathBuf>,
+    loaded: Option<Loaded>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum SessionMessage {
+    Resume {
+        session: Uuid,
+        player: CryptoTyped<CryptoKey>,
+        head: TranscriptHash,
+        proof: Signature,
+    },
+    Resumed {
+        session: Uuid,
+        proof: Signature,
+    },
+    Refused {
+        session: Uuid,
+        reason: String,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Writes the game with [`VeilidApp::other_peer_dht`] to `path`.
+#[derive(Event)]
+pub struct EventSaveGame {
+    pub path: PathBuf,
+}
+
+/// Restores the game saved at `path`. Send [`EventResumeSession`] once the peer is
+/// connected again.
+#[derive(Event)]
+pub struct EventLoadGame {
+    pub path: PathBuf,
+}
+
+/// Asks `dht_key` to continue the loaded game. The peer must have loaded its own save of
+/// the same game at the same move.
+#[derive(Event)]
+pub struct EventResumeSession {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct EventGameSaved {
+    pub path: PathBuf,
+    pub session: Uuid,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventGameLoaded {
+    pub session: Uuid,
+    pub turn: u32,
+}
+
+/// Both players continue the loaded game, `dht_key` in the seat of the saved peer.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventSessionResumed {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub session: Uuid,
+    pub turn: u32,
+}
+
+#[derive(Event, Debug, Clone)]
+pub struct EventSessionResumeFailed {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub reason: String,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn on_save_commands(
+    mut er_save_game: EventReader<EventSaveGame>,
+    mut er_load_game: EventReader<EventLoadGame>,
+    mut saved_games: ResMut<SavedGames>,
+) {
+    for e in er_save_game.read() {
+        saved_games.to_save.push(e.path.clone());
+    }
+    for e in er_load_game.read() {
+        saved_games.to_load.push(e.path.clone());
+    }
+}
+
+fn save_game(world: &mut World, registry: &SnapshotRegistry) -> Result<SavedGame, Error> {
+    let veilid_app = world.resource::<VeilidApp>();
+    let app = veilid_app
+        .app
+        .as_ref()
+        .ok_or_else(|| anyhow!("veilid is not initialized"))?;
+    let (player, player_keypair) = (app.our_dht_key, app.dht_keypair);
+    let peer = veilid_app
+        .other_peer_dht
+        .ok_or_else(|| anyhow!("no peer to save a game with"))?;
+    let peer_owner = world
+        .resource::<RecordOwners>()
+        .get(peer)
+        .ok_or_else(|| anyhow!("no verified message from {peer} yet"))?;
+
+    let first_move = world
+        .resource::<Transcript>()
+        .entries()
+        .first()
+        .map(|entry| entry.hash)
+        .unwrap_or_default();
+    let codec = world.resource::<VeilidSettings>().codec;
+    let saved_at = timestamp();
+    // A running pause ends with the save, and the time until the load isn't charged.
+    let mut clock = world.resource::<TurnClock>().clone();
+    clock.resume(saved_at, world.resource::<TurnState>().started_at());
+    Ok(SavedGame {
+        session: session_id(player, peer, first_move),
+        player,
+        peer,
+        saved_at,
+        player_keypair,
+        peer_owner,
+        snapshot: registry.capture(world, codec)?,
+        clock,
+        result: world.resource::<GameResult>().clone(),
+        correspondence: world.resource::<Correspondence>().clone(),
+    })
+}
+
+pub(crate) fn process_saved_games(world: &mut World) {
+    let (to_save, to_load) = {
+        let mut saved_games = world.resource_mut::<SavedGames>();
+        (
+            std::mem::take(&mut saved_games.to_save),
+            std::mem::take(&mut saved_games.to_load),
+        )
+    };
+    if to_save.is_empty() && to_load.is_empty() {
+        return;
+    }
+
+    world.resource_scope(|world, registry: Mut<SnapshotRegistry>| {
+        for path in to_save {
+            let result = save_game(world, &registry).and_then(|saved| {
+                saved.save(&path)?;
+                Ok(saved.session)
+            });
+            match result {
+                Ok(session) => {
+                    world.send_event(EventGameSaved { path, session });
+                }
+                Err(err) => {
+                    world.send_event(EventError(err));
+                }
+            }
+        }
+
+        for path in to_load {
+            let result = SavedGame::load(&path).and_then(|saved| {
+                let loaded = Loaded {
+                    session: saved.session,
+                    player: saved.player,
+                    peer: saved.peer,
+                    player_keypair: saved.player_keypair,
+                    peer_owner: saved.peer_owner,
+                };
+                registry.restore(world, saved.snapshot)?;
+                let mut clock = saved.clock;
+                clock.pause(saved.saved_at);
+                clock.resume(timestamp(), world.resource::<TurnState>().started_at());
+                world.insert_resource(clock);
+                world.insert_resource(saved.result);
+                world.insert_resource(saved.correspondence);
+                Ok(loaded)
+            });
+            match result {
+                Ok(loaded) => {
+                    let session = loaded.session;
+                    world.resource_mut::<SavedGames>().loaded = Some(loaded);
+                    let turn = world.resource::<TurnState>().turn();
+                    world.send_event(EventGameLoaded { session, turn });
+                }
+                Err(err) => {
+                    world.send_event(EventError(err));
+                }
+            }
+        }
+    });
+}
+
+pub(crate) fn on_ev_resume_session(
+    mut er_resume_session: EventReader<EventResumeSession>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_resume_failed: EventWriter<EventSessionResumeFailed>,
+    mut ew_error: EventWriter<EventError>,
+    saved_games: Res<SavedGames>,
+    transcript: Res<Transcript>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+
+    for e in er_resume_session.read() {
+        let Some(loaded) = saved_games.loaded.as_ref() else {
+            ew_resume_failed.send(EventSessionResumeFailed {
+                dht_key: e.dht_key,
+                reason: "no game loaded".to_string(),
+            });
+            continue;
+        };
+        let head = transcript.head();
+        let data = resume_data(
+            loaded.session,
+            loaded.player,
+            app.our_dht_key,
+            e.dht_key,
+            head,
+        );
+        let proof = match sign_with(app, &loaded.player_keypair, &data) {
+            Ok(proof) => proof,
+            Err(err) => {
+                ew_error.send(EventError(err));
+                continue;
+            }
+        };
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::Session(SessionMessage::Resume {
+                session: loaded.session,
+                player: loaded.player,
+                head,
+                proof,
+            }),
+            dht_key: e.dht_key,
+        });
+    }
+}
+
+fn take_seats(
+    loaded: Loaded,
+    peer: CryptoTyped<CryptoKey>,
+    veilid_app: &mut VeilidApp,
+    turn_state: &mut TurnState,
+    correspondence: &mut Correspondence,
+) {
+    if let Some(app) = veilid_app.app.as_ref() {
+        turn_state.replace_player(loaded.player, app.our_dht_key);
+    }
+    turn_state.replace_player(loaded.peer, peer);
+    correspondence.replace_peer(loaded.peer, peer);
+    veilid_app.other_peer_dht = Some(peer);
+}
+
+pub(crate) fn on_ev_receive_session(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_session_resumed: EventWriter<EventSessionResumed>,
+    mut ew_resume_failed: EventWriter<EventSessionResumeFailed>,
+    mut saved_games: ResMut<SavedGames>,
+    mut turn_state: ResMut<TurnState>,
+    mut ew_error: EventWriter<EventError>,
+    mut veilid_app: ResMut<VeilidApp>,
+    mut correspondence: ResMut<Correspondence>,
+    transcript: Res<Transcript>,
+) {
+    let Some(app) = veilid_app.app.clone() else {
+        return;
+    };
+
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Session(message) = &e.message else {
+            continue;
+        };
+
+        match message {
+            SessionMessage::Resume {
+                session,
+                player,
+                head,
+                proof,
+            } => {
+                let refusal = match saved_games.loaded.as_ref() {
+                    None => Some("no game loaded"),
+                    Some(loaded) if loaded.session != *session || loaded.peer != *player => {
+                        Some("a different game is loaded")
+                    }
+                    Some(_) if transcript.head() != *head => Some("transcripts differ"),
+                    Some(loaded) => {
+                        let data =
+                            resume_data(*session, *player, e.dht_key, app.our_dht_key, *head);
+                        verify(&app, &loaded.peer_owner, &data, proof)
+                            .err()
+                            .map(|_| "no proof of playing the saved game")
+                    }
+                };
+                if let Some(reason) = refusal {
+                    ew_send_protocol.send(EventSendProtocol {
+                        message: ProtocolMessage::Session(SessionMessage::Refused {
+                            session: *session,
+                            reason: reason.to_string(),
+                        }),
+                        dht_key: e.dht_key,
+                    });
+                    ew_resume_failed.send(EventSessionResumeFailed {
+                        dht_key: e.dht_key,
+                        reason: reason.to_string(),
+                    });
+                    continue;
+                }
+
+                let loaded = saved_games.loaded.take().unwrap();
+                let data = resume_data(*session, loaded.player, app.our_dht_key, e.dht_key, *head);
+                let proof = match sign_with(&app, &loaded.player_keypair, &data) {
+                    Ok(proof) => proof,
+                    Err(err) => {
+                        saved_games.loaded = Some(loaded);
+                        ew_error.send(EventError(err));
+                        continue;
+                    }
+                };
+                take_seats(
+                    loaded,
+                    e.dht_key,
+                    &mut veilid_app,
+                    &mut turn_state,
+                    &mut correspondence,
+                );
+                ew_send_protocol.send(EventSendProtocol {
+                    message: ProtocolMessage::Session(SessionMessage::Resumed {
+                        session: *session,
+                        proof,
+                    }),
+                    dht_key: e.dht_key,
+                });
+                ew_session_resumed.send(EventSessionResumed {
+                    dht_key: e.dht_key,
+                    session: *session,
+                    turn: turn_state.turn(),
+                });
+            }
+            SessionMessage::Resumed { session, proof } => {
+                let Some(loaded) = saved_games
+                    .loaded
+                    .as_ref()
+                    .filter(|loaded| loaded.session == *session)
+                else {
+                    continue;
+                };
+                let data = resume_data(
+                    *session,
+                    loaded.peer,
+                    e.dht_key,
+                    app.our_dht_key,
+                    transcript.head(),
+                );
+                if verify(&app, &loaded.peer_owner, &data, proof).is_err() {
+                    ew_resume_failed.send(EventSessionResumeFailed {
+                        dht_key: e.dht_key,
+                        reason: "no proof of playing the saved game".to_string(),
+                    });
+                    continue;
+                }
+                let loaded = saved_games.loaded.take().unwrap();
+                take_seats(
+                    loaded,
+                    e.dht_key,
+                    &mut veilid_app,
+                    &mut turn_state,
+                    &mut correspondence,
+                );
+                ew_session_resumed.send(EventSessionResumed {
+                    dht_key: e.dht_key,
+                    session: *session,
+                    turn: turn_state.turn(),
+                });
+            }
+            SessionMessage::Refused { reason, .. } => {
+                ew_resume_failed.send(EventSessionResumeFailed {
+                    dht_key: e.dht_key,
+                    reason: reason.clone(),
+                });
+            }
+        }
+    }
+}
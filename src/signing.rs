@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use uuid::Uuid;
+use veilid_duplex::utils::CRYPTO_KIND;
+use veilid_duplex::veilid::VeilidDuplex;
+use veilid_duplex::veilid_core::{
+    CryptoKey, CryptoSystemVersion, CryptoTyped, KeyPair, PublicKey, Signature,
+};
+
+use crate::envelope::Envelope;
+
+const REPLAY_WINDOW: u64 = 10 * 60 * 1000;
+
+#[derive(Resource, Clone, Default)]
+pub(crate) struct RecordOwners(Arc<Mutex<HashMap<CryptoTyped<CryptoKey>, PublicKey>>>);
+
+impl RecordOwners {
+    pub(crate) fn get(&self, dht_key: CryptoTyped<CryptoKey>) -> Option<PublicKey> {
+        self.0.lock().unwrap().get(&dht_key).copied()
+    }
+
+    pub(crate) async fn fetch(
+        &self,
+        app: &VeilidDuplex,
+        dht_key: CryptoTyped<CryptoKey>,
+    ) -> Result<PublicKey, Error> {
+        if let Some(owner) = self.get(dht_key) {
+            return Ok(owner);
+        }
+        let owner = record_owner(app, dht_key).await?;
+        self.0.lock().unwrap().insert(dht_key, owner);
+        Ok(owner)
+    }
+}
+
+// Message ids seen from each sender. Ids are forgotten once they are REPLAY_WINDOW
+// older than the newest message of the same sender, so only the sender's clock is
+// compared and peers don't need synchronized clocks.
+#[derive(Default)]
+struct SenderHistory {
+    newest: u64,
+    ids: HashMap<Uuid, u64>,
+}
+
+#[derive(Resource, Clone, Default)]
+pub(crate) struct ReceivedMessages(Arc<Mutex<HashMap<CryptoTyped<CryptoKey>, SenderHistory>>>);
+
+impl ReceivedMessages {
+    pub(crate) fn check(
+        &self,
+        sender: CryptoTyped<CryptoKey>,
+        id: Uuid,
+        sent_at: u64,
+    ) -> Result<(), Error> {
+        let mut received = self.0.lock().unwrap();
+        let history = received.entry(sender).or_default();
+        if sent_at < history.newest.saturating_sub(REPLAY_WINDOW) {
+            return Err(anyhow!("message was sent too long before the latest one"));
+        }
+        if history.ids.insert(id, sent_at).is_some() {
+            return Err(anyhow!("message {id} was already received"));
+        }
+        history.newest = history.newest.max(sent_at);
+        let oldest = history.newest.saturating_sub(REPLAY_WINDOW);
+        history.ids.retain(|_, at| *at >= oldest);
+        Ok(())
+    }
+}
+
+fn crypto_system(app: &VeilidDuplex) -> Result<CryptoSystemVersion, Error> {
+    app.api
+        .crypto()?
+        .get(CRYPTO_KIND)
+        .ok_or_else(|| anyhow!("crypto system is not available"))
+}
+
+pub(crate) fn sign(app: &VeilidDuplex, data: &[u8]) -> Result<Signature, Error> {
+    sign_with(app, &app.dht_keypair, data)
+}
+
+pub(crate) fn sign_with(
+    app: &VeilidDuplex,
+    keypair: &KeyPair,
+    data: &[u8],
+) -> Result<Signature, Error> {
+    Ok(crypto_system(app)?.sign(&keypair.key, &keypair.secret, data)?)
+}
+
+pub(crate) async fn record_owner(
+    app: &VeilidDuplex,
+    dht_key: CryptoTyped<CryptoKey>,
+) -> Result<PublicKey, Error> {
+    let record = app.routing_context.open_dht_record(dht_key, None).await?;
+    let owner = *record.owner();
+    app.routing_context.close_dht_record(dht_key).await?;
+    Ok(owner)
+}
+
+pub(crate) fn verify(
+    app: &VeilidDuplex,
+    public_key: &PublicKey,
+    data: &[u8],
+    signature: &Signature,
+) -> Result<(), Error> {
+    match crypto_system(app)?.verify(public_key, data, signature)? {
+        true => Ok(()),
+        false => Err(anyhow!("invalid signature")),
+    }
+}
+
+pub(crate) async fn verify_peer(
+    app: &VeilidDuplex,
+    dht_key: CryptoTyped<CryptoKey>,
+    data: &[u8],
+    signature: &Signature,
+) -> Result<PublicKey, Error> {
+    let owner = record_owner(app, dht_key).await?;
+    verify(app, &owner, data, signature)?;
+    Ok(owner)
+}
+
+pub(crate) async fn verify_envelope(
+    app: &VeilidDuplex,
+    owners: &RecordOwners,
+    received: &ReceivedMessages,
+    sender: CryptoTyped<CryptoKey>,
+    envelope: &Envelope,
+) -> Result<(), Error> {
+    let signature = envelope
+        .signature
+        .as_ref()
+        .ok_or_else(|| anyhow!("message is not signed"))?;
+    let data = envelope.signed_data(sender, app.our_dht_key)?;
+
+    let owner = owners.fetch(app, sender).await?;
+    verify(app, &owner, &data, signature)?;
+    received.check(sender, envelope.id, envelope.sent_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::timestamp;
+
+    fn peer(b: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([b; 32]))
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let received = ReceivedMessages::default();
+        let id = Uuid::new_v4();
+
+        assert!(received.check(peer(1), id, 1000).is_ok());
+        assert!(received.check(peer(1), id, 1000).is_err());
+        assert!(received.check(peer(1), Uuid::new_v4(), 1000).is_ok());
+        assert!(received.check(peer(2), id, 1000).is_ok());
+    }
+
+    #[test]
+    fn ignores_the_local_clock() {
+        let received = ReceivedMessages::default();
+        let days_ago = timestamp() - 3 * 24 * 60 * 60 * 1000;
+
+        received.check(peer(1), Uuid::new_v4(), days_ago).unwrap();
+        received
+            .check(peer(1), Uuid::new_v4(), timestamp() + 60 * 60 * 1000)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_messages_older_than_the_senders_latest() {
+        let received = ReceivedMessages::default();
+        let sent_at = 10 * REPLAY_WINDOW;
+        received.check(peer(1), Uuid::new_v4(), sent_at).unwrap();
+
+        assert!(received
+            .check(peer(1), Uuid::new_v4(), sent_at - REPLAY_WINDOW - 1)
+            .is_err());
+        assert!(received
+            .check(peer(2), Uuid::new_v4(), sent_at - REPLAY_WINDOW - 1)
+            .is_ok());
+    }
+
+    #[test]
+    fn forgets_ids_outside_the_window() {
+        let received = ReceivedMessages::default();
+        received.check(peer(1), Uuid::new_v4(), 1000).unwrap();
+        received
+            .check(peer(1), Uuid::new_v4(), 1000 + REPLAY_WINDOW + 1)
+            .unwrap();
+
+        assert_eq!(received.0.lock().unwrap()[&peer(1)].ids.len(), 1);
+    }
+}
@@ -0,0 +1,453 @@
+use std::any::type_name;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::codec::{CodecKind, PeerCodecs};
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::rematch::EventRematchStarted;
+use crate::{EventError, VeilidApp, VeilidSettings};
+
+/// Adds commit–reveal rounds for moves of type `T` that both players pick without seeing
+/// the other's, such as rock-paper-scissors or sealed bids. Add it next to
+/// [`VeilidPlugin`](crate::VeilidPlugin).
+#[derive(Default, Clone)]
+pub struct SimultaneousMovePlugin<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(pub PhantomData<T>);
+
+impl<T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static>
+    Plugin for SimultaneousMovePlugin<T>
+{
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimultaneousMove<T>>();
+        app.init_resource::<OpenRounds>();
+        app.add_systems(
+            Update,
+            (on_simultaneous_move::<T>, clear_rounds_on_rematch::<T>),
+        );
+        app.add_event::<EventCommitMove<T>>();
+        app.add_event::<EventMoveCommitted>();
+        app.add_event::<EventMovesRevealed<T>>();
+    }
+}
+
+type Commitment = [u8; 32];
+
+struct LocalMove<T> {
+    value: T,
+    codec: CodecKind,
+    salt: [u8; 32],
+    data: Vec<u8>,
+    revealed: bool,
+}
+
+struct RemoteReveal {
+    codec: CodecKind,
+    salt: [u8; 32],
+    data: Vec<u8>,
+}
+
+struct Round<T> {
+    local: Option<LocalMove<T>>,
+    commitment: Option<Commitment>,
+    reveal: Option<RemoteReveal>,
+    remote: Option<T>,
+}
+
+impl<T> Default for Round<T> {
+    fn default() -> Self {
+        Self {
+            local: None,
+            commitment: None,
+            reveal: None,
+            remote: None,
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Round<T> {
+    fn settle(&mut self, dht_key: CryptoTyped<CryptoKey>, number: u32) -> Result<(), &'static str> {
+        // Only take the reveal once the commitment is here, otherwise keep it for later
+        let Some(expected) = self.commitment else {
+            return Ok(());
+        };
+        let Some(reveal) = self.reveal.take() else {
+            return Ok(());
+        };
+        if commitment(dht_key, number, &reveal.salt, &reveal.data) != expected {
+            return Err("revealed a move that does not match its commitment");
+        }
+        let value = reveal
+            .codec
+            .decode::<T>(&reveal.data)
+            .map_err(|_| "committed to a move that can't be decoded")?;
+        self.remote = Some(value);
+        Ok(())
+    }
+}
+
+/// Open commit–reveal rounds for `T`, per peer and round number.
+#[derive(Resource)]
+pub struct SimultaneousMove<T> {
+    rounds: HashMap<(CryptoTyped<CryptoKey>, u32), Round<T>>,
+}
+
+impl<T> Default for SimultaneousMove<T> {
+    fn default() -> Self {
+        Self {
+            rounds: HashMap::new(),
+        }
+    }
+}
+
+impl<T> SimultaneousMove<T> {
+    /// The local player has committed to a move in `round` with `dht_key`.
+    pub fn is_committed(&self, dht_key: CryptoTyped<CryptoKey>, round: u32) -> bool {
+        self.rounds
+            .get(&(dht_key, round))
+            .is_some_and(|r| r.local.is_some())
+    }
+
+    /// `dht_key` has committed to a move in `round`.
+    pub fn is_peer_committed(&self, dht_key: CryptoTyped<CryptoKey>, round: u32) -> bool {
+        self.rounds
+            .get(&(dht_key, round))
+            .is_some_and(|r| r.commitment.is_some())
+    }
+}
+
+// Rounds still open for each move type, so other systems can tell without knowing `T`
+#[derive(Resource, Default)]
+pub(crate) struct OpenRounds(HashMap<&'static str, usize>);
+
+impl OpenRounds {
+    pub(crate) fn any(&self) -> bool {
+        self.0.values().any(|open| *open > 0)
+    }
+}
+
+fn commitment(player: CryptoTyped<CryptoKey>, round: u32, salt: &[u8], data: &[u8]) -> Commitment {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(player.to_string().as_bytes());
+    hasher.update(&round.to_le_bytes());
+    hasher.update(salt);
+    hasher.update(data);
+    *hasher.finalize().as_bytes()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum CommitRevealMessage {
+    Commit {
+        kind: String,
+        round: u32,
+        commitment: Commitment,
+    },
+    Reveal {
+        kind: String,
+        round: u32,
+        codec: CodecKind,
+        salt: [u8; 32],
+        data: Vec<u8>,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Commits the local player to `value` for `round`. The value is revealed to `dht_key`
+/// only after their commitment for the same round has arrived.
+#[derive(Event)]
+pub struct EventCommitMove<T> {
+    pub round: u32,
+    pub value: T,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// `dht_key` committed to a move in `round`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventMoveCommitted {
+    pub round: u32,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// Both moves of `round` were revealed and the peer's reveal matched its commitment.
+#[derive(Event)]
+pub struct EventMovesRevealed<T> {
+    pub round: u32,
+    pub local: T,
+    pub remote: T,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// `dht_key` broke the protocol, e.g. revealed a move other than the one it committed to.
+#[derive(Event, Debug, Clone)]
+pub struct EventCheatDetected {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    /// 0 for shared random draws.
+    pub round: u32,
+    pub reason: String,
+}
+
+// -------
+// Systems
+// -------
+
+fn reveal_message<T>(kind: &str, round: u32, local: &LocalMove<T>) -> ProtocolMessage {
+    ProtocolMessage::CommitReveal(CommitRevealMessage::Reveal {
+        kind: kind.to_string(),
+        round,
+        codec: local.codec,
+        salt: local.salt,
+        data: local.data.clone(),
+    })
+}
+
+pub(crate) fn on_simultaneous_move<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    mut er_commit_move: EventReader<EventCommitMove<T>>,
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_move_committed: EventWriter<EventMoveCommitted>,
+    mut ew_moves_revealed: EventWriter<EventMovesRevealed<T>>,
+    mut ew_cheat_detected: EventWriter<EventCheatDetected>,
+    mut ew_error: EventWriter<EventError>,
+    mut moves: ResMut<SimultaneousMove<T>>,
+    mut open_rounds: ResMut<OpenRounds>,
+    peer_codecs: Res<PeerCodecs>,
+    veilid_app: Res<VeilidApp>,
+    settings: Res<VeilidSettings>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+    let kind = type_name::<T>();
+    let mut touched = Vec::new();
+
+    for e in er_commit_move.read() {
+        let round = moves.rounds.entry((e.dht_key, e.round)).or_default();
+        if round.local.is_some() {
+            ew_error.send(EventError(anyhow::anyhow!(
+                "already committed to a move in round {}",
+                e.round
+            )));
+            continue;
+        }
+
+        let codec = peer_codecs.choose(e.dht_key, settings.codec);
+        let data = match codec.encode(&e.value) {
+            Ok(data) => data,
+            Err(err) => {
+                ew_error.send(EventError(err));
+                continue;
+            }
+        };
+        let salt: [u8; 32] = rand::random();
+        ew_send_protocol.send(EventSendProtocol {
+            message: ProtocolMessage::CommitReveal(CommitRevealMessage::Commit {
+                kind: kind.to_string(),
+                round: e.round,
+                commitment: commitment(app.our_dht_key, e.round, &salt, &data),
+            }),
+            dht_key: e.dht_key,
+        });
+        round.local = Some(LocalMove {
+            value: e.value.clone(),
+            codec,
+            salt,
+            data,
+            revealed: false,
+        });
+        touched.push((e.dht_key, e.round));
+    }
+
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::CommitReveal(message) = &e.message else {
+            continue;
+        };
+
+        match message {
+            CommitRevealMessage::Commit {
+                kind: k,
+                round: number,
+                commitment,
+            } if k == kind => {
+                let round = moves.rounds.entry((e.dht_key, *number)).or_default();
+                match round.commitment {
+                    Some(existing) if existing != *commitment => {
+                        ew_cheat_detected.send(EventCheatDetected {
+                            dht_key: e.dht_key,
+                            round: *number,
+                            reason: "changed its commitment".to_string(),
+                        });
+                    }
+                    Some(_) => {}
+                    None => {
+                        round.commitment = Some(*commitment);
+                        ew_move_committed.send(EventMoveCommitted {
+                            round: *number,
+                            dht_key: e.dht_key,
+                        });
+                    }
+                }
+                touched.push((e.dht_key, *number));
+            }
+            CommitRevealMessage::Reveal {
+                kind: k,
+                round: number,
+                codec,
+                salt,
+                data,
+            } if k == kind => {
+                // Kept until the commitment arrives, since messages may be reordered
+                let round = moves.rounds.entry((e.dht_key, *number)).or_default();
+                round.reveal = Some(RemoteReveal {
+                    codec: *codec,
+                    salt: *salt,
+                    data: data.clone(),
+                });
+                touched.push((e.dht_key, *number));
+            }
+            _ => {}
+        }
+    }
+
+    for (dht_key, number) in touched {
+        let Some(round) = moves.rounds.get_mut(&(dht_key, number)) else {
+            continue;
+        };
+
+        if let (Some(local), Some(_)) = (round.local.as_mut(), round.commitment) {
+            if !local.revealed {
+                local.revealed = true;
+                ew_send_protocol.send(EventSendProtocol {
+                    message: reveal_message(kind, number, local),
+                    dht_key,
+                });
+            }
+        }
+
+        if let Err(reason) = round.settle(dht_key, number) {
+            ew_cheat_detected.send(EventCheatDetected {
+                dht_key,
+                round: number,
+                reason: reason.to_string(),
+            });
+            moves.rounds.remove(&(dht_key, number));
+            continue;
+        }
+
+        let finished = round
+            .local
+            .as_ref()
+            .is_some_and(|local| local.revealed && round.remote.is_some());
+        if finished {
+            let round = moves.rounds.remove(&(dht_key, number)).unwrap();
+            ew_moves_revealed.send(EventMovesRevealed {
+                round: number,
+                local: round.local.unwrap().value,
+                remote: round.remote.unwrap(),
+                dht_key,
+            });
+        }
+    }
+    open_rounds.0.insert(kind, moves.rounds.len());
+}
+
+fn clear_rounds_on_rematch<T: Send + Sync + 'static>(
+    mut er_rematch_started: EventReader<EventRematchStarted>,
+    mut simultaneous: ResMut<SimultaneousMove<T>>,
+    mut open_rounds: ResMut<OpenRounds>,
+) {
+    if er_rematch_started.read().count() > 0 {
+        simultaneous.rounds.clear();
+        open_rounds.0.insert(type_name::<T>(), 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn player(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn reveal(value: u32) -> RemoteReveal {
+        RemoteReveal {
+            codec: CodecKind::default(),
+            salt: rand::random(),
+            data: CodecKind::default().encode(&value).unwrap(),
+        }
+    }
+
+    #[test]
+    fn keeps_early_reveal_until_commitment() {
+        let mut round = Round::<u32>::default();
+        let reveal = reveal(7);
+        let expected = commitment(player(1), 3, &reveal.salt, &reveal.data);
+
+        round.reveal = Some(reveal);
+        round.settle(player(1), 3).unwrap();
+        assert!(round.remote.is_none());
+        assert!(round.reveal.is_some());
+
+        round.commitment = Some(expected);
+        round.settle(player(1), 3).unwrap();
+        assert_eq!(round.remote, Some(7));
+    }
+
+    #[test]
+    fn detects_changed_move() {
+        let mut round = Round::<u32>::default();
+        let committed = reveal(7);
+        let mut changed = reveal(8);
+        changed.salt = committed.salt;
+
+        round.commitment = Some(commitment(player(1), 3, &committed.salt, &committed.data));
+        round.reveal = Some(changed);
+        assert!(round.settle(player(1), 3).is_err());
+        assert!(round.remote.is_none());
+    }
+
+    #[test]
+    fn detects_replayed_commitment() {
+        let reveal = reveal(7);
+        let mut round = Round::<u32> {
+            commitment: Some(commitment(player(1), 3, &reveal.salt, &reveal.data)),
+            reveal: Some(reveal),
+            ..Default::default()
+        };
+        assert!(round.settle(player(2), 3).is_err());
+    }
+
+    #[test]
+    fn detects_commitment_from_another_round() {
+        let reveal = reveal(7);
+        let mut round = Round::<u32> {
+            commitment: Some(commitment(player(1), 2, &reveal.salt, &reveal.data)),
+            reveal: Some(reveal),
+            ..Default::default()
+        };
+        assert!(round.settle(player(1), 3).is_err());
+    }
+
+    #[test]
+    fn detects_undecodable_move() {
+        let mut round = Round::<u32>::default();
+        let mut reveal = reveal(7);
+        reveal.data = b"not a number".to_vec();
+
+        round.commitment = Some(commitment(player(1), 3, &reveal.salt, &reveal.data));
+        round.reveal = Some(reveal);
+        assert!(round.settle(player(1), 3).is_err());
+    }
+}
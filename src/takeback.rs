@@ -0,0 +1,572 @@
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Error};
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::lockstep::LockstepLog;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::resync::{EventRequestResync, Snapshot, SnapshotRegistry};
+use crate::transcript::{Transcript, TranscriptHash};
+use crate::turn::TurnState;
+use crate::{EventError, VeilidSettings};
+
+/// Undoes a single move of `T` made by `player`.
+pub trait Reversible<T>: Send + Sync + 'static {
+    fn reverse(&self, world: &mut World, player: CryptoTyped<CryptoKey>, message: &T);
+}
+
+impl<T, F> Reversible<T> for F
+where
+    F: Fn(&mut World, CryptoTyped<CryptoKey>, &T) + Send + Sync + 'static,
+{
+    fn reverse(&self, world: &mut World, player: CryptoTyped<CryptoKey>, message: &T) {
+        self(world, player, message)
+    }
+}
+
+enum Rollback<T> {
+    Reverse(Box<dyn Reversible<T>>),
+    // Taken at the start of each turn, with the number of moves made before it.
+    Snapshots {
+        limit: usize,
+        snapshots: VecDeque<(u32, usize, Snapshot)>,
+    },
+}
+
+/// Turns on takebacks of moves of `T`. Both peers must insert it with the same kind of
+/// rollback.
+#[derive(Resource)]
+pub struct Takeback<T> {
+    rollback: Rollback<T>,
+}
+
+impl<T> Takeback<T> {
+    /// Rolls back by calling `reversible` for each taken back move, last move first.
+    pub fn reversible(reversible: impl Reversible<T>) -> Self {
+        Self {
+            rollback: Rollback::Reverse(Box::new(reversible)),
+        }
+    }
+
+    /// Rolls back by restoring one of the [`SnapshotRegistry`] snapshots taken at the start
+    /// of the last `limit` turns. Needs an active [`TurnState`] and takes back whole turns.
+    pub fn snapshots(limit: usize) -> Self {
+        Self {
+            rollback: Rollback::Snapshots {
+                limit,
+                snapshots: VecDeque::new(),
+            },
+        }
+    }
+}
+
+struct Request {
+    id: Uuid,
+    dht_key: CryptoTyped<CryptoKey>,
+    count: u32,
+    head: TranscriptHash,
+}
+
+struct Pending {
+    request: Request,
+    reply: bool,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct Takebacks {
+    to_send: Vec<Request>,
+    sent: Option<Request>,
+    received: Option<Request>,
+    to_apply: Vec<Pending>,
+}
+
+impl Takebacks {
+    pub(crate) fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum TakebackMessage {
+    Request {
+        id: Uuid,
+        count: u32,
+        head: TranscriptHash,
+    },
+    Accept {
+        id: Uuid,
+    },
+    Decline {
+        id: Uuid,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Asks `dht_key` to take back the last `count` moves, by either player.
+#[derive(Event)]
+pub struct EventRequestTakeback {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub count: u32,
+}
+
+/// Accepts the takeback requested by `dht_key` and rolls back. Ignored without an open
+/// request.
+#[derive(Event)]
+pub struct EventAcceptTakeback {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event)]
+pub struct EventDeclineTakeback {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventTakebackRequested {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub count: u32,
+}
+
+/// `dht_key` declined the takeback, or it couldn't be rolled back on their side.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventTakebackDeclined {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// `count` moves were taken back and it's now `turn`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventTakenBack {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub count: u32,
+    pub turn: u32,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn on_takeback_commands(
+    mut er_request_takeback: EventReader<EventRequestTakeback>,
+    mut er_accept_takeback: EventReader<EventAcceptTakeback>,
+    mut er_decline_takeback: EventReader<EventDeclineTakeback>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut takebacks: ResMut<Takebacks>,
+    transcript: Res<Transcript>,
+) {
+    // Sent once process_takebacks made sure the moves can be rolled back here too
+    for e in er_request_takeback.read() {
+        if e.count == 0 || e.count as usize > transcript.entries().len() {
+            continue;
+        }
+        takebacks.to_send.push(Request {
+            id: Uuid::new_v4(),
+            dht_key: e.dht_key,
+            count: e.count,
+            head: transcript.head(),
+        });
+    }
+
+    for e in er_accept_takeback.read() {
+        if takebacks
+            .received
+            .as_ref()
+            .is_some_and(|r| r.dht_key == e.dht_key)
+        {
+            let request = takebacks.received.take().unwrap();
+            takebacks.to_apply.push(Pending {
+                request,
+                reply: true,
+            });
+        }
+    }
+
+    for e in er_decline_takeback.read() {
+        if takebacks
+            .received
+            .as_ref()
+            .is_some_and(|r| r.dht_key == e.dht_key)
+        {
+            let request = takebacks.received.take().unwrap();
+            ew_send_protocol.send(EventSendProtocol {
+                message: ProtocolMessage::Takeback(TakebackMessage::Decline { id: request.id }),
+                dht_key: e.dht_key,
+            });
+        }
+    }
+}
+
+pub(crate) fn on_ev_receive_takeback(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_takeback_requested: EventWriter<EventTakebackRequested>,
+    mut ew_takeback_declined: EventWriter<EventTakebackDeclined>,
+    mut takebacks: ResMut<Takebacks>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Takeback(message) = &e.message else {
+            continue;
+        };
+
+        match *message {
+            TakebackMessage::Request { id, count, head } => {
+                takebacks.received = Some(Request {
+                    id,
+                    dht_key: e.dht_key,
+                    count,
+                    head,
+                });
+                ew_takeback_requested.send(EventTakebackRequested {
+                    dht_key: e.dht_key,
+                    count,
+                });
+            }
+            TakebackMessage::Accept { id } => {
+                if takebacks.sent.as_ref().is_some_and(|r| r.id == id) {
+                    let request = takebacks.sent.take().unwrap();
+                    takebacks.to_apply.push(Pending {
+                        request,
+                        reply: false,
+                    });
+                }
+            }
+            TakebackMessage::Decline { id } => {
+                if takebacks.sent.as_ref().is_some_and(|r| r.id == id) {
+                    takebacks.sent = None;
+                    ew_takeback_declined.send(EventTakebackDeclined { dht_key: e.dht_key });
+                }
+            }
+        }
+    }
+}
+
+fn rollback_len(transcript: &Transcript, head: TranscriptHash, count: u32) -> Option<usize> {
+    let len = if head == TranscriptHash::default() {
+        0
+    } else {
+        transcript.entries().iter().position(|e| e.hash == head)? + 1
+    };
+    len.checked_sub(count as usize)
+}
+
+fn reverse_moves<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    world: &mut World,
+    reversible: &dyn Reversible<T>,
+    len: usize,
+) -> Result<(), Error> {
+    let limit = world.resource::<VeilidSettings>().fragmentation.memory_cap;
+    let transcript = world.resource::<Transcript>();
+    let taken_back = &transcript.entries()[len..];
+    let moves = taken_back
+        .iter()
+        .map(|entry| Ok((entry.player, entry.open_within::<T>(limit)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // The turn the first taken back move was made in is current again
+    let rewind = taken_back.first().and_then(|entry| entry.turn).map(|turn| {
+        let started_at = transcript.entries()[..len]
+            .iter()
+            .rev()
+            .find(|e| e.turn.is_some_and(|t| t < turn))
+            .map(|e| e.sent_at);
+        (turn, started_at)
+    });
+
+    for (player, message) in moves.iter().rev() {
+        reversible.reverse(world, *player, message);
+    }
+    world.resource_mut::<Transcript>().truncate(len);
+    if let Some((turn, started_at)) = rewind {
+        world
+            .resource_mut::<TurnState>()
+            .rewind_to(turn, started_at);
+    }
+    world.resource_mut::<LockstepLog>().clear();
+    Ok(())
+}
+
+fn snapshot_position(
+    snapshots: &VecDeque<(u32, usize, Snapshot)>,
+    transcript: &Transcript,
+    len: usize,
+) -> Result<usize, Error> {
+    let turn = transcript
+        .entries()
+        .get(len)
+        .and_then(|entry| entry.turn)
+        .ok_or_else(|| anyhow!("move {} was not made in a turn", len + 1))?;
+    snapshots
+        .iter()
+        .position(|(t, moves, _)| *t == turn && *moves == len)
+        .ok_or_else(|| anyhow!("no snapshot from the start of turn {turn} after move {len}"))
+}
+
+// Fails for anything that would make roll_back fail, so a request we send can be
+// carried out once the peer accepted it.
+fn check_rollback<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    world: &World,
+    takeback: &Takeback<T>,
+    request: &Request,
+) -> Result<usize, Error> {
+    let transcript = world.resource::<Transcript>();
+    let len = rollback_len(transcript, request.head, request.count)
+        .ok_or_else(|| anyhow!("can't take back {} moves", request.count))?;
+
+    match &takeback.rollback {
+        Rollback::Reverse(_) => {
+            let limit = world.resource::<VeilidSettings>().fragmentation.memory_cap;
+            for entry in &transcript.entries()[len..] {
+                entry.open_within::<T>(limit)?;
+            }
+        }
+        Rollback::Snapshots { snapshots, .. } => {
+            snapshot_position(snapshots, transcript, len)?;
+        }
+    }
+    Ok(len)
+}
+
+fn roll_back<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    world: &mut World,
+    takeback: &mut Takeback<T>,
+    request: &Request,
+) -> Result<(), Error> {
+    let len = check_rollback(world, takeback, request)?;
+
+    match &mut takeback.rollback {
+        Rollback::Reverse(reversible) => reverse_moves(world, reversible.as_ref(), len),
+        Rollback::Snapshots { snapshots, .. } => {
+            let position = snapshot_position(snapshots, world.resource::<Transcript>(), len)?;
+            let (_, _, snapshot) = snapshots[position].clone();
+            world.resource_scope(|world, registry: Mut<SnapshotRegistry>| {
+                registry.restore(world, snapshot)
+            })?;
+            snapshots.truncate(position + 1);
+            Ok(())
+        }
+    }
+}
+
+fn send_request(world: &mut World, request: Request) {
+    world.send_event(EventSendProtocol {
+        message: ProtocolMessage::Takeback(TakebackMessage::Request {
+            id: request.id,
+            count: request.count,
+            head: request.head,
+        }),
+        dht_key: request.dht_key,
+    });
+    world.resource_mut::<Takebacks>().sent = Some(request);
+}
+
+pub(crate) fn process_takebacks<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    world: &mut World,
+) {
+    let (to_send, to_apply) = {
+        let mut takebacks = world.resource_mut::<Takebacks>();
+        (
+            std::mem::take(&mut takebacks.to_send),
+            std::mem::take(&mut takebacks.to_apply),
+        )
+    };
+    if to_send.is_empty() && to_apply.is_empty() {
+        return;
+    }
+    if !world.contains_resource::<Takeback<T>>() {
+        for request in to_send {
+            world.send_event(EventError(anyhow!(
+                "can't take back {} moves: takebacks are not enabled",
+                request.count
+            )));
+        }
+        // Nothing can be rolled back, so accepted requests are declined after all
+        for Pending { request, reply } in to_apply {
+            if reply {
+                world.send_event(EventSendProtocol {
+                    message: ProtocolMessage::Takeback(TakebackMessage::Decline { id: request.id }),
+                    dht_key: request.dht_key,
+                });
+            }
+            world.send_event(EventError(anyhow!(
+                "can't take back {} moves: takebacks are not enabled",
+                request.count
+            )));
+        }
+        return;
+    }
+
+    world.resource_scope(|world, mut takeback: Mut<Takeback<T>>| {
+        for request in to_send {
+            match check_rollback(world, &takeback, &request) {
+                Ok(_) => send_request(world, request),
+                Err(err) => {
+                    world.send_event(EventError(err));
+                }
+            }
+        }
+
+        for Pending { request, reply } in to_apply {
+            let result = roll_back(world, &mut takeback, &request);
+
+            if reply {
+                let message = match result {
+                    Ok(()) => TakebackMessage::Accept { id: request.id },
+                    Err(_) => TakebackMessage::Decline { id: request.id },
+                };
+                world.send_event(EventSendProtocol {
+                    message: ProtocolMessage::Takeback(message),
+                    dht_key: request.dht_key,
+                });
+            } else if result.is_err() {
+                // The peer already rolled back, so its state is taken over instead
+                world.send_event(EventRequestResync {
+                    dht_key: request.dht_key,
+                });
+            }
+
+            match result {
+                Ok(()) => {
+                    let turn = world.resource::<TurnState>().turn();
+                    world.send_event(EventTakenBack {
+                        dht_key: request.dht_key,
+                        count: request.count,
+                        turn,
+                    });
+                }
+                Err(err) => {
+                    world.send_event(EventError(err));
+                }
+            }
+        }
+    });
+}
+
+pub(crate) fn capture_takeback_snapshots<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    world: &mut World,
+) {
+    let Some(takeback) = world.get_resource::<Takeback<T>>() else {
+        return;
+    };
+    let Rollback::Snapshots { snapshots, .. } = &takeback.rollback else {
+        return;
+    };
+    let turn = world.resource::<TurnState>().turn();
+    if turn == 0 || snapshots.back().is_some_and(|(last, _, _)| *last == turn) {
+        return;
+    }
+    let moves = world.resource::<Transcript>().entries().len();
+
+    let codec = world.resource::<VeilidSettings>().codec;
+    let snapshot = world
+        .resource_scope(|world, registry: Mut<SnapshotRegistry>| registry.capture(world, codec));
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            world.send_event(EventError(err));
+            return;
+        }
+    };
+
+    let mut takeback = world.resource_mut::<Takeback<T>>();
+    if let Rollback::Snapshots { limit, snapshots } = &mut takeback.rollback {
+        // Turns were taken back or a new game started, later snapshots are stale
+        snapshots.retain(|(t, _, _)| *t < turn);
+        snapshots.push_back((turn, moves, snapshot));
+        while snapshots.len() > *limit {
+            snapshots.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::codec::CodecKind;
+    use crate::envelope::{Envelope, PayloadKind};
+    use crate::transcript::TranscriptEntry;
+
+    fn player(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn world(moves: u32) -> World {
+        let mut world = World::new();
+        world.init_resource::<VeilidSettings>();
+        world.init_resource::<TurnState>();
+        world.init_resource::<LockstepLog>();
+        let mut transcript = Transcript::default();
+        for turn in 1..=moves {
+            let mut envelope = Envelope::new(
+                PayloadKind::User,
+                &turn,
+                CodecKind::Json,
+                &VeilidSettings::default(),
+            )
+            .unwrap();
+            envelope.turn = Some(turn);
+            let entry = TranscriptEntry::new(player(turn as u8 % 2), &envelope, transcript.head());
+            transcript.push(entry);
+        }
+        world.insert_resource(transcript);
+        world
+    }
+
+    fn request(world: &World, count: u32) -> Request {
+        Request {
+            id: Uuid::new_v4(),
+            dht_key: player(1),
+            count,
+            head: world.resource::<Transcript>().head(),
+        }
+    }
+
+    #[test]
+    fn checks_reversible_moves_before_asking() {
+        let world = world(3);
+        let takeback = Takeback::<u32>::reversible(|_: &mut World, _, _: &u32| {});
+
+        assert_eq!(
+            check_rollback(&world, &takeback, &request(&world, 2)).ok(),
+            Some(1)
+        );
+        assert!(check_rollback(&world, &takeback, &request(&world, 4)).is_err());
+
+        let undecodable = Takeback::<String>::reversible(|_: &mut World, _, _: &String| {});
+        assert!(check_rollback(&world, &undecodable, &request(&world, 1)).is_err());
+    }
+
+    #[test]
+    fn needs_a_snapshot_from_the_start_of_the_turn() {
+        let mut world = world(3);
+        let snapshot = SnapshotRegistry::default()
+            .capture(&mut world, CodecKind::Json)
+            .unwrap();
+        let mut takeback = Takeback::<u32>::snapshots(4);
+        if let Rollback::Snapshots { snapshots, .. } = &mut takeback.rollback {
+            snapshots.push_back((3, 2, snapshot));
+        }
+
+        assert_eq!(
+            check_rollback(&world, &takeback, &request(&world, 1)).ok(),
+            Some(2)
+        );
+        assert!(check_rollback(&world, &takeback, &request(&world, 2)).is_err());
+    }
+}
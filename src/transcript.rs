@@ -0,0 +1,464 @@
+use anyhow::Error;
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid::VeilidDuplex;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped, PublicKey, Signature};
+
+use crate::codec::CodecKind;
+use crate::compression::CompressionAlgorithm;
+use crate::envelope::{base64_payload, Envelope};
+use crate::fragment::FragmentationSettings;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::signing::{record_owner, sign, verify, verify_peer};
+use crate::{EventError, TasksRutime, VeilidApp};
+
+pub type TranscriptHash = [u8; 32];
+
+/// One move of the transcript, chained to the move before it by `prev`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    pub player: CryptoTyped<CryptoKey>,
+    pub turn: Option<u32>,
+    pub sent_at: u64,
+    pub codec: CodecKind,
+    pub compression: Option<CompressionAlgorithm>,
+    /// The move as it was sent, encoded and possibly compressed.
+    #[serde(with = "base64_payload")]
+    pub payload: Vec<u8>,
+    pub prev: TranscriptHash,
+    pub hash: TranscriptHash,
+}
+
+impl TranscriptEntry {
+    pub(crate) fn new(
+        player: CryptoTyped<CryptoKey>,
+        envelope: &Envelope,
+        prev: TranscriptHash,
+    ) -> Self {
+        let mut entry = Self {
+            player,
+            turn: envelope.turn,
+            sent_at: envelope.sent_at,
+            codec: envelope.codec,
+            compression: envelope.compression,
+            payload: envelope.payload.clone(),
+            prev,
+            hash: [0; 32],
+        };
+        entry.hash = entry.compute_hash();
+        entry
+    }
+
+    fn compute_hash(&self) -> TranscriptHash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.prev);
+        hasher.update(self.player.to_string().as_bytes());
+        hasher.update(&self.turn.unwrap_or(0).to_le_bytes());
+        hasher.update(&self.sent_at.to_le_bytes());
+        hasher.update(format!("{:?}/{:?}", self.codec, self.compression).as_bytes());
+        hasher.update(&self.payload);
+        *hasher.finalize().as_bytes()
+    }
+
+    /// Decodes the move. Compressed moves may expand to at most the default
+    /// [`FragmentationSettings::memory_cap`].
+    pub fn open<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.open_within(FragmentationSettings::default().memory_cap)
+    }
+
+    pub(crate) fn open_within<T: DeserializeOwned>(&self, limit: usize) -> Result<T, Error> {
+        match self.compression {
+            Some(algorithm) => self
+                .codec
+                .decode(&algorithm.decompress(&self.payload, limit)?),
+            None => self.codec.decode(&self.payload),
+        }
+    }
+}
+
+/// A player's signature over the transcript head.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptSignature {
+    pub player: CryptoTyped<CryptoKey>,
+    /// Owner key of the player's DHT record.
+    pub public_key: PublicKey,
+    pub head: TranscriptHash,
+    pub signature: Signature,
+}
+
+fn signed_data(head: &TranscriptHash) -> Vec<u8> {
+    [b"bevy_veilid transcript".as_slice(), head].concat()
+}
+
+/// Every move sent and received, as a hash chain.
+#[derive(Resource, Asset, TypePath, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Transcript {
+    entries: Vec<TranscriptEntry>,
+    signatures: Vec<TranscriptSignature>,
+}
+
+impl Transcript {
+    /// Hash of the last move, or zeros for an empty transcript.
+    pub fn head(&self) -> TranscriptHash {
+        self.entries.last().map(|e| e.hash).unwrap_or_default()
+    }
+
+    pub fn entries(&self) -> &[TranscriptEntry] {
+        &self.entries
+    }
+
+    pub fn signatures(&self) -> &[TranscriptSignature] {
+        &self.signatures
+    }
+
+    /// Two different players signed the current head.
+    pub fn is_signed_by_both(&self) -> bool {
+        let head = self.head();
+        let mut signers = self
+            .signatures
+            .iter()
+            .filter(|s| s.head == head)
+            .map(|s| s.player);
+        signers
+            .next()
+            .is_some_and(|first| signers.any(|other| other != first))
+    }
+
+    /// Recomputes every link of the chain.
+    pub fn verify_chain(&self) -> bool {
+        let mut prev = TranscriptHash::default();
+        for entry in &self.entries {
+            if entry.prev != prev || entry.compute_hash() != entry.hash {
+                return false;
+            }
+            prev = entry.hash;
+        }
+        true
+    }
+
+    /// Checks the chain and every signature against the signers' DHT record owners.
+    pub async fn verify(&self, app: &VeilidDuplex) -> Result<(), Error> {
+        if !self.verify_chain() {
+            return Err(anyhow::anyhow!("transcript chain is broken"));
+        }
+        for s in &self.signatures {
+            if record_owner(app, s.player).await? != s.public_key {
+                return Err(anyhow::anyhow!(
+                    "{} signed with a key that doesn't own its record",
+                    s.player
+                ));
+            }
+            verify(app, &s.public_key, &signed_data(&s.head), &s.signature)?;
+        }
+        Ok(())
+    }
+
+    pub fn export(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn import(json: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.signatures.clear();
+    }
+
+    pub(crate) fn push(&mut self, entry: TranscriptEntry) {
+        self.entries.push(entry);
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.entries.truncate(len);
+    }
+
+    pub(crate) fn remove_sent(&mut self, player: CryptoTyped<CryptoKey>, sent_at: u64) {
+        if self
+            .entries
+            .last()
+            .is_some_and(|e| e.player == player && e.sent_at == sent_at)
+        {
+            self.entries.pop();
+        }
+    }
+
+    fn add_signature(&mut self, signature: TranscriptSignature) {
+        self.signatures
+            .retain(|s| !(s.player == signature.player && s.head == signature.head));
+        self.signatures.push(signature);
+    }
+
+    fn is_signed_by(&self, player: CryptoTyped<CryptoKey>, head: TranscriptHash) -> bool {
+        self.signatures
+            .iter()
+            .any(|s| s.player == player && s.head == head)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum TranscriptMessage {
+    Signature {
+        head: TranscriptHash,
+        signature: Signature,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// Signs the transcript head and sends the signature to `dht_key`, who countersigns if
+/// its head is the same.
+#[derive(Event)]
+pub struct EventSignTranscript {
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+/// `dht_key` signed `head`, either the local player or a verified peer.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventTranscriptSigned {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub head: TranscriptHash,
+    pub signed_by_both: bool,
+}
+
+/// A move or signature from `dht_key` refers to a history other than the local one.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventTranscriptMismatch {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub local: TranscriptHash,
+    pub remote: TranscriptHash,
+}
+
+#[derive(Event)]
+pub(crate) struct EventPeerSignatureVerified {
+    signature: TranscriptSignature,
+}
+
+// -------
+// Systems
+// -------
+
+fn sign_head(app: &VeilidDuplex, head: TranscriptHash) -> Result<TranscriptSignature, Error> {
+    Ok(TranscriptSignature {
+        player: app.our_dht_key,
+        public_key: app.dht_keypair.key,
+        head,
+        signature: sign(app, &signed_data(&head))?,
+    })
+}
+
+fn send_signature(
+    ew_send_protocol: &mut EventWriter<EventSendProtocol>,
+    signature: &TranscriptSignature,
+    dht_key: CryptoTyped<CryptoKey>,
+) {
+    ew_send_protocol.send(EventSendProtocol {
+        message: ProtocolMessage::Transcript(TranscriptMessage::Signature {
+            head: signature.head,
+            signature: signature.signature,
+        }),
+        dht_key,
+    });
+}
+
+pub(crate) fn on_ev_sign_transcript(
+    mut er_sign_transcript: EventReader<EventSignTranscript>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_transcript_signed: EventWriter<EventTranscriptSigned>,
+    mut ew_error: EventWriter<EventError>,
+    mut transcript: ResMut<Transcript>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+
+    for e in er_sign_transcript.read() {
+        let signature = match sign_head(app, transcript.head()) {
+            Ok(signature) => signature,
+            Err(err) => {
+                ew_error.send(EventError(err));
+                continue;
+            }
+        };
+
+        send_signature(&mut ew_send_protocol, &signature, e.dht_key);
+        transcript.add_signature(signature);
+        ew_transcript_signed.send(EventTranscriptSigned {
+            dht_key: app.our_dht_key,
+            head: transcript.head(),
+            signed_by_both: transcript.is_signed_by_both(),
+        });
+    }
+}
+
+pub(crate) fn on_ev_receive_transcript(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    veilid_app: Res<VeilidApp>,
+    runtime: ResMut<TasksRutime>,
+) {
+    let Some(app) = veilid_app.app.clone() else {
+        return;
+    };
+
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Transcript(TranscriptMessage::Signature { head, signature }) =
+            e.message
+        else {
+            continue;
+        };
+
+        let app = app.clone();
+        let dht_key = e.dht_key;
+        runtime.spawn_background_task(move |mut ctx| async move {
+            let result = verify_peer(&app, dht_key, &signed_data(&head), &signature).await;
+
+            ctx.run_on_main_thread(move |ctx| match result {
+                Ok(public_key) => {
+                    ctx.world.send_event(EventPeerSignatureVerified {
+                        signature: TranscriptSignature {
+                            player: dht_key,
+                            public_key,
+                            head,
+                            signature,
+                        },
+                    });
+                }
+                Err(err) => {
+                    ctx.world.send_event(EventError(err));
+                }
+            })
+            .await;
+        });
+    }
+}
+
+pub(crate) fn on_ev_peer_signature_verified(
+    mut er_peer_signature_verified: EventReader<EventPeerSignatureVerified>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_transcript_signed: EventWriter<EventTranscriptSigned>,
+    mut ew_transcript_mismatch: EventWriter<EventTranscriptMismatch>,
+    mut ew_error: EventWriter<EventError>,
+    mut transcript: ResMut<Transcript>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+
+    for e in er_peer_signature_verified.read() {
+        let peer = e.signature.player;
+        let head = transcript.head();
+        if e.signature.head != head {
+            ew_transcript_mismatch.send(EventTranscriptMismatch {
+                dht_key: peer,
+                local: head,
+                remote: e.signature.head,
+            });
+            continue;
+        }
+
+        transcript.add_signature(e.signature.clone());
+        ew_transcript_signed.send(EventTranscriptSigned {
+            dht_key: peer,
+            head,
+            signed_by_both: transcript.is_signed_by_both(),
+        });
+
+        if transcript.is_signed_by(app.our_dht_key, head) {
+            continue;
+        }
+        match sign_head(app, head) {
+            Ok(signature) => {
+                send_signature(&mut ew_send_protocol, &signature, peer);
+                transcript.add_signature(signature);
+                ew_transcript_signed.send(EventTranscriptSigned {
+                    dht_key: app.our_dht_key,
+                    head,
+                    signed_by_both: transcript.is_signed_by_both(),
+                });
+            }
+            Err(err) => {
+                ew_error.send(EventError(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::envelope::PayloadKind;
+    use crate::VeilidSettings;
+
+    fn player(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    fn transcript(moves: u32) -> Transcript {
+        let mut transcript = Transcript::default();
+        for turn in 0..moves {
+            let mut envelope = Envelope::new(
+                PayloadKind::User,
+                &turn,
+                CodecKind::Json,
+                &VeilidSettings::default(),
+            )
+            .unwrap();
+            envelope.turn = Some(turn + 1);
+            let entry = TranscriptEntry::new(player(turn as u8 % 2), &envelope, transcript.head());
+            transcript.push(entry);
+        }
+        transcript
+    }
+
+    #[test]
+    fn chain_verifies() {
+        let transcript = transcript(4);
+        assert!(transcript.verify_chain());
+        assert_eq!(transcript.entries()[3].open::<u32>().unwrap(), 3);
+    }
+
+    #[test]
+    fn detects_changed_move() {
+        let mut transcript = transcript(4);
+        transcript.entries[1].payload = transcript.entries[2].payload.clone();
+        assert!(!transcript.verify_chain());
+    }
+
+    #[test]
+    fn detects_changed_player() {
+        let mut transcript = transcript(4);
+        transcript.entries[2].player = player(9);
+        assert!(!transcript.verify_chain());
+    }
+
+    #[test]
+    fn detects_changed_codec() {
+        let mut transcript = transcript(4);
+        transcript.entries[0].compression = Some(CompressionAlgorithm::Deflate);
+        assert!(!transcript.verify_chain());
+    }
+
+    #[test]
+    fn detects_removed_move() {
+        let mut transcript = transcript(4);
+        transcript.entries.remove(1);
+        assert!(!transcript.verify_chain());
+    }
+
+    #[test]
+    fn detects_rehashed_move() {
+        let mut transcript = transcript(4);
+        let entry = &mut transcript.entries[1];
+        entry.sent_at += 1;
+        entry.hash = entry.compute_hash();
+        assert!(!transcript.verify_chain());
+    }
+}
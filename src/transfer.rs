@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::envelope::base64_payload;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::VeilidSettings;
+
+/// Settings for [`EventSendTransfer`] transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferSettings {
+    /// Bytes sent per chunk.
+    pub chunk_size: usize,
+    /// A transfer without progress for this long is reported as failed and can be resumed.
+    pub timeout: Duration,
+    /// Incoming transfers larger than this many bytes are refused.
+    pub max_size: u64,
+    /// Incoming transfers a single peer may have open at once. Further offers are refused.
+    pub max_incoming_per_peer: usize,
+}
+
+impl Default for TransferSettings {
+    fn default() -> Self {
+        Self {
+            chunk_size: 8 * 1024,
+            timeout: Duration::from_secs(30),
+            max_size: 16 * 1024 * 1024,
+            max_incoming_per_peer: 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum TransferMessage {
+    Offer {
+        id: Uuid,
+        name: String,
+        total: u64,
+        hash: [u8; 32],
+    },
+    Request {
+        id: Uuid,
+        offset: u64,
+    },
+    Chunk {
+        id: Uuid,
+        offset: u64,
+        #[serde(with = "base64_payload")]
+        data: Vec<u8>,
+    },
+    Complete {
+        id: Uuid,
+    },
+    Cancel {
+        id: Uuid,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Outgoing,
+    Incoming,
+}
+
+// ------
+// Events
+// ------
+
+/// Sends a byte blob to a peer outside of the [`EventSendMessage`](crate::EventSendMessage)
+/// path.
+#[derive(Event)]
+pub struct EventSendTransfer {
+    pub id: Uuid,
+    pub name: String,
+    pub data: Vec<u8>,
+    pub dht_key: CryptoTyped<CryptoKey>,
+}
+
+impl EventSendTransfer {
+    pub fn new(
+        name: impl Into<String>,
+        data: Vec<u8>,
+        dht_key: CryptoTyped<CryptoKey>,
+    ) -> EventSendTransfer {
+        EventSendTransfer {
+            id: Uuid::new_v4(),
+            name: name.into(),
+            data,
+            dht_key,
+        }
+    }
+
+    /// Reads the file at `path` into a new transfer named after the file.
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        dht_key: CryptoTyped<CryptoKey>,
+    ) -> std::io::Result<EventSendTransfer> {
+        let path = path.as_ref();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(EventSendTransfer::new(name, std::fs::read(path)?, dht_key))
+    }
+}
+
+/// Resumes a transfer reported by [`EventTransferFailed`] with `resumable` set.
+#[derive(Event)]
+pub struct EventResumeTransfer {
+    pub id: Uuid,
+}
+
+#[derive(Event)]
+pub struct EventCancelTransfer {
+    pub id: Uuid,
+}
+
+#[derive(Event)]
+pub struct EventTransferProgress {
+    pub id: Uuid,
+    pub direction: TransferDirection,
+    pub bytes: u64,
+    pub total: u64,
+}
+
+/// A transfer finished. `data` holds the received bytes on the receiving side.
+#[derive(Event)]
+pub struct EventTransferComplete {
+    pub id: Uuid,
+    pub direction: TransferDirection,
+    pub name: String,
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Event)]
+pub struct EventTransferFailed {
+    pub id: Uuid,
+    pub direction: TransferDirection,
+    pub reason: String,
+    /// The transfer state is kept and [`EventResumeTransfer`] picks it up where it stopped.
+    pub resumable: bool,
+}
+
+// ---------
+// Resources
+// ---------
+
+struct OutgoingTransfer {
+    peer: CryptoTyped<CryptoKey>,
+    name: String,
+    data: Vec<u8>,
+    hash: [u8; 32],
+    last_activity: Instant,
+    stalled: bool,
+}
+
+struct IncomingTransfer {
+    peer: CryptoTyped<CryptoKey>,
+    name: String,
+    total: u64,
+    hash: [u8; 32],
+    data: Vec<u8>,
+    last_activity: Instant,
+    stalled: bool,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct Transfers {
+    outgoing: HashMap<Uuid, OutgoingTransfer>,
+    incoming: HashMap<Uuid, IncomingTransfer>,
+}
+
+impl Transfers {
+    pub(crate) fn touch(&mut self) {
+        let now = Instant::now();
+        for transfer in self.outgoing.values_mut() {
+            transfer.last_activity = now;
+        }
+        for transfer in self.incoming.values_mut() {
+            transfer.last_activity = now;
+        }
+    }
+}
+
+// -------
+// Systems
+// -------
+
+fn send(
+    ew_send_protocol: &mut EventWriter<EventSendProtocol>,
+    dht_key: CryptoTyped<CryptoKey>,
+    message: TransferMessage,
+) {
+    ew_send_protocol.send(EventSendProtocol {
+        message: ProtocolMessage::Transfer(message),
+        dht_key,
+    });
+}
+
+fn offer(id: Uuid, transfer: &OutgoingTransfer) -> TransferMessage {
+    TransferMessage::Offer {
+        id,
+        name: transfer.name.clone(),
+        total: transfer.data.len() as u64,
+        hash: transfer.hash,
+    }
+}
+
+pub(crate) fn on_ev_send_transfer(
+    mut er_send_transfer: EventReader<EventSendTransfer>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut transfers: ResMut<Transfers>,
+) {
+    for e in er_send_transfer.read() {
+        let transfer = OutgoingTransfer {
+            peer: e.dht_key,
+            name: e.name.clone(),
+            data: e.data.clone(),
+            hash: *blake3::hash(&e.data).as_bytes(),
+            last_activity: Instant::now(),
+            stalled: false,
+        };
+
+        send(&mut ew_send_protocol, e.dht_key, offer(e.id, &transfer));
+        transfers.outgoing.insert(e.id, transfer);
+    }
+}
+
+pub(crate) fn on_ev_receive_transfer(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_progress: EventWriter<EventTransferProgress>,
+    mut ew_complete: EventWriter<EventTransferComplete>,
+    mut ew_failed: EventWriter<EventTransferFailed>,
+    mut transfers: ResMut<Transfers>,
+    settings: Res<VeilidSettings>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Transfer(message) = &e.message else {
+            continue;
+        };
+        let peer = e.dht_key;
+
+        match message.clone() {
+            TransferMessage::Offer {
+                id,
+                name,
+                total,
+                hash,
+            } => {
+                if let Some(transfer) = transfers.incoming.get_mut(&id) {
+                    if transfer.peer == peer {
+                        transfer.last_activity = Instant::now();
+                        transfer.stalled = false;
+                        let offset = transfer.data.len() as u64;
+                        send(
+                            &mut ew_send_protocol,
+                            peer,
+                            TransferMessage::Request { id, offset },
+                        );
+                    }
+                    continue;
+                }
+
+                let open = transfers
+                    .incoming
+                    .values()
+                    .filter(|transfer| transfer.peer == peer)
+                    .count();
+                let refusal = if total > settings.transfer.max_size {
+                    Some(format!("transfer of {} bytes exceeds limit", total))
+                } else if open >= settings.transfer.max_incoming_per_peer {
+                    Some(format!("too many transfers in progress ({})", open))
+                } else {
+                    None
+                };
+                if let Some(reason) = refusal {
+                    send(
+                        &mut ew_send_protocol,
+                        peer,
+                        TransferMessage::Cancel { id, reason },
+                    );
+                    continue;
+                }
+
+                transfers.incoming.insert(
+                    id,
+                    IncomingTransfer {
+                        peer,
+                        name,
+                        total,
+                        hash,
+                        data: Vec::new(),
+                        last_activity: Instant::now(),
+                        stalled: false,
+                    },
+                );
+                send(
+                    &mut ew_send_protocol,
+                    peer,
+                    TransferMessage::Request { id, offset: 0 },
+                );
+            }
+            TransferMessage::Request { id, offset } => {
+                let Some(transfer) = transfers.outgoing.get_mut(&id) else {
+                    continue;
+                };
+                if transfer.peer != peer {
+                    continue;
+                }
+                transfer.last_activity = Instant::now();
+                transfer.stalled = false;
+
+                let total = transfer.data.len() as u64;
+                let offset = offset.min(total);
+                ew_progress.send(EventTransferProgress {
+                    id,
+                    direction: TransferDirection::Outgoing,
+                    bytes: offset,
+                    total,
+                });
+
+                if offset < total {
+                    let end = (offset as usize + settings.transfer.chunk_size.max(1))
+                        .min(transfer.data.len());
+                    let data = transfer.data[offset as usize..end].to_vec();
+                    send(
+                        &mut ew_send_protocol,
+                        peer,
+                        TransferMessage::Chunk { id, offset, data },
+                    );
+                }
+            }
+            TransferMessage::Chunk { id, offset, data } => {
+                let Some(transfer) = transfers.incoming.get_mut(&id) else {
+                    continue;
+                };
+                if transfer.peer != peer {
+                    continue;
+                }
+                transfer.last_activity = Instant::now();
+                transfer.stalled = false;
+
+                if offset == transfer.data.len() as u64
+                    && offset + data.len() as u64 <= transfer.total
+                {
+                    transfer.data.extend_from_slice(&data);
+                    ew_progress.send(EventTransferProgress {
+                        id,
+                        direction: TransferDirection::Incoming,
+                        bytes: transfer.data.len() as u64,
+                        total: transfer.total,
+                    });
+                }
+
+                let offset = transfer.data.len() as u64;
+                if offset < transfer.total {
+                    send(
+                        &mut ew_send_protocol,
+                        peer,
+                        TransferMessage::Request { id, offset },
+                    );
+                    continue;
+                }
+
+                let transfer = transfers.incoming.remove(&id).unwrap();
+                if *blake3::hash(&transfer.data).as_bytes() != transfer.hash {
+                    let reason = "checksum mismatch".to_string();
+                    send(
+                        &mut ew_send_protocol,
+                        peer,
+                        TransferMessage::Cancel {
+                            id,
+                            reason: reason.clone(),
+                        },
+                    );
+                    ew_failed.send(EventTransferFailed {
+                        id,
+                        direction: TransferDirection::Incoming,
+                        reason,
+                        resumable: false,
+                    });
+                    continue;
+                }
+
+                send(
+                    &mut ew_send_protocol,
+                    peer,
+                    TransferMessage::Complete { id },
+                );
+                ew_complete.send(EventTransferComplete {
+                    id,
+                    direction: TransferDirection::Incoming,
+                    name: transfer.name,
+                    dht_key: peer,
+                    data: Some(transfer.data),
+                });
+            }
+            TransferMessage::Complete { id } => {
+                if transfers.outgoing.get(&id).map(|t| t.peer) != Some(peer) {
+                    continue;
+                }
+                let transfer = transfers.outgoing.remove(&id).unwrap();
+                ew_complete.send(EventTransferComplete {
+                    id,
+                    direction: TransferDirection::Outgoing,
+                    name: transfer.name,
+                    dht_key: peer,
+                    data: None,
+                });
+            }
+            TransferMessage::Cancel { id, reason } => {
+                let direction = if transfers.outgoing.get(&id).map(|t| t.peer) == Some(peer) {
+                    transfers.outgoing.remove(&id);
+                    TransferDirection::Outgoing
+                } else if transfers.incoming.get(&id).map(|t| t.peer) == Some(peer) {
+                    transfers.incoming.remove(&id);
+                    TransferDirection::Incoming
+                } else {
+                    continue;
+                };
+
+                ew_failed.send(EventTransferFailed {
+                    id,
+                    direction,
+                    reason,
+                    resumable: false,
+                });
+            }
+        }
+    }
+}
+
+pub(crate) fn on_ev_resume_transfer(
+    mut er_resume_transfer: EventReader<EventResumeTransfer>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut transfers: ResMut<Transfers>,
+) {
+    for e in er_resume_transfer.read() {
+        if let Some(transfer) = transfers.outgoing.get_mut(&e.id) {
+            transfer.last_activity = Instant::now();
+            transfer.stalled = false;
+            send(&mut ew_send_protocol, transfer.peer, offer(e.id, transfer));
+        } else if let Some(transfer) = transfers.incoming.get_mut(&e.id) {
+            transfer.last_activity = Instant::now();
+            transfer.stalled = false;
+            let offset = transfer.data.len() as u64;
+            send(
+                &mut ew_send_protocol,
+                transfer.peer,
+                TransferMessage::Request { id: e.id, offset },
+            );
+        }
+    }
+}
+
+pub(crate) fn on_ev_cancel_transfer(
+    mut er_cancel_transfer: EventReader<EventCancelTransfer>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_failed: EventWriter<EventTransferFailed>,
+    mut transfers: ResMut<Transfers>,
+) {
+    for e in er_cancel_transfer.read() {
+        let (peer, direction) = if let Some(transfer) = transfers.outgoing.remove(&e.id) {
+            (transfer.peer, TransferDirection::Outgoing)
+        } else if let Some(transfer) = transfers.incoming.remove(&e.id) {
+            (transfer.peer, TransferDirection::Incoming)
+        } else {
+            continue;
+        };
+
+        let reason = "cancelled".to_string();
+        send(
+            &mut ew_send_protocol,
+            peer,
+            TransferMessage::Cancel {
+                id: e.id,
+                reason: reason.clone(),
+            },
+        );
+        ew_failed.send(EventTransferFailed {
+            id: e.id,
+            direction,
+            reason,
+            resumable: false,
+        });
+    }
+}
+
+pub(crate) fn detect_stalled_transfers(
+    mut ew_failed: EventWriter<EventTransferFailed>,
+    mut transfers: ResMut<Transfers>,
+    settings: Res<VeilidSettings>,
+) {
+    let timeout = settings.transfer.timeout;
+    let transfers = transfers.as_mut();
+
+    let outgoing = transfers.outgoing.iter_mut().map(|(id, t)| {
+        (
+            id,
+            TransferDirection::Outgoing,
+            &mut t.stalled,
+            t.last_activity,
+        )
+    });
+    let incoming = transfers.incoming.iter_mut().map(|(id, t)| {
+        (
+            id,
+            TransferDirection::Incoming,
+            &mut t.stalled,
+            t.last_activity,
+        )
+    });
+
+    for (id, direction, stalled, last_activity) in outgoing.chain(incoming) {
+        if *stalled || last_activity.elapsed() < timeout {
+            continue;
+        }
+
+        *stalled = true;
+        ew_failed.send(EventTransferFailed {
+            id: *id,
+            direction,
+            reason: "timed out".to_string(),
+            resumable: true,
+        });
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Error};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::utils::{get_service_route_from_dht, CRYPTO_KIND};
+use veilid_duplex::veilid::VeilidDuplex;
+use veilid_duplex::veilid_core::tools::sleep;
+use veilid_duplex::veilid_core::{
+    CryptoKey, CryptoTyped, RouteId, Sequencing, Stability, Target, VeilidRouteChange,
+};
+
+use crate::envelope::Envelope;
+
+const FRAME_VERSION: u8 = 1;
+const SEND_ATTEMPTS: u32 = 10;
+
+// Largest message veilid carries in an app_call or a DHT value.
+const MAX_FRAME_SIZE: usize = 32 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct FrameHeader {
+    sender: CryptoTyped<CryptoKey>,
+    envelope: Envelope,
+}
+
+// A frame is the version byte, the length of the JSON header as u32 LE, the header
+// (the envelope without its payload) and then the payload as it is.
+pub(crate) fn encode_frame(
+    sender: CryptoTyped<CryptoKey>,
+    mut envelope: Envelope,
+) -> Result<Vec<u8>, Error> {
+    let payload = std::mem::take(&mut envelope.payload);
+    let header = serde_json::to_vec(&FrameHeader { sender, envelope })?;
+
+    let mut frame = Vec::with_capacity(5 + header.len() + payload.len());
+    frame.push(FRAME_VERSION);
+    frame.extend((header.len() as u32).to_le_bytes());
+    frame.extend(header);
+    frame.extend(payload);
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "message of {} bytes is larger than {MAX_FRAME_SIZE} bytes",
+            frame.len()
+        ));
+    }
+    Ok(frame)
+}
+
+pub(crate) fn decode_frame(frame: &[u8]) -> Result<(CryptoTyped<CryptoKey>, Envelope), Error> {
+    let Some((&version, rest)) = frame.split_first() else {
+        return Err(anyhow!("empty message"));
+    };
+    if version != FRAME_VERSION {
+        return Err(anyhow!("unknown message version {version}"));
+    }
+    if rest.len() < 4 {
+        return Err(anyhow!("truncated message"));
+    }
+    let (length, rest) = rest.split_at(4);
+    let length = u32::from_le_bytes(length.try_into()?) as usize;
+    if rest.len() < length {
+        return Err(anyhow!("truncated message"));
+    }
+    let (header, payload) = rest.split_at(length);
+
+    let FrameHeader {
+        sender,
+        mut envelope,
+    } = serde_json::from_slice(header)?;
+    envelope.payload = payload.to_vec();
+    Ok((sender, envelope))
+}
+
+#[derive(Resource, Clone, Default)]
+pub(crate) struct Routes(Arc<Mutex<HashMap<CryptoTyped<CryptoKey>, RouteId>>>);
+
+impl Routes {
+    async fn target(
+        &self,
+        app: &VeilidDuplex,
+        peer: CryptoTyped<CryptoKey>,
+    ) -> Result<Target, Error> {
+        if let Some(route) = self.0.lock().unwrap().get(&peer) {
+            return Ok(Target::PrivateRoute(*route));
+        }
+        let (target, route) =
+            get_service_route_from_dht(app.api.clone(), app.routing_context.clone(), peer, true)
+                .await?;
+        self.0.lock().unwrap().insert(peer, route);
+        Ok(target)
+    }
+
+    fn forget(&self, peer: CryptoTyped<CryptoKey>) {
+        self.0.lock().unwrap().remove(&peer);
+    }
+
+    fn forget_routes(&self, dead: &[RouteId]) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, route| !dead.contains(route));
+    }
+}
+
+pub(crate) async fn send_frame(
+    app: &VeilidDuplex,
+    routes: &Routes,
+    destination: CryptoTyped<CryptoKey>,
+    frame: Vec<u8>,
+) -> Result<(), Error> {
+    let mut attempt = 0;
+    loop {
+        let result = async {
+            let target = routes.target(app, destination).await?;
+            app.routing_context.app_call(target, frame.clone()).await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+
+        attempt += 1;
+        match result {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt == SEND_ATTEMPTS => {
+                return Err(anyhow!("could not reach {destination}: {err}"));
+            }
+            // The peer may have moved to a new route, so it is looked up again.
+            Err(_) => {
+                routes.forget(destination);
+                sleep(500).await;
+            }
+        }
+    }
+}
+
+pub(crate) async fn new_private_route(app: &VeilidDuplex) -> Result<(RouteId, Vec<u8>), Error> {
+    let (route, blob) = app
+        .api
+        .new_custom_private_route(
+            &[CRYPTO_KIND],
+            Stability::Reliable,
+            Sequencing::PreferOrdered,
+        )
+        .await?;
+    Ok((route, STANDARD_NO_PAD.encode(blob).into_bytes()))
+}
+
+// Replaces our route if it died and points our DHT record at the new one, and forgets
+// dead routes of peers.
+pub(crate) async fn on_route_change(
+    app: &mut VeilidDuplex,
+    routes: &Routes,
+    change: &VeilidRouteChange,
+) -> Result<(), Error> {
+    routes.forget_routes(&change.dead_remote_routes);
+    if !change.dead_routes.contains(&app.our_route) {
+        return Ok(());
+    }
+
+    let (route, blob) = new_private_route(app).await?;
+    let routing_context = &app.routing_context;
+    routing_context
+        .open_dht_record(app.our_dht_key, Some(app.dht_keypair))
+        .await?;
+    let result = routing_context
+        .set_dht_value(app.our_dht_key, 0, blob, None)
+        .await;
+    routing_context.close_dht_record(app.our_dht_key).await?;
+    result?;
+    app.our_route = route;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+    use crate::codec::CodecKind;
+    use crate::envelope::PayloadKind;
+    use crate::VeilidSettings;
+
+    #[test]
+    fn carries_payload_as_raw_bytes() {
+        let sender = CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([1; 32]));
+        let payload = vec![7u8; 4096];
+        let envelope = Envelope::new(
+            PayloadKind::User,
+            &payload,
+            CodecKind::Json,
+            &VeilidSettings::default(),
+        )
+        .unwrap();
+        let encoded = envelope.payload.clone();
+
+        let frame = encode_frame(sender, envelope).unwrap();
+        assert!(frame.len() < encoded.len() + 512);
+        let (decoded_sender, decoded) = decode_frame(&frame).unwrap();
+        assert_eq!(decoded_sender, sender);
+        assert_eq!(decoded.payload, encoded);
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert!(decode_frame(&[]).is_err());
+        assert!(decode_frame(&[2, 0, 0, 0, 0]).is_err());
+        assert!(decode_frame(&[FRAME_VERSION, 10, 0, 0, 0, b'{']).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::clock::TurnClock;
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::{timestamp, VeilidApp};
+
+/// What happens to a message that arrives out of turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfTurnPolicy {
+    /// Drop the message. [`EventOutOfTurn`] is emitted with `rejected` set.
+    #[default]
+    Reject,
+    /// Deliver the message anyway and emit [`EventOutOfTurn`].
+    Flag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnSettings {
+    pub out_of_turn: OutOfTurnPolicy,
+    /// A message from the player whose turn it is ends that turn.
+    pub end_turn_on_message: bool,
+}
+
+impl Default for TurnSettings {
+    fn default() -> Self {
+        Self {
+            out_of_turn: OutOfTurnPolicy::Reject,
+            end_turn_on_message: true,
+        }
+    }
+}
+
+/// Whose turn it is. Inactive until seats are assigned.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TurnState {
+    seats: Vec<CryptoTyped<CryptoKey>>,
+    current: usize,
+    turn: u32,
+    started_at: Option<u64>,
+    #[serde(default)]
+    previous_started_at: Option<u64>,
+    #[serde(skip)]
+    changes: Vec<TurnChange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TurnChange {
+    Ended(EventTurnEnded),
+    Started(EventTurnStarted),
+}
+
+impl TurnState {
+    pub fn new(seats: Vec<CryptoTyped<CryptoKey>>) -> Self {
+        let mut state = Self::default();
+        state.start(seats);
+        state
+    }
+
+    /// Assigns seats and starts turn 1 with the first seat.
+    pub fn start(&mut self, seats: Vec<CryptoTyped<CryptoKey>>) {
+        self.seats = seats;
+        self.current = 0;
+        self.turn = 1;
+        self.started_at = Some(timestamp());
+        self.previous_started_at = None;
+        self.record_start();
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.seats.is_empty()
+    }
+
+    pub fn seats(&self) -> &[CryptoTyped<CryptoKey>] {
+        &self.seats
+    }
+
+    /// Number of the current turn, starting at 1. 0 while inactive.
+    pub fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    pub fn current_player(&self) -> Option<CryptoTyped<CryptoKey>> {
+        self.seats.get(self.current).copied()
+    }
+
+    /// The player whose turn ended last.
+    pub fn previous_player(&self) -> Option<CryptoTyped<CryptoKey>> {
+        if self.turn <= 1 {
+            return None;
+        }
+        let previous = (self.current + self.seats.len() - 1) % self.seats.len();
+        self.seats.get(previous).copied()
+    }
+
+    pub fn seat_of(&self, player: CryptoTyped<CryptoKey>) -> Option<usize> {
+        self.seats.iter().position(|seat| *seat == player)
+    }
+
+    pub fn is_turn_of(&self, player: CryptoTyped<CryptoKey>) -> bool {
+        self.current_player() == Some(player)
+    }
+
+    /// Sender timestamp, in milliseconds since the Unix epoch, of the message that started
+    /// the current turn. The first turn starts when the seats are assigned.
+    pub fn started_at(&self) -> Option<u64> {
+        self.started_at
+    }
+
+    /// Ends the current turn and passes it to the next seat.
+    pub fn advance(&mut self) {
+        self.advance_at(timestamp());
+    }
+
+    /// Ends the current turn at `at` milliseconds since the Unix epoch.
+    pub fn advance_at(&mut self, at: u64) {
+        let Some(player) = self.current_player() else {
+            return;
+        };
+        self.changes.push(TurnChange::Ended(EventTurnEnded {
+            turn: self.turn,
+            player,
+            started_at: self.started_at,
+            ended_at: at,
+        }));
+        self.current = (self.current + 1) % self.seats.len();
+        self.turn += 1;
+        self.previous_started_at = self.started_at;
+        self.started_at = Some(at);
+        self.record_start();
+    }
+
+    /// Undoes the last [`TurnState::advance`], e.g. after the move that ended the turn was
+    /// rejected. Only one step can be undone.
+    pub fn rewind(&mut self) {
+        if !self.is_active() || self.turn <= 1 {
+            return;
+        }
+        self.current = (self.current + self.seats.len() - 1) % self.seats.len();
+        self.turn -= 1;
+        self.started_at = self.previous_started_at.take();
+        // A rewound turn never ended, it just starts again
+        self.record_start();
+    }
+
+    pub(crate) fn replace_player(
+        &mut self,
+        old: CryptoTyped<CryptoKey>,
+        new: CryptoTyped<CryptoKey>,
+    ) {
+        for seat in self.seats.iter_mut().filter(|seat| **seat == old) {
+            *seat = new;
+        }
+    }
+
+    pub(crate) fn rewind_to(&mut self, turn: u32, started_at: Option<u64>) {
+        if !self.is_active() || turn == 0 || turn >= self.turn {
+            return;
+        }
+        let steps = (self.turn - turn) as usize % self.seats.len();
+        self.current = (self.current + self.seats.len() - steps) % self.seats.len();
+        self.turn = turn;
+        self.started_at = started_at;
+        self.previous_started_at = None;
+        self.record_start();
+    }
+
+    pub(crate) fn restore(&mut self, state: TurnState) {
+        let changes = std::mem::take(&mut self.changes);
+        *self = Self { changes, ..state };
+        self.record_start();
+    }
+
+    fn record_start(&mut self) {
+        if let Some(player) = self.current_player() {
+            self.changes.push(TurnChange::Started(EventTurnStarted {
+                turn: self.turn,
+                player,
+            }));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum TurnMessage {
+    End { turn: u32, at: u64 },
+}
+
+// ------
+// Events
+// ------
+
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventTurnStarted {
+    pub turn: u32,
+    pub player: CryptoTyped<CryptoKey>,
+}
+
+/// A turn ended. Timestamps are milliseconds since the Unix epoch.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventTurnEnded {
+    pub turn: u32,
+    pub player: CryptoTyped<CryptoKey>,
+    /// `None` if the start of the turn is unknown.
+    pub started_at: Option<u64>,
+    pub ended_at: u64,
+}
+
+/// Ends the local player's turn and tells the other seats.
+#[derive(Event)]
+pub struct EventEndTurn;
+
+/// A message or turn end from a player whose turn it isn't. `dht_key` is our own key for
+/// local sends.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EventOutOfTurn {
+    pub dht_key: CryptoTyped<CryptoKey>,
+    pub turn: u32,
+    pub rejected: bool,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn on_ev_end_turn(
+    mut er_end_turn: EventReader<EventEndTurn>,
+    mut ew_send_protocol: EventWriter<EventSendProtocol>,
+    mut ew_out_of_turn: EventWriter<EventOutOfTurn>,
+    mut turn_state: ResMut<TurnState>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+    let our_dht_key = app.our_dht_key;
+
+    for _ in er_end_turn.read() {
+        if !turn_state.is_turn_of(our_dht_key) {
+            ew_out_of_turn.send(EventOutOfTurn {
+                dht_key: our_dht_key,
+                turn: turn_state.turn(),
+                rejected: true,
+            });
+            continue;
+        }
+
+        let at = timestamp();
+        for seat in turn_state.seats().iter().filter(|s| **s != our_dht_key) {
+            ew_send_protocol.send(EventSendProtocol {
+                message: ProtocolMessage::Turn(TurnMessage::End {
+                    turn: turn_state.turn(),
+                    at,
+                }),
+                dht_key: *seat,
+            });
+        }
+        turn_state.advance_at(at);
+    }
+}
+
+pub(crate) fn on_ev_receive_turn(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_out_of_turn: EventWriter<EventOutOfTurn>,
+    mut turn_state: ResMut<TurnState>,
+    clock: Res<TurnClock>,
+) {
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Turn(TurnMessage::End { turn, at }) = e.message else {
+            continue;
+        };
+
+        if turn_state.is_turn_of(e.dht_key) && turn == turn_state.turn() {
+            let at = clock.remote_time(at, turn_state.started_at());
+            turn_state.advance_at(at);
+        } else {
+            ew_out_of_turn.send(EventOutOfTurn {
+                dht_key: e.dht_key,
+                turn: turn_state.turn(),
+                rejected: true,
+            });
+        }
+    }
+}
+
+pub(crate) fn emit_turn_events(
+    mut ew_turn_started: EventWriter<EventTurnStarted>,
+    mut ew_turn_ended: EventWriter<EventTurnEnded>,
+    mut turn_state: ResMut<TurnState>,
+) {
+    if turn_state.changes.is_empty() {
+        return;
+    }
+    for change in std::mem::take(&mut turn_state.bypass_change_detection().changes) {
+        match change {
+            TurnChange::Ended(e) => {
+                ew_turn_ended.send(e);
+            }
+            TurnChange::Started(e) => {
+                ew_turn_started.send(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use veilid_duplex::veilid_core::CRYPTO_KIND_VLD0;
+
+    use super::*;
+
+    fn player(byte: u8) -> CryptoTyped<CryptoKey> {
+        CryptoTyped::new(CRYPTO_KIND_VLD0, CryptoKey::new([byte; 32]))
+    }
+
+    #[test]
+    fn passes_the_turn_around_the_seats() {
+        let mut state = TurnState::new(vec![player(1), player(2), player(3)]);
+        assert_eq!(state.turn(), 1);
+        assert!(state.is_turn_of(player(1)));
+        assert_eq!(state.previous_player(), None);
+
+        for _ in 0..3 {
+            state.advance_at(10);
+        }
+        assert_eq!(state.turn(), 4);
+        assert!(state.is_turn_of(player(1)));
+        assert_eq!(state.previous_player(), Some(player(3)));
+        assert_eq!(state.started_at(), Some(10));
+    }
+
+    #[test]
+    fn rewinds_one_turn() {
+        let mut state = TurnState::new(vec![player(1), player(2)]);
+        state.advance_at(10);
+        state.advance_at(20);
+
+        state.rewind();
+        assert_eq!(state.turn(), 2);
+        assert!(state.is_turn_of(player(2)));
+        assert_eq!(state.started_at(), Some(10));
+
+        // The start of the turn before is gone, so it isn't guessed
+        state.rewind();
+        assert_eq!(state.turn(), 1);
+        assert!(state.is_turn_of(player(1)));
+        assert_eq!(state.started_at(), None);
+
+        state.rewind();
+        assert_eq!(state.turn(), 1);
+    }
+
+    #[test]
+    fn rewinds_to_an_earlier_turn() {
+        let mut state = TurnState::new(vec![player(1), player(2), player(3)]);
+        for at in [10, 20, 30, 40] {
+            state.advance_at(at);
+        }
+        assert_eq!(state.turn(), 5);
+
+        state.rewind_to(6, Some(50));
+        assert_eq!(state.turn(), 5);
+
+        state.rewind_to(3, Some(20));
+        assert_eq!(state.turn(), 3);
+        assert!(state.is_turn_of(player(3)));
+        assert_eq!(state.started_at(), Some(20));
+    }
+
+    #[test]
+    fn restores_a_peers_state_and_keeps_pending_events() {
+        let mut state = TurnState::new(vec![player(1), player(2)]);
+        let mut remote = state.clone();
+        remote.advance_at(10);
+        remote.changes.clear();
+
+        state.restore(remote.clone());
+        assert_eq!(state.turn(), 2);
+        assert!(state.is_turn_of(player(2)));
+        assert_eq!(
+            state.changes,
+            vec![
+                TurnChange::Started(EventTurnStarted {
+                    turn: 1,
+                    player: player(1),
+                }),
+                TurnChange::Started(EventTurnStarted {
+                    turn: 2,
+                    player: player(2),
+                }),
+            ]
+        );
+        assert_eq!(state.started_at(), remote.started_at());
+    }
+
+    #[test]
+    fn replaces_a_player_in_every_seat() {
+        let mut state = TurnState::new(vec![player(1), player(2), player(1)]);
+        state.replace_player(player(1), player(4));
+        assert_eq!(state.seats(), &[player(4), player(2), player(4)]);
+        assert!(state.is_turn_of(player(4)));
+        assert_eq!(state.seat_of(player(1)), None);
+    }
+}
@@ -0,0 +1,175 @@
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use veilid_duplex::veilid_core::{CryptoKey, CryptoTyped};
+
+use crate::protocol::{EventReceiveProtocol, EventSendProtocol, ProtocolMessage};
+use crate::transcript::Transcript;
+use crate::turn::TurnState;
+use crate::{EventIncomingMessage, VeilidApp};
+
+/// Checks a move of `T` from `peer` against the local game state. Returning `Err` with a
+/// reason drops the move.
+pub trait MoveValidator<T>: Send + Sync + 'static {
+    fn validate(
+        &self,
+        world: &mut World,
+        peer: CryptoTyped<CryptoKey>,
+        message: &T,
+    ) -> Result<(), String>;
+}
+
+impl<T, F> MoveValidator<T> for F
+where
+    F: Fn(&mut World, CryptoTyped<CryptoKey>, &T) -> Result<(), String> + Send + Sync + 'static,
+{
+    fn validate(
+        &self,
+        world: &mut World,
+        peer: CryptoTyped<CryptoKey>,
+        message: &T,
+    ) -> Result<(), String> {
+        self(world, peer, message)
+    }
+}
+
+/// Runs a [`MoveValidator`] on every incoming message of `T` before it is delivered as
+/// [`EventReceiveMessage`](crate::EventReceiveMessage).
+#[derive(Resource)]
+pub struct MoveValidation<T> {
+    validator: Box<dyn MoveValidator<T>>,
+    notify_sender: bool,
+}
+
+impl<T> MoveValidation<T> {
+    pub fn new(validator: impl MoveValidator<T>) -> Self {
+        Self {
+            validator: Box::new(validator),
+            notify_sender: true,
+        }
+    }
+
+    /// Tells the sender about rejected moves with [`EventMoveRejected`]. On by default.
+    pub fn notify_sender(mut self, notify_sender: bool) -> Self {
+        self.notify_sender = notify_sender;
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ValidationMessage {
+    Rejected {
+        turn: Option<u32>,
+        sent_at: u64,
+        reason: String,
+    },
+}
+
+// ------
+// Events
+// ------
+
+/// A move from `peer` failed validation and was dropped.
+#[derive(Event, Debug, Clone)]
+pub struct EventInvalidMove {
+    pub peer: CryptoTyped<CryptoKey>,
+    pub turn: Option<u32>,
+    pub reason: String,
+}
+
+/// `peer` rejected the local move sent at `sent_at`.
+#[derive(Event, Debug, Clone)]
+pub struct EventMoveRejected {
+    pub peer: CryptoTyped<CryptoKey>,
+    pub turn: Option<u32>,
+    pub sent_at: u64,
+    pub reason: String,
+}
+
+// -------
+// Systems
+// -------
+
+pub(crate) fn validate_incoming_messages<
+    T: DeserializeOwned + Serialize + std::marker::Sync + std::marker::Send + Clone + 'static,
+>(
+    world: &mut World,
+) {
+    if !world.contains_resource::<MoveValidation<T>>() {
+        return;
+    }
+
+    let incoming: Vec<EventIncomingMessage<T>> = world
+        .resource_mut::<Events<EventIncomingMessage<T>>>()
+        .drain()
+        .collect();
+    if incoming.is_empty() {
+        return;
+    }
+
+    world.resource_scope(|world, validation: Mut<MoveValidation<T>>| {
+        for e in incoming {
+            let Err(reason) = validation.validator.validate(world, e.dht_key, &e.message) else {
+                world.send_event(e);
+                continue;
+            };
+
+            if validation.notify_sender {
+                world.send_event(EventSendProtocol {
+                    message: ProtocolMessage::Validation(ValidationMessage::Rejected {
+                        turn: e.turn,
+                        sent_at: e.sent_at,
+                        reason: reason.clone(),
+                    }),
+                    dht_key: e.dht_key,
+                });
+            }
+            world.send_event(EventInvalidMove {
+                peer: e.dht_key,
+                turn: e.turn,
+                reason,
+            });
+        }
+    });
+}
+
+pub(crate) fn on_ev_receive_rejection(
+    mut er_receive_protocol: EventReader<EventReceiveProtocol>,
+    mut ew_move_rejected: EventWriter<EventMoveRejected>,
+    mut turn_state: ResMut<TurnState>,
+    mut transcript: ResMut<Transcript>,
+    veilid_app: Res<VeilidApp>,
+) {
+    let Some(app) = veilid_app.app.as_ref() else {
+        return;
+    };
+
+    for e in er_receive_protocol.read() {
+        let ProtocolMessage::Validation(ValidationMessage::Rejected {
+            turn,
+            sent_at,
+            reason,
+        }) = &e.message
+        else {
+            continue;
+        };
+
+        // Only the move that ended our last turn can be taken back
+        let ended_our_turn = turn.is_some_and(|turn| {
+            turn_state.turn() == turn + 1
+                && turn_state.started_at() == Some(*sent_at)
+                && turn_state.previous_player() == Some(app.our_dht_key)
+        });
+        if ended_our_turn {
+            turn_state.rewind();
+        }
+        transcript.remove_sent(app.our_dht_key, *sent_at);
+
+        ew_move_rejected.send(EventMoveRejected {
+            peer: e.dht_key,
+            turn: *turn,
+            sent_at: *sent_at,
+            reason: reason.clone(),
+        });
+    }
+}